@@ -1,25 +1,67 @@
-use chrono::DateTime;
 use eframe::{
     egui::{self, RichText, ScrollArea, Spinner, TextEdit, TextStyle},
     emath::Align,
     epaint::Color32,
     epi,
 };
-use flume::{unbounded, Receiver};
-use log::{debug, info, trace};
-use reqwest::header::HeaderValue;
+use log::{debug, info, trace, warn};
+use protocol::{
+    Attachment, Channel, GatewayEvent, MessageReference, Presence, Status, User, TYPING_TIMEOUT_MS,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
-    thread,
+    time::{Duration, Instant},
 };
-use tungstenite::{client::IntoClientRequest, connect};
+
+use crate::gateway::{Gateway, GatewayUpdate};
 
 #[derive(Deserialize)]
 struct Account {
     id: String,
     username: String,
     token: String,
+    refresh_token: String,
+    expires_at: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenRefresh {
+    token: String,
+    refresh_token: String,
+    expires_at: i64,
+}
+
+enum RefreshError {
+    /// the server turned the refresh token down, the session is over
+    Rejected,
+    /// the server couldn't be reached or didn't answer properly, worth retrying
+    Failed,
+}
+
+/// Trades a refresh token for a new access token and rotated refresh token.
+fn refresh_token(
+    http: &reqwest::blocking::Client,
+    refresh_token: &str,
+) -> Result<TokenRefresh, RefreshError> {
+    let mut data = HashMap::new();
+    data.insert("refresh_token", refresh_token);
+
+    let response = http
+        .post(format!("{}/token/refresh", INSTANCE_URL))
+        .json(&data)
+        .send()
+        .map_err(|_| RefreshError::Failed)?;
+
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(RefreshError::Rejected);
+    }
+
+    response
+        .error_for_status()
+        .map_err(|_| RefreshError::Failed)?
+        .json()
+        .map_err(|_| RefreshError::Failed)
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -30,33 +72,77 @@ struct Guild {
     created_at: String,
     owner_id: String,
     channels: Vec<Channel>,
+    /// the current account's permission bitfield in this guild
+    #[serde(default)]
+    permissions: i32,
+    #[serde(default)]
+    read_states: Vec<Unread>,
+    /// roles the current account has in this guild
+    #[serde(default)]
+    role_ids: Vec<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    default_channel_id: Option<String>,
+    #[serde(default)]
+    system_channel_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct SocketMessagePayload {
-    content: String,
-    author: User,
+/// How much of a channel the current account hasn't read yet.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct Unread {
     channel_id: String,
-    created_at: String,
-    id: String,
+    last_read_message_id: Option<String>,
+    unread_count: u32,
+    mention_count: u32,
+}
+
+/// The unread state of every guild channel, keyed by channel id.
+fn collect_unread(guilds: &[Guild]) -> HashMap<String, Unread> {
+    guilds
+        .iter()
+        .flat_map(|guild| &guild.read_states)
+        .map(|state| (state.channel_id.to_owned(), state.clone()))
+        .collect()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// mirrors the bits in server/src/permissions.rs
+const MANAGE_CHANNELS: i32 = 1 << 0;
+const MANAGE_GUILD: i32 = 1 << 1;
 
-struct SocketTypePayload {
-    msg_type: String,
+/// Group DMs can be named, otherwise DMs are labelled with who else is in them.
+fn channel_label(channel: &Channel, account_id: &str) -> String {
+    if !channel.name.is_empty() {
+        return channel.name.to_owned();
+    }
+
+    channel
+        .recipients
+        .iter()
+        .filter(|recipient| recipient.id != account_id)
+        .map(|recipient| recipient.username.to_owned())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-#[derive(Deserialize, Clone, Debug)]
-struct Channel {
-    name: String,
-    id: String,
+fn fetch_dm_channels(http: &reqwest::blocking::Client, token: &str) -> Vec<Channel> {
+    http.get(format!("{}/users/me/channels", INSTANCE_URL))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct User {
-    id: String,
-    username: String,
+/// Pinned messages of a channel, most recently pinned first.
+fn fetch_pins(http: &reqwest::blocking::Client, token: &str, channel_id: &str) -> Vec<Message> {
+    http.get(format!("{}/channels/{}/pins", INSTANCE_URL, channel_id))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap()
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -64,14 +150,187 @@ struct Message {
     id: String,
     content: String,
     created_at: String,
+    #[serde(default)]
+    edited_at: Option<String>,
+    /// set while the message is pinned
+    #[serde(default)]
+    pinned_at: Option<String>,
     author: User,
+    #[serde(default)]
+    mentions: Vec<String>,
+    #[serde(default)]
+    mention_roles: Vec<String>,
+    #[serde(default)]
+    mention_everyone: bool,
+    #[serde(default)]
+    reply_to: Option<MessageReference>,
+    #[serde(default)]
+    reactions: Vec<ReactionCount>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
 }
 
+/// Everyone who reacted to a message with one emoji.
+#[derive(Debug, Deserialize, Clone)]
+struct ReactionCount {
+    emoji: String,
+    count: u32,
+    /// whether the current account is one of them
+    me: bool,
+}
+
+/// Offered in a message's context menu, other emoji are added from their chips.
+const QUICK_REACTIONS: [&str; 5] = ["👍", "❤", "😂", "😮", "😢"];
+
+impl Message {
+    /// Whether the message pings `account_id`, who has `role_ids` in its guild.
+    fn mentions_account(&self, account_id: &str, role_ids: &[String]) -> bool {
+        self.mention_everyone
+            || self.mentions.iter().any(|id| id == account_id)
+            || self.mention_roles.iter().any(|id| role_ids.contains(id))
+    }
+}
+
+/// Attachments with one of these types get an inline preview.
+const PREVIEW_CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// largest width or height an image preview is drawn at
+const MAX_PREVIEW_SIZE: f32 = 240.0;
+
+/// The server only trusts this for previews, so a guess from the extension is enough.
+fn guess_content_type(filename: &str) -> &'static str {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "txt" => "text/plain",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Downloads and decodes an image attachment, `None` if either fails.
+fn load_preview(
+    ctx: &egui::Context,
+    http: &reqwest::blocking::Client,
+    token: &str,
+    attachment: &Attachment,
+) -> Option<egui::TextureHandle> {
+    let bytes = http
+        .get(format!("{}{}", INSTANCE_URL, attachment.url))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .bytes()
+        .ok()?;
+
+    let image = image::load_from_memory(&bytes).ok()?.to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    let image = egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw());
+
+    Some(ctx.load_texture(&attachment.url, image))
+}
+
+/// "2.5 MB", "812 B"
+fn format_size(size: u64) -> String {
+    match size {
+        0..=1023 => format!("{} B", size),
+        1024..=1_048_575 => format!("{:.1} KB", size as f64 / 1024.0),
+        _ => format!("{:.1} MB", size as f64 / 1_048_576.0),
+    }
+}
+
+/// Quotes a replied-to message on a single line.
+fn quote_preview(reference: &MessageReference) -> String {
+    const MAX_CHARS: usize = 48;
+
+    let first_line = reference.content.lines().next().unwrap_or_default();
+    let mut preview: String = first_line.chars().take(MAX_CHARS).collect();
+    if preview.len() < reference.content.len() {
+        preview.push('…');
+    }
+
+    format!("↪ {}: {}", reference.author.username, preview)
+}
+
+/// The name a new thread gets from the message it is started from.
+fn thread_name(message: &Message) -> String {
+    let name: String = message.content.chars().take(32).collect();
+    match name.trim() {
+        "" => "Thread".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+const MENTION_HIGHLIGHT: Color32 = Color32::from_rgba_premultiplied(60, 45, 10, 60);
+
 #[derive(Debug, Deserialize, Clone)]
 struct Invite {
     code: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct Member {
+    id: String,
+    username: String,
+    nickname: Option<String>,
+    presence: Presence,
+}
+
+const STATUSES: [Status; 4] = [Status::Online, Status::Idle, Status::Dnd, Status::Offline];
+
+fn status_label(status: Status) -> &'static str {
+    match status {
+        Status::Online => "Online",
+        Status::Idle => "Idle",
+        Status::Dnd => "Do Not Disturb",
+        Status::Offline => "Invisible",
+    }
+}
+
+fn status_color(status: Status) -> Color32 {
+    match status {
+        Status::Online => Color32::from_rgb(67, 181, 129),
+        Status::Idle => Color32::from_rgb(250, 166, 26),
+        Status::Dnd => Color32::from_rgb(240, 71, 71),
+        Status::Offline => Color32::GRAY,
+    }
+}
+
+/// The presence the current account chose, which may differ from what others see.
+fn fetch_presence(http: &reqwest::blocking::Client, token: &str) -> Option<Presence> {
+    http.get(format!("{}/users/me/presence", INSTANCE_URL))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .ok()?
+        .json()
+        .ok()
+}
+
+fn update_presence(
+    http: &reqwest::blocking::Client,
+    token: &str,
+    data: &impl Serialize,
+) -> Option<Presence> {
+    http.patch(format!("{}/users/me/presence", INSTANCE_URL))
+        .header("Authorization", format!("Bearer {}", token))
+        .json(data)
+        .send()
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .json()
+        .ok()
+}
+
 #[derive(Default)]
 pub struct RustCord {
     inputs: HashMap<String, String>,
@@ -79,14 +338,59 @@ pub struct RustCord {
     current_guild: Option<Guild>,
     current_channel: Option<Channel>,
     message_cache: HashMap<String, Vec<Message>>,
+    /// channels whose oldest message is already in `message_cache`
+    history_complete: HashSet<String>,
+    /// vertical offset to apply to the message list on the next frame
+    scroll_offset: Option<f32>,
+    /// members of each guild, with their presence
+    member_cache: HashMap<String, Vec<Member>>,
+    /// unread state of each channel, keyed by channel id
+    unread: HashMap<String, Unread>,
+    /// who is typing in each channel, and when that runs out
+    typing: HashMap<String, Vec<(User, Instant)>>,
+    /// the channel a typing indicator was last sent for, and when
+    typing_sent: Option<(String, Instant)>,
+    /// the message the next one sent replies to
+    replying_to: Option<Message>,
+    /// files dropped onto the window, sent with the next message
+    pending_uploads: Vec<(String, Vec<u8>)>,
+    /// decoded image previews keyed by attachment url, `None` if loading failed
+    image_cache: HashMap<String, Option<egui::TextureHandle>>,
+    /// pinned messages of the channels whose pins were looked at, kept up to date
+    pinned_messages: HashMap<String, Vec<Message>>,
     guilds: Vec<Guild>,
+    dm_channels: Vec<Channel>,
     account: Option<Account>,
+    /// when to retry a failed token refresh, and how long the last wait was
+    refresh_backoff: Option<(Instant, Duration)>,
+    presence: Option<Presence>,
     http: reqwest::blocking::Client,
-    socket_channel: Option<Receiver<String>>,
+    gateway: Option<Gateway>,
 }
 
 const INSTANCE_URL: &str = "http://localhost:3000";
 
+/// number of messages fetched per request when loading channel history
+const MESSAGE_PAGE_SIZE: usize = 50;
+
+/// how long to wait before retrying a failed token refresh, doubled on each failure
+const MIN_REFRESH_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_REFRESH_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// a little under `TYPING_TIMEOUT_MS`, so the indicator doesn't flicker for others
+const TYPING_RESEND_INTERVAL: Duration = Duration::from_secs(8);
+
+/// "alice is typing…", "alice and bob are typing…"
+fn typing_text(users: &[(User, Instant)]) -> String {
+    match users {
+        [(user, _)] => format!("{} is typing…", user.username),
+        [(first, _), (second, _)] => {
+            format!("{} and {} are typing…", first.username, second.username)
+        }
+        _ => "Several people are typing…".to_owned(),
+    }
+}
+
 impl epi::App for RustCord {
     fn update(&mut self, ctx: &egui::Context, frame: &epi::Frame) {
         let Self {
@@ -95,10 +399,23 @@ impl epi::App for RustCord {
             current_guild,
             current_channel,
             message_cache,
+            history_complete,
+            scroll_offset,
+            member_cache,
+            unread,
+            typing,
+            typing_sent,
+            replying_to,
+            pending_uploads,
+            image_cache,
+            pinned_messages,
             guilds,
+            dm_channels,
             account,
+            refresh_backoff,
+            presence,
             http,
-            socket_channel,
+            gateway,
         } = self;
 
         if account.is_none() {
@@ -148,28 +465,17 @@ impl epi::App for RustCord {
 
                         trace!("Fetched guilds: {:?}", self.guilds);
 
-                        let mut request = "ws://localhost:3000/ws".into_client_request().unwrap();
-
-                        request.headers_mut().append(
-                            "Authorization",
-                            HeaderValue::from_str(&self.account.as_ref().unwrap().token).unwrap(),
-                        );
-
-                        let (mut sock, _response) = connect(request).unwrap();
-
-                        let (s, r) = unbounded();
-
-                        *socket_channel = Some(r);
+                        self.unread = collect_unread(&self.guilds);
 
-                        let frame = frame.0.clone();
+                        self.dm_channels =
+                            fetch_dm_channels(http, &self.account.as_ref().unwrap().token);
 
-                        thread::spawn(move || loop {
-                            let socket_msg = sock.read_message().unwrap().into_text().unwrap();
+                        *presence = fetch_presence(http, &self.account.as_ref().unwrap().token);
 
-                            frame.lock().unwrap().repaint_signal.request_repaint();
-
-                            s.send(socket_msg).unwrap();
-                        });
+                        *gateway = Some(Gateway::connect(
+                            &self.account.as_ref().unwrap().token,
+                            frame,
+                        ));
                     }
 
                     if ui.button("Register").clicked() {
@@ -192,18 +498,72 @@ impl epi::App for RustCord {
             return;
         }
 
-        let socket = socket_channel.as_ref().unwrap();
+        // refresh the access token a minute before it expires
+        let mut session_ended = false;
+
+        if let Some(account) = self.account.as_mut() {
+            let due = chrono::Utc::now().timestamp() >= account.expires_at - 60;
+            let waiting =
+                matches!(refresh_backoff, Some((retry_at, _)) if Instant::now() < *retry_at);
+
+            if due && !waiting {
+                debug!("Access token is about to expire, refreshing");
+                match refresh_token(http, &account.refresh_token) {
+                    Ok(refreshed) => {
+                        account.token = refreshed.token;
+                        if let Some(gateway) = gateway.as_ref() {
+                            gateway.set_token(&account.token);
+                        }
+                        account.refresh_token = refreshed.refresh_token;
+                        account.expires_at = refreshed.expires_at;
+                        *refresh_backoff = None;
+                    }
+                    Err(RefreshError::Rejected) => session_ended = true,
+                    Err(RefreshError::Failed) => {
+                        let delay = refresh_backoff
+                            .map_or(MIN_REFRESH_RETRY_DELAY, |(_, delay)| {
+                                (delay * 2).min(MAX_REFRESH_RETRY_DELAY)
+                            });
+
+                        warn!(
+                            "Refreshing the access token failed, retrying in {:?}",
+                            delay
+                        );
+                        *refresh_backoff = Some((Instant::now() + delay, delay));
+                    }
+                }
+            }
+        }
+
+        if session_ended {
+            info!("The session has ended, logging out");
+            self.account = None;
+            *refresh_backoff = None;
+            *gateway = None;
+            ctx.request_repaint();
+            return;
+        }
+
+        let update = gateway.as_ref().unwrap().try_recv();
 
-        let msg = socket.try_recv().unwrap_or_else(|_| "".to_owned());
+        // events were missed while disconnected, start over from fresh data
+        let resync = matches!(update, Some(GatewayUpdate::Resync));
 
-        let update_payload: SocketTypePayload =
-            serde_json::from_str(&msg).unwrap_or(SocketTypePayload {
-                msg_type: "".to_string(),
-            });
+        let event = match update {
+            Some(GatewayUpdate::Event(event)) => Some(event),
+            _ => None,
+        };
 
         let account = self.account.as_ref().unwrap();
 
-        if update_payload.msg_type == "user_guild_data_update" {
+        if resync {
+            debug!("Gateway session was lost, refetching everything");
+            *dm_channels = fetch_dm_channels(http, &account.token);
+            message_cache.clear();
+            member_cache.clear();
+        }
+
+        if let Some(GatewayEvent::UserGuildDataUpdate) = event {
             debug!("received user guild update, updating guilds");
             *guilds = http
                 .get(format!("{}/{}", INSTANCE_URL, "users/me/guilds"))
@@ -215,6 +575,89 @@ impl epi::App for RustCord {
                 .unwrap()
                 .json()
                 .unwrap();
+
+            *unread = collect_unread(guilds);
+        }
+
+        if let Some(GatewayEvent::ChannelCreate(channel)) = &event {
+            debug!("Received channel create, adding direct message");
+            if !dm_channels.iter().any(|c| c.id == channel.id) {
+                dm_channels.push(channel.clone());
+            }
+        }
+
+        match &event {
+            Some(GatewayEvent::TypingStart { channel_id, user }) => {
+                let users = typing.entry(channel_id.to_owned()).or_default();
+                users.retain(|(u, _)| u.id != user.id);
+                users.push((
+                    user.clone(),
+                    Instant::now() + Duration::from_millis(TYPING_TIMEOUT_MS),
+                ));
+            }
+            Some(GatewayEvent::MessageCreate(message)) => {
+                // sending a message ends the indicator early
+                if let Some(users) = typing.get_mut(&message.channel_id) {
+                    users.retain(|(u, _)| u.id != message.author.id);
+                }
+
+                // the open channel is acked as soon as the message shows up
+                let is_open = current_channel.as_ref().map(|c| &c.id) == Some(&message.channel_id);
+
+                if message.author.id != account.id && !is_open {
+                    unread
+                        .entry(message.channel_id.to_owned())
+                        .or_insert_with(|| Unread {
+                            channel_id: message.channel_id.to_owned(),
+                            ..Unread::default()
+                        })
+                        .unread_count += 1;
+                }
+            }
+            Some(GatewayEvent::ReadStateUpdate(read_state)) => {
+                let state = unread
+                    .entry(read_state.channel_id.to_owned())
+                    .or_insert_with(|| Unread {
+                        channel_id: read_state.channel_id.to_owned(),
+                        ..Unread::default()
+                    });
+
+                state.last_read_message_id = read_state.last_read_message_id.clone();
+                state.unread_count = 0;
+                state.mention_count = read_state.mention_count;
+            }
+            Some(GatewayEvent::MessageMention(message)) => {
+                let is_open = current_channel.as_ref().map(|c| &c.id) == Some(&message.channel_id);
+
+                if !is_open {
+                    unread
+                        .entry(message.channel_id.to_owned())
+                        .or_insert_with(|| Unread {
+                            channel_id: message.channel_id.to_owned(),
+                            ..Unread::default()
+                        })
+                        .mention_count += 1;
+                }
+            }
+            _ => {}
+        }
+
+        typing.retain(|_, users| {
+            users.retain(|(_, expires_at)| *expires_at > Instant::now());
+            !users.is_empty()
+        });
+
+        // keep redrawing so indicators disappear once they run out
+        if !typing.is_empty() {
+            ctx.request_repaint();
+        }
+
+        if let Some(GatewayEvent::PresenceUpdate(updated)) = &event {
+            for members in member_cache.values_mut() {
+                if let Some(member) = members.iter_mut().find(|m| m.id == updated.user_id) {
+                    member.presence = updated.clone();
+                }
+            }
         }
 
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
@@ -242,15 +685,109 @@ impl epi::App for RustCord {
                     };
                 });
 
-                if current_guild.is_some() && current_guild.as_ref().unwrap().owner_id == account.id
-                {
+                ui.menu_button("Direct Messages", |ui| {
+                    if dm_channels.is_empty() {
+                        ui.label(RichText::new("No direct messages yet").weak());
+                    }
+
+                    for channel in dm_channels.iter() {
+                        if ui
+                            .radio(
+                                current_guild.is_none()
+                                    && current_channel.as_ref().map(|c| &c.id) == Some(&channel.id),
+                                channel_label(channel, &account.id),
+                            )
+                            .clicked()
+                        {
+                            *current_guild = None;
+                            *current_channel = Some(channel.clone());
+                            *replying_to = None;
+                            message_cache.remove(&channel.id);
+                        }
+                    }
+                });
+
+                ui.menu_button("Status", |ui| {
+                    let current = presence.as_ref().map(|p| p.status);
+
+                    for status in STATUSES {
+                        if ui
+                            .radio(current == Some(status), status_label(status))
+                            .clicked()
+                        {
+                            let mut data = HashMap::new();
+                            data.insert("status", status);
+
+                            if let Some(updated) = update_presence(http, &account.token, &data) {
+                                *presence = Some(updated);
+                            }
+                            ui.close_menu();
+                        }
+                    }
+
+                    ui.separator();
+
+                    if ui.button("Set custom status...").clicked() {
+                        let custom_status = presence
+                            .as_ref()
+                            .and_then(|p| p.custom_status.clone())
+                            .unwrap_or_default();
+                        inputs.insert("custom_status".to_owned(), custom_status);
+
+                        open_windows.insert("custom_status".to_owned());
+                        ui.close_menu();
+                    }
+                });
+
+                if let Some(guild) = current_guild.as_ref() {
+                    let is_owner = guild.owner_id == account.id;
+                    let can_manage_channels = guild.permissions & MANAGE_CHANNELS != 0;
+                    let can_manage_guild = guild.permissions & MANAGE_GUILD != 0;
+
                     ui.menu_button("Manage", |ui| {
-                        if ui.button("Create Channel").clicked() {
+                        if can_manage_channels && ui.button("Create Channel").clicked() {
                             open_windows.insert("create_channel".to_owned());
                         }
-                        if ui.button("Delete Guild").clicked() {
+                        if can_manage_guild && ui.button("Guild Settings").clicked() {
+                            inputs.insert("settings_name".to_owned(), guild.name.to_owned());
+                            inputs.insert(
+                                "settings_description".to_owned(),
+                                guild.description.clone().unwrap_or_default(),
+                            );
+                            inputs.insert(
+                                "settings_default_channel".to_owned(),
+                                guild.default_channel_id.clone().unwrap_or_default(),
+                            );
+                            inputs.insert(
+                                "settings_system_channel".to_owned(),
+                                guild.system_channel_id.clone().unwrap_or_default(),
+                            );
+                            inputs.insert("settings_icon_path".to_owned(), "".to_owned());
+
+                            open_windows.insert("guild_settings".to_owned());
+                            ui.close_menu();
+                        }
+                        if is_owner && ui.button("Transfer Ownership").clicked() {
+                            inputs.insert("transfer_user".to_owned(), "".to_owned());
+                            inputs.insert("transfer_password".to_owned(), "".to_owned());
+
+                            open_windows.insert("transfer_guild".to_owned());
+                            ui.close_menu();
+                        }
+                        if is_owner && ui.button("Delete Guild").clicked() {
                             open_windows.insert("delete_guild".to_owned());
                         }
+                        // owners have to transfer the guild first
+                        if !is_owner && ui.button("Leave Guild").clicked() {
+                            let res = http
+                                .delete(format!("{}/users/me/guilds/{}", INSTANCE_URL, guild.id))
+                                .header("Authorization", format!("Bearer {}", account.token))
+                                .send();
+
+                            // the guild is dropped once the gateway confirms it
+                            trace!("Left guild: {:?}", res);
+                            ui.close_menu();
+                        }
                     });
                 }
 
@@ -258,6 +795,40 @@ impl epi::App for RustCord {
             });
         });
 
+        if open_windows.contains("custom_status") {
+            egui::Window::new("Custom Status").show(ctx, |ui| {
+                ui.add(
+                    TextEdit::singleline(inputs.get_mut("custom_status").unwrap())
+                        .desired_width(f32::INFINITY)
+                        .desired_rows(1)
+                        .hint_text("What's happening?"),
+                );
+                ui.horizontal(|ui| {
+                    let save = ui.button("Save").clicked();
+                    // an empty custom status clears it
+                    let clear = ui.button("Clear").clicked();
+
+                    if save || clear {
+                        if clear {
+                            inputs.get_mut("custom_status").unwrap().clear();
+                        }
+
+                        let mut data = HashMap::new();
+                        data.insert("customStatus", inputs.get("custom_status").unwrap());
+
+                        if let Some(updated) = update_presence(http, &account.token, &data) {
+                            *presence = Some(updated);
+                        }
+
+                        open_windows.remove("custom_status");
+                    }
+                    if ui.button("Cancel").clicked() {
+                        open_windows.remove("custom_status");
+                    }
+                });
+            });
+        }
+
         if open_windows.contains("create_channel") {
             egui::Window::new("Create Channel").show(ctx, |ui| {
                 ui.add(
@@ -432,7 +1003,175 @@ impl epi::App for RustCord {
                 .unwrap();
         }
 
-        if update_payload.msg_type == "guild_data_update" {
+        if open_windows.contains("guild_settings") {
+            let guild = current_guild.as_ref().unwrap();
+
+            egui::Window::new("Guild Settings").show(ctx, |ui| {
+                ui.add(
+                    TextEdit::singleline(inputs.get_mut("settings_name").unwrap())
+                        .desired_width(f32::INFINITY)
+                        .hint_text("Guild Name"),
+                );
+                ui.add(
+                    TextEdit::multiline(inputs.get_mut("settings_description").unwrap())
+                        .desired_width(f32::INFINITY)
+                        .desired_rows(3)
+                        .hint_text("Description"),
+                );
+
+                // threads can't be picked
+                let text_channels: Vec<&Channel> =
+                    guild.channels.iter().filter(|c| c.kind == "text").collect();
+
+                for (key, label) in [
+                    ("settings_default_channel", "Default Channel"),
+                    ("settings_system_channel", "System Messages Channel"),
+                ] {
+                    let selected = inputs.get_mut(key).unwrap();
+                    let selected_text = text_channels
+                        .iter()
+                        .find(|c| &c.id == selected)
+                        .map(|c| format!("#{}", c.name))
+                        .unwrap_or_else(|| "None".to_owned());
+
+                    egui::ComboBox::from_label(label)
+                        .selected_text(selected_text)
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(selected, "".to_owned(), "None");
+                            for channel in &text_channels {
+                                ui.selectable_value(
+                                    selected,
+                                    channel.id.to_owned(),
+                                    format!("#{}", channel.name),
+                                );
+                            }
+                        });
+                }
+
+                ui.add(
+                    TextEdit::singleline(inputs.get_mut("settings_icon_path").unwrap())
+                        .desired_width(f32::INFINITY)
+                        .hint_text("New icon (path to an image, optional)"),
+                );
+
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        let mut data = HashMap::new();
+                        for (field, key) in [
+                            ("name", "settings_name"),
+                            ("description", "settings_description"),
+                            ("defaultChannelId", "settings_default_channel"),
+                            ("systemChannelId", "settings_system_channel"),
+                        ] {
+                            data.insert(field, inputs.get(key).unwrap().trim());
+                        }
+
+                        let request = http
+                            .patch(format!("{}/guilds/{}", INSTANCE_URL, guild.id))
+                            .header("Authorization", format!("Bearer {}", account.token));
+
+                        // a new icon goes in a multipart form, like message attachments
+                        let icon_path = inputs.get("settings_icon_path").unwrap().trim();
+                        let request = match std::fs::read(icon_path) {
+                            Ok(bytes) if !icon_path.is_empty() => {
+                                let part = reqwest::blocking::multipart::Part::bytes(bytes)
+                                    .mime_str(guess_content_type(icon_path))
+                                    .unwrap()
+                                    .file_name(icon_path.to_owned());
+                                let form = reqwest::blocking::multipart::Form::new()
+                                    .text("payload_json", serde_json::to_string(&data).unwrap())
+                                    .part("icon", part);
+
+                                request.multipart(form)
+                            }
+                            _ => request.json(&data),
+                        };
+
+                        let res = request.send();
+
+                        trace!("Updated guild settings: {:?}", res);
+
+                        open_windows.remove("guild_settings");
+                    }
+
+                    if ui.button("Cancel").clicked() {
+                        open_windows.remove("guild_settings");
+                    }
+                });
+            });
+        }
+
+        if open_windows.contains("transfer_guild") {
+            let guild = current_guild.as_ref().unwrap();
+            let members: Vec<&Member> = member_cache
+                .get(&guild.id)
+                .map(|members| members.iter().filter(|m| m.id != account.id).collect())
+                .unwrap_or_default();
+
+            egui::Window::new("Transfer Ownership").show(ctx, |ui| {
+                ui.label(format!(
+                    "Hand {} to another member. You will lose all of your owner privileges.",
+                    guild.name
+                ));
+
+                let selected = inputs.get_mut("transfer_user").unwrap();
+                let selected_text = members
+                    .iter()
+                    .find(|m| &m.id == selected)
+                    .map(|m| m.username.to_owned())
+                    .unwrap_or_else(|| "Select a member".to_owned());
+
+                egui::ComboBox::from_label("New Owner")
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        for member in &members {
+                            ui.selectable_value(
+                                selected,
+                                member.id.to_owned(),
+                                member.username.to_owned(),
+                            );
+                        }
+                    });
+
+                ui.add(
+                    TextEdit::singleline(inputs.get_mut("transfer_password").unwrap())
+                        .desired_width(f32::INFINITY)
+                        .password(true)
+                        .hint_text("Your Password"),
+                );
+
+                ui.horizontal(|ui| {
+                    let can_transfer = !inputs.get("transfer_user").unwrap().is_empty();
+
+                    if ui
+                        .add_enabled(can_transfer, egui::Button::new("Transfer"))
+                        .clicked()
+                    {
+                        let mut data = HashMap::new();
+                        data.insert("userId", inputs.get("transfer_user").unwrap());
+                        data.insert("password", inputs.get("transfer_password").unwrap());
+
+                        let res = http
+                            .post(format!("{}/guilds/{}/transfer", INSTANCE_URL, guild.id))
+                            .header("Authorization", format!("Bearer {}", account.token))
+                            .json(&data)
+                            .send();
+
+                        trace!("Transferred guild: {:?}", res);
+
+                        open_windows.remove("transfer_guild");
+                        inputs.get_mut("transfer_password").unwrap().clear();
+                    }
+
+                    if ui.button("Cancel").clicked() {
+                        open_windows.remove("transfer_guild");
+                        inputs.get_mut("transfer_password").unwrap().clear();
+                    }
+                });
+            });
+        }
+
+        if resync || matches!(event, Some(GatewayEvent::GuildDataUpdate { .. })) {
             debug!("Received guild data update");
             let res: Vec<Guild> = http
                 .get(format!("{}/{}", INSTANCE_URL, "users/me/guilds"))
@@ -453,12 +1192,16 @@ impl epi::App for RustCord {
                 }
             }
 
+            *unread = collect_unread(&res);
             self.guilds = res;
 
+            // roles and memberships may have changed
+            member_cache.clear();
+
             ctx.request_repaint();
         }
 
-        if current_guild.is_none() {
+        if current_guild.is_none() && current_channel.is_none() {
             egui::CentralPanel::default().show(ctx, |ui| {
                 ui.with_layout(
                     egui::Layout::centered_and_justified(egui::Direction::TopDown),
@@ -470,67 +1213,241 @@ impl epi::App for RustCord {
             return;
         }
 
-        let guild = current_guild.as_ref().unwrap();
+        // (re)load the newest page whenever a channel is opened
+        if let Some(channel) = current_channel.as_ref() {
+            if !message_cache.contains_key(&channel.id) {
+                // the server returns the newest page, oldest message first
+                let messages: Vec<Message> = http
+                    .get(format!(
+                        "{}/channels/{}/messages?limit={}",
+                        INSTANCE_URL, channel.id, MESSAGE_PAGE_SIZE
+                    ))
+                    .header("Authorization", format!("Bearer {}", account.token))
+                    .send()
+                    .unwrap()
+                    .json()
+                    .unwrap();
+
+                trace!("Fetched messages: {:?}", messages);
+
+                if messages.len() < MESSAGE_PAGE_SIZE {
+                    history_complete.insert(channel.id.to_owned());
+                } else {
+                    history_complete.remove(&channel.id);
+                }
 
-        egui::SidePanel::left("side_panel").show(ctx, |ui| {
-            ui.add_space(4.0);
-            ui.heading("Channels");
+                message_cache.insert(channel.id.to_owned(), messages);
 
-            for channel in &guild.channels {
-                if ui.button(format!("#{}", channel.name.to_owned())).clicked() {
-                    *current_channel = Some(channel.clone());
+                // start at the newest message rather than the top of the page
+                *scroll_offset = Some(f32::INFINITY);
+            }
+        }
 
-                    let mut messages: Vec<Message> = http
-                        .get(format!("{}/channels/{}/messages", INSTANCE_URL, channel.id))
-                        .header("Authorization", format!("Bearer {}", account.token))
-                        .send()
-                        .unwrap()
-                        .json()
-                        .unwrap();
+        // everything in the open channel counts as read
+        if let Some(channel) = current_channel.as_ref() {
+            if let Some(newest) = message_cache.get(&channel.id).and_then(|m| m.last()) {
+                let state = unread
+                    .entry(channel.id.to_owned())
+                    .or_insert_with(|| Unread {
+                        channel_id: channel.id.to_owned(),
+                        ..Unread::default()
+                    });
 
-                    trace!("Fetched messages: {:?}", messages);
+                if state.last_read_message_id.as_ref() != Some(&newest.id) {
+                    let res = http
+                        .post(format!(
+                            "{}/channels/{}/messages/{}/ack",
+                            INSTANCE_URL, channel.id, newest.id
+                        ))
+                        .header("Authorization", format!("Bearer {}", account.token))
+                        .send();
 
-                    messages.sort_by(|a, b| {
-                        DateTime::parse_from_rfc3339(&a.created_at)
-                            .unwrap()
-                            .cmp(&DateTime::parse_from_rfc3339(&b.created_at).unwrap())
-                    });
+                    trace!("Acked message: {:?}", res);
 
-                    message_cache.insert(channel.id.to_owned(), messages);
-                };
+                    state.last_read_message_id = Some(newest.id.to_owned());
+                    state.unread_count = 0;
+                    state.mention_count = 0;
+                }
             }
+        }
 
-            if update_payload.msg_type == "new_message" {
-                let data: SocketMessagePayload = serde_json::from_str(&msg).unwrap();
+        egui::SidePanel::left("side_panel").show(ctx, |ui| {
+            ui.add_space(4.0);
 
-                if message_cache.contains_key(&data.channel_id) {
-                    let messages = message_cache.get_mut(&data.channel_id).unwrap();
+            match current_guild.as_ref() {
+                Some(guild) => {
+                    ui.heading("Channels");
+
+                    // open threads are listed right under the channel they were started in
+                    let channels = guild
+                        .channels
+                        .iter()
+                        .filter(|channel| channel.parent_channel_id.is_none())
+                        .flat_map(|parent| {
+                            std::iter::once(parent).chain(guild.channels.iter().filter(
+                                move |channel| {
+                                    channel.parent_channel_id.as_ref() == Some(&parent.id)
+                                        && !channel.archived
+                                },
+                            ))
+                        });
 
-                    messages.push(Message {
-                        author: User {
-                            id: data.author.id,
-                            username: data.author.username,
-                        },
-                        content: data.content,
-                        created_at: data.created_at,
-                        id: data.id,
-                    });
+                    for channel in channels {
+                        let state = unread.get(&channel.id);
 
-                    messages.sort_by(|a, b| {
-                        DateTime::parse_from_rfc3339(&a.created_at)
-                            .unwrap()
-                            .cmp(&DateTime::parse_from_rfc3339(&b.created_at).unwrap())
-                    });
+                        let mut label = RichText::new(match channel.parent_channel_id {
+                            Some(_) => format!("  ↳ {}", channel.name),
+                            None => format!("#{}", channel.name),
+                        });
+                        if state.map_or(false, |s| s.unread_count > 0) {
+                            label = label.strong().color(Color32::WHITE);
+                        }
+
+                        ui.horizontal(|ui| {
+                            if ui.button(label).clicked() {
+                                *current_channel = Some(channel.clone());
+                                *replying_to = None;
+                                message_cache.remove(&channel.id);
+                            };
+
+                            if let Some(mentions) = state.filter(|s| s.mention_count > 0) {
+                                ui.label(
+                                    RichText::new(format!(" {} ", mentions.mention_count))
+                                        .color(Color32::WHITE)
+                                        .background_color(Color32::from_rgb(240, 71, 71)),
+                                );
+                            }
+                        });
+                    }
+                }
+                None => {
+                    ui.heading("Direct Messages");
+
+                    for channel in dm_channels.iter() {
+                        if ui.button(channel_label(channel, &account.id)).clicked() {
+                            *current_channel = Some(channel.clone());
+                            *replying_to = None;
+                            message_cache.remove(&channel.id);
+                        };
+                    }
                 }
             }
 
+            match &event {
+                Some(GatewayEvent::MessageCreate(data)) => {
+                    if let Some(messages) = message_cache.get_mut(&data.channel_id) {
+                        messages.push(Message {
+                            author: data.author.clone(),
+                            content: data.content.to_owned(),
+                            created_at: data.created_at.to_owned(),
+                            edited_at: data.edited_at.clone(),
+                            pinned_at: None,
+                            id: data.id.to_owned(),
+                            mentions: data.mentions.clone(),
+                            mention_roles: data.mention_roles.clone(),
+                            mention_everyone: data.mention_everyone,
+                            reply_to: data.reply_to.clone(),
+                            reactions: vec![],
+                            attachments: data.attachments.clone(),
+                        });
+                    }
+                }
+                Some(GatewayEvent::MessageUpdate(data)) => {
+                    if let Some(messages) = message_cache.get_mut(&data.channel_id) {
+                        if let Some(message) = messages.iter_mut().find(|m| m.id == data.id) {
+                            message.content = data.content.to_owned();
+                            message.edited_at = data.edited_at.clone();
+                            message.mentions = data.mentions.clone();
+                            message.mention_roles = data.mention_roles.clone();
+                            message.mention_everyone = data.mention_everyone;
+                        }
+                    }
+                }
+                Some(GatewayEvent::MessageDelete { channel_id, id }) => {
+                    if let Some(messages) = message_cache.get_mut(channel_id) {
+                        messages.retain(|m| &m.id != id);
+                    }
+                }
+                Some(GatewayEvent::ReactionAdd(reaction)) => {
+                    if let Some(message) =
+                        message_cache
+                            .get_mut(&reaction.channel_id)
+                            .and_then(|messages| {
+                                messages.iter_mut().find(|m| m.id == reaction.message_id)
+                            })
+                    {
+                        let me = reaction.user_id == account.id;
+
+                        match message
+                            .reactions
+                            .iter_mut()
+                            .find(|r| r.emoji == reaction.emoji)
+                        {
+                            Some(count) => {
+                                count.count += 1;
+                                count.me |= me;
+                            }
+                            None => message.reactions.push(ReactionCount {
+                                emoji: reaction.emoji.to_owned(),
+                                count: 1,
+                                me,
+                            }),
+                        }
+                    }
+                }
+                Some(GatewayEvent::ReactionRemove(reaction)) => {
+                    if let Some(message) =
+                        message_cache
+                            .get_mut(&reaction.channel_id)
+                            .and_then(|messages| {
+                                messages.iter_mut().find(|m| m.id == reaction.message_id)
+                            })
+                    {
+                        if let Some(count) = message
+                            .reactions
+                            .iter_mut()
+                            .find(|r| r.emoji == reaction.emoji)
+                        {
+                            count.count = count.count.saturating_sub(1);
+                            if reaction.user_id == account.id {
+                                count.me = false;
+                            }
+                        }
+
+                        message.reactions.retain(|r| r.count > 0);
+                    }
+                }
+                Some(GatewayEvent::ChannelPinsUpdate { channel_id, .. }) => {
+                    // the event doesn't say which message changed, so refetch them all
+                    if pinned_messages.contains_key(channel_id)
+                        || message_cache.contains_key(channel_id)
+                    {
+                        let pins = fetch_pins(http, &account.token, channel_id);
+
+                        if let Some(messages) = message_cache.get_mut(channel_id) {
+                            for message in messages.iter_mut() {
+                                message.pinned_at = pins
+                                    .iter()
+                                    .find(|pin| pin.id == message.id)
+                                    .and_then(|pin| pin.pinned_at.clone());
+                            }
+                        }
+
+                        if pinned_messages.contains_key(channel_id) {
+                            pinned_messages.insert(channel_id.to_owned(), pins);
+                        }
+                    }
+                }
+                _ => {}
+            }
+
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
                 ui.horizontal(|ui| {
                     ui.spacing_mut().item_spacing.x = 0.0;
                     ui.label("Logged in as ");
                     ui.label(RichText::new(account.username.to_owned()).color(Color32::WHITE));
                 });
-                if ui.button("Create Invite").clicked() {
+                if current_guild.is_some() && ui.button("Create Invite").clicked() {
                     let invite: Invite = http
                         .post(format!(
                             "{}/guilds/{}/invites/create",
@@ -555,57 +1472,388 @@ impl epi::App for RustCord {
             });
         });
 
-        egui::CentralPanel::default().show(ctx, |ui| {
-            let guild_name = current_guild.as_ref().unwrap().name.to_owned();
+        if let Some(guild) = current_guild.as_ref() {
+            if !member_cache.contains_key(&guild.id) {
+                let members: Vec<Member> = http
+                    .get(format!("{}/guilds/{}/members", INSTANCE_URL, guild.id))
+                    .header("Authorization", format!("Bearer {}", account.token))
+                    .send()
+                    .unwrap()
+                    .json()
+                    .unwrap();
+
+                trace!("Fetched members: {:?}", members);
+
+                member_cache.insert(guild.id.to_owned(), members);
+            }
+
+            egui::SidePanel::right("member_list").show(ctx, |ui| {
+                ui.add_space(4.0);
+                ui.heading("Members");
+
+                // online members first, then alphabetically
+                let mut members: Vec<&Member> = member_cache[&guild.id].iter().collect();
+                members.sort_by_key(|m| {
+                    (
+                        m.presence.status == Status::Offline,
+                        m.nickname.as_ref().unwrap_or(&m.username).to_lowercase(),
+                    )
+                });
+
+                ScrollArea::vertical().show(ui, |ui| {
+                    for member in members {
+                        let status = member.presence.status;
+
+                        ui.horizontal(|ui| {
+                            let (dot, _) = ui
+                                .allocate_exact_size(egui::vec2(10.0, 10.0), egui::Sense::hover());
+                            ui.painter()
+                                .circle_filled(dot.center(), 4.0, status_color(status));
+
+                            let name = member.nickname.as_ref().unwrap_or(&member.username);
+                            let text = match status {
+                                Status::Offline => RichText::new(name).weak(),
+                                _ => RichText::new(name).color(Color32::WHITE),
+                            };
+                            ui.label(text);
+                        })
+                        .response
+                        .on_hover_text(match status {
+                            Status::Offline => "Offline",
+                            _ => status_label(status),
+                        });
+
+                        if let Some(custom_status) = &member.presence.custom_status {
+                            ui.label(RichText::new(custom_status).small().weak());
+                        }
+                    }
+                });
+            });
+        }
+
+        if open_windows.contains("pins") {
+            if let Some(channel) = current_channel.as_ref() {
+                let pins = pinned_messages
+                    .entry(channel.id.to_owned())
+                    .or_insert_with(|| fetch_pins(http, &account.token, &channel.id));
+
+                let mut open = true;
+
+                egui::Window::new("Pinned Messages")
+                    .open(&mut open)
+                    .show(ctx, |ui| {
+                        if pins.is_empty() {
+                            ui.label("This channel doesn't have any pinned messages yet.");
+                        }
+
+                        ScrollArea::vertical().max_height(320.0).show(ui, |ui| {
+                            for message in pins.iter() {
+                                ui.horizontal_wrapped(|ui| {
+                                    ui.label(
+                                        RichText::new(format!("[{}]", message.author.username))
+                                            .color(Color32::WHITE),
+                                    );
+                                    ui.label(&message.content);
+                                });
+                                ui.separator();
+                            }
+                        });
+                    });
 
+                if !open {
+                    open_windows.remove("pins");
+                }
+            }
+        }
+
+        // set from an author's context menu, handled once the panel is drawn
+        let mut open_dm_with: Option<String> = None;
+        let mut start_thread_from: Option<Message> = None;
+        // message id, emoji and whether the current account already reacted with it
+        let mut toggle_reaction: Option<(String, String, bool)> = None;
+        // message id and whether it is pinned already
+        let mut toggle_pin: Option<(String, bool)> = None;
+
+        egui::CentralPanel::default().show(ctx, |ui| {
             if current_channel.is_none() {
+                let guild_name = current_guild.as_ref().unwrap().name.to_owned();
                 ui.heading(format!("{}: Select a channel", guild_name));
                 return;
             }
 
             let current_channel = current_channel.as_ref().unwrap();
 
-            ui.heading(format!(
-                "{}: #{}",
-                guild_name,
-                current_channel.name.to_owned()
-            ));
+            let channel_title = match current_guild.as_ref() {
+                Some(_) => format!("#{}", current_channel.name),
+                None => channel_label(current_channel, &account.id),
+            };
+
+            ui.horizontal(|ui| {
+                match current_guild.as_ref() {
+                    Some(guild) => ui.heading(format!(
+                        "{}: #{}",
+                        guild.name,
+                        current_channel.name.to_owned()
+                    )),
+                    None => ui.heading(format!(
+                        "Direct Messages: {}",
+                        channel_label(current_channel, &account.id)
+                    )),
+                };
+
+                if ui.button("📌").on_hover_text("Pinned Messages").clicked() {
+                    open_windows.insert("pins".to_owned());
+                }
+            });
 
             ui.add_space(4.0);
 
+            for file in ctx.input().raw.dropped_files.iter() {
+                // native drops only have a path, web drops carry the bytes
+                let bytes = match (&file.bytes, &file.path) {
+                    (Some(bytes), _) => Some(bytes.to_vec()),
+                    (None, Some(path)) => std::fs::read(path).ok(),
+                    (None, None) => None,
+                };
+
+                let name = match &file.path {
+                    Some(path) => path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    None => file.name.to_owned(),
+                };
+
+                if let Some(bytes) = bytes {
+                    pending_uploads.push((name, bytes));
+                }
+            }
+
             let current_message_cache = message_cache.get(current_channel.id.as_str()).unwrap();
 
+            // direct messages have no roles to mention
+            let role_ids = current_guild
+                .as_ref()
+                .map(|guild| guild.role_ids.as_slice())
+                .unwrap_or_default();
+
             let text_style = TextStyle::Body;
             let row_height = ui.text_style_height(&text_style);
+            let row_height_with_spacing = row_height + ui.spacing().item_spacing.y;
             let num_rows = current_message_cache.len();
 
-            ScrollArea::vertical()
+            // rows taken up by the chatbox, typing indicator and the bars above them
+            let mut reserved_rows = 4.5;
+            if replying_to.is_some() {
+                reserved_rows += 1.0;
+            }
+            if !pending_uploads.is_empty() {
+                reserved_rows += 1.0;
+            }
+
+            let mut scroll_area = ScrollArea::vertical()
                 .stick_to_bottom()
                 .auto_shrink([false; 2])
-                .max_height(ui.max_rect().height() - row_height * 3.5)
-                .show_rows(ui, row_height, num_rows, |ui, row_range| {
-                    if num_rows < row_range.start {
-                        ui.scroll_to_cursor(Some(Align::TOP));
-                        return;
-                    }
-                    for message in &current_message_cache[row_range] {
-                        ui.horizontal(|ui| {
-                            ui.label(
+                .max_height(ui.max_rect().height() - row_height * reserved_rows);
+
+            if let Some(offset) = scroll_offset.take() {
+                scroll_area = scroll_area
+                    .vertical_scroll_offset(offset.min(num_rows as f32 * row_height_with_spacing));
+            }
+
+            let output = scroll_area.show_rows(ui, row_height, num_rows, |ui, row_range| {
+                if num_rows < row_range.start {
+                    ui.scroll_to_cursor(Some(Align::TOP));
+                    return false;
+                }
+
+                let reached_top = row_range.start == 0;
+
+                for message in &current_message_cache[row_range] {
+                    ui.horizontal(|ui| {
+                        if let Some(reference) = &message.reply_to {
+                            ui.label(RichText::new(quote_preview(reference)).small().weak());
+                        }
+                        ui.add(
+                            egui::Label::new(
                                 RichText::new(format!("[{}]", message.author.username.to_owned()))
                                     .color(Color32::WHITE),
-                            );
-                            ui.add(egui::Label::new(message.content.to_owned()).wrap(true));
+                            )
+                            .sense(egui::Sense::click()),
+                        )
+                        .context_menu(|ui| {
+                            if message.author.id != account.id && ui.button("Message").clicked() {
+                                open_dm_with = Some(message.author.id.to_owned());
+                                ui.close_menu();
+                            }
+                            if ui.button("Reply").clicked() {
+                                *replying_to = Some(message.clone());
+                                ui.close_menu();
+                            }
+                            let pinned = message.pinned_at.is_some();
+                            if ui.button(if pinned { "Unpin" } else { "Pin" }).clicked() {
+                                toggle_pin = Some((message.id.to_owned(), pinned));
+                                ui.close_menu();
+                            }
+                            // threads can only be started in guild text channels
+                            if current_channel.kind == "text"
+                                && ui.button("Create Thread").clicked()
+                            {
+                                start_thread_from = Some(message.clone());
+                                ui.close_menu();
+                            }
+                            ui.horizontal(|ui| {
+                                for emoji in QUICK_REACTIONS {
+                                    if ui.button(emoji).clicked() {
+                                        let me = message
+                                            .reactions
+                                            .iter()
+                                            .any(|r| r.emoji == emoji && r.me);
+                                        toggle_reaction =
+                                            Some((message.id.to_owned(), emoji.to_owned(), me));
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
                         });
+                        let mut content = RichText::new(message.content.to_owned());
+                        if message.mentions_account(&account.id, role_ids) {
+                            content = content.background_color(MENTION_HIGHLIGHT);
+                        }
+                        ui.add(egui::Label::new(content).wrap(true));
+                        if message.edited_at.is_some() {
+                            ui.label(RichText::new("(edited)").small().weak());
+                        }
+                    });
+
+                    for attachment in &message.attachments {
+                        let preview =
+                            if PREVIEW_CONTENT_TYPES.contains(&attachment.content_type.as_str()) {
+                                image_cache
+                                    .entry(attachment.url.to_owned())
+                                    .or_insert_with(|| {
+                                        load_preview(ctx, http, &account.token, attachment)
+                                    })
+                                    .as_ref()
+                            } else {
+                                None
+                            };
+
+                        match preview {
+                            Some(texture) => {
+                                let size = texture.size_vec2();
+                                let scale = (MAX_PREVIEW_SIZE / size.x.max(size.y)).min(1.0);
+                                ui.image(texture, size * scale);
+                            }
+                            None => {
+                                ui.label(format!(
+                                    "📎 {} ({})",
+                                    attachment.filename,
+                                    format_size(attachment.size)
+                                ));
+                            }
+                        }
                     }
-                });
+
+                    if !message.reactions.is_empty() {
+                        ui.horizontal(|ui| {
+                            for reaction in &message.reactions {
+                                let chip = ui.selectable_label(
+                                    reaction.me,
+                                    format!("{} {}", reaction.emoji, reaction.count),
+                                );
+
+                                if chip.clicked() {
+                                    toggle_reaction = Some((
+                                        message.id.to_owned(),
+                                        reaction.emoji.to_owned(),
+                                        reaction.me,
+                                    ));
+                                }
+                            }
+                        });
+                    }
+                }
+
+                reached_top
+            });
+
+            // lazily load older history once the user scrolls up to the oldest cached message
+            if output.inner && num_rows > 0 && !history_complete.contains(&current_channel.id) {
+                let oldest_id = current_message_cache[0].id.to_owned();
+
+                let older: Vec<Message> = http
+                    .get(format!(
+                        "{}/channels/{}/messages?before={}&limit={}",
+                        INSTANCE_URL, current_channel.id, oldest_id, MESSAGE_PAGE_SIZE
+                    ))
+                    .header("Authorization", format!("Bearer {}", account.token))
+                    .send()
+                    .unwrap()
+                    .json()
+                    .unwrap();
+
+                trace!("Fetched {} older messages", older.len());
+
+                if older.len() < MESSAGE_PAGE_SIZE {
+                    history_complete.insert(current_channel.id.to_owned());
+                }
+
+                // keep the same messages in view after prepending
+                *scroll_offset =
+                    Some(output.state.offset.y + older.len() as f32 * row_height_with_spacing);
+
+                let messages = message_cache.get_mut(&current_channel.id).unwrap();
+                messages.splice(0..0, older);
+            }
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::Center), |ui| {
                 let textbox = ui.add(
                     TextEdit::multiline(inputs.get_mut("chatbox").unwrap())
                         .desired_width(f32::INFINITY)
                         .desired_rows(1)
-                        .hint_text(format!("Message #{}", current_channel.name)),
+                        .hint_text(format!("Message {}", channel_title)),
                 );
+
+                // laid out bottom up, so this ends up above the chatbox
+                match typing.get(&current_channel.id) {
+                    Some(users) => ui.label(RichText::new(typing_text(users)).small()),
+                    None => ui.label(RichText::new(" ").small()),
+                };
+
+                if let Some(message) = replying_to.as_ref() {
+                    let mut cancelled = false;
+
+                    ui.horizontal(|ui| {
+                        ui.label(
+                            RichText::new(format!("Replying to {}", message.author.username))
+                                .small(),
+                        );
+                        cancelled = ui.small_button("✖").clicked();
+                    });
+
+                    if cancelled {
+                        *replying_to = None;
+                    }
+                }
+
+                if !pending_uploads.is_empty() {
+                    let mut removed = None;
+
+                    ui.horizontal(|ui| {
+                        for (index, (name, _)) in pending_uploads.iter().enumerate() {
+                            ui.label(RichText::new(format!("📎 {}", name)).small());
+                            if ui.small_button("✖").clicked() {
+                                removed = Some(index);
+                            }
+                        }
+                    });
+
+                    if let Some(index) = removed {
+                        pending_uploads.remove(index);
+                    }
+                }
+
                 // enter sends message, shift+enter creates a new line
                 if textbox.has_focus()
                     && ctx.input().key_pressed(egui::Key::Enter)
@@ -613,25 +1861,154 @@ impl epi::App for RustCord {
                 {
                     let mut data = HashMap::new();
                     data.insert("content", inputs.get("chatbox").unwrap().trim());
+                    if let Some(message) = replying_to.as_ref() {
+                        data.insert("replyToId", message.id.as_str());
+                    }
 
                     ui.with_layout(egui::Layout::right_to_left(), |ui| ui.add(Spinner::new()));
 
-                    let res = http
+                    let request = http
                         .post(format!(
                             "{}/channels/{}/messages",
                             INSTANCE_URL, current_channel.id
                         ))
-                        .header("Authorization", format!("Bearer {}", account.token))
-                        .json(&data)
-                        .send()
-                        .unwrap();
+                        .header("Authorization", format!("Bearer {}", account.token));
+
+                    // files go in a multipart form, with the message itself as json
+                    let request = if pending_uploads.is_empty() {
+                        request.json(&data)
+                    } else {
+                        let mut form = reqwest::blocking::multipart::Form::new()
+                            .text("payload_json", serde_json::to_string(&data).unwrap());
+
+                        for (index, (name, bytes)) in pending_uploads.drain(..).enumerate() {
+                            let part = reqwest::blocking::multipart::Part::bytes(bytes)
+                                .mime_str(guess_content_type(&name))
+                                .unwrap()
+                                .file_name(name);
+                            form = form.part(format!("file{}", index), part);
+                        }
+
+                        request.multipart(form)
+                    };
+
+                    let res = request.send().unwrap();
 
                     trace!("Posted message, response: {:?}", res);
 
                     *inputs.get_mut("chatbox").unwrap() = "".to_owned();
+                    *typing_sent = None;
+                    *replying_to = None;
+                }
+
+                if !inputs.get("chatbox").unwrap().trim().is_empty() {
+                    let due = match typing_sent.as_ref() {
+                        Some((channel_id, sent_at)) => {
+                            channel_id != &current_channel.id
+                                || sent_at.elapsed() >= TYPING_RESEND_INTERVAL
+                        }
+                        None => true,
+                    };
+
+                    if due {
+                        gateway.as_ref().unwrap().start_typing(&current_channel.id);
+                        *typing_sent = Some((current_channel.id.to_owned(), Instant::now()));
+                    }
                 }
             });
         });
+
+        if let Some(user_id) = open_dm_with {
+            let mut data = HashMap::new();
+            data.insert("recipients", vec![user_id]);
+
+            let channel: Channel = http
+                .post(format!("{}/users/me/channels", INSTANCE_URL))
+                .header("Authorization", format!("Bearer {}", account.token))
+                .json(&data)
+                .send()
+                .unwrap()
+                .json()
+                .unwrap();
+
+            trace!("Opened direct message: {:?}", channel);
+
+            if !dm_channels.iter().any(|c| c.id == channel.id) {
+                dm_channels.push(channel.clone());
+            }
+
+            *current_guild = None;
+            message_cache.remove(&channel.id);
+            *current_channel = Some(channel);
+            *replying_to = None;
+        }
+
+        // the chip itself is updated once the gateway echoes the change back
+        if let Some((message_id, emoji, me)) = toggle_reaction {
+            let url = format!(
+                "{}/channels/{}/messages/{}/reactions/{}/@me",
+                INSTANCE_URL,
+                current_channel.as_ref().unwrap().id,
+                message_id,
+                emoji
+            );
+
+            let request = if me { http.delete(url) } else { http.put(url) };
+
+            let res = request
+                .header("Authorization", format!("Bearer {}", account.token))
+                .send();
+
+            trace!("Toggled reaction, response: {:?}", res);
+        }
+
+        // fails without the manage messages permission, which the menu doesn't know about
+        if let Some((message_id, pinned)) = toggle_pin {
+            let url = format!(
+                "{}/channels/{}/pins/{}",
+                INSTANCE_URL,
+                current_channel.as_ref().unwrap().id,
+                message_id
+            );
+
+            let request = if pinned {
+                http.delete(url)
+            } else {
+                http.put(url)
+            };
+
+            let res = request
+                .header("Authorization", format!("Bearer {}", account.token))
+                .send();
+
+            trace!("Toggled pin, response: {:?}", res);
+        }
+
+        if let Some(message) = start_thread_from {
+            let mut data = HashMap::new();
+            data.insert("name", thread_name(&message));
+            data.insert("messageId", message.id);
+
+            let res = http
+                .post(format!(
+                    "{}/channels/{}/threads",
+                    INSTANCE_URL,
+                    current_channel.as_ref().unwrap().id
+                ))
+                .header("Authorization", format!("Bearer {}", account.token))
+                .json(&data)
+                .send()
+                .unwrap();
+
+            // fails if someone already started a thread from the message
+            if let Ok(thread) = res.error_for_status().and_then(|res| res.json::<Channel>()) {
+                trace!("Started thread: {:?}", thread);
+
+                message_cache.remove(&thread.id);
+                *current_channel = Some(thread);
+                *replying_to = None;
+            }
+        }
     }
 
     fn setup(
@@ -648,19 +2025,27 @@ impl epi::App for RustCord {
         self.inputs.insert("channel_name".to_owned(), "".to_owned());
         self.inputs
             .insert("created_invite".to_owned(), "".to_owned());
+        self.inputs
+            .insert("custom_status".to_owned(), "".to_owned());
 
         self.http = reqwest::blocking::Client::new();
 
-        // let stored_token = storage.unwrap().get_string("token");
+        // let stored_token = storage.unwrap().get_string("refresh_token");
         let stored_token = Some("".to_owned());
 
         if stored_token.is_some() && stored_token.unwrap() != *"" {
-            info!("Logging in with stored token");
-            let token = storage.unwrap().get_string("token").unwrap();
+            info!("Logging in with stored refresh token");
+            let stored_refresh_token = storage.unwrap().get_string("refresh_token").unwrap();
+
+            let refreshed = match refresh_token(&self.http, &stored_refresh_token) {
+                Ok(refreshed) => refreshed,
+                Err(_) => return,
+            };
+
             let user_req = self
                 .http
                 .get(format!("{}/users/me", INSTANCE_URL))
-                .header("Authorization", format!("Bearer {}", token))
+                .header("Authorization", format!("Bearer {}", refreshed.token))
                 .send()
                 .unwrap()
                 .json::<User>();
@@ -674,7 +2059,9 @@ impl epi::App for RustCord {
             self.account = Some(Account {
                 id: user.id,
                 username: user.username,
-                token,
+                token: refreshed.token,
+                refresh_token: refreshed.refresh_token,
+                expires_at: refreshed.expires_at,
             });
 
             self.guilds = self
@@ -689,38 +2076,23 @@ impl epi::App for RustCord {
                 .json()
                 .unwrap();
 
-            let mut request = "ws://localhost:3000/ws".into_client_request().unwrap();
-
-            request.headers_mut().append(
-                "Authorization",
-                HeaderValue::from_str(&self.account.as_ref().unwrap().token).unwrap(),
-            );
-
-            let (mut sock, _response) = connect(request).unwrap();
-
-            let (s, r) = unbounded();
-
-            self.socket_channel = Some(r);
-
-            let frame = frame.0.clone();
+            self.unread = collect_unread(&self.guilds);
 
-            info!("Established websocket connection");
+            self.dm_channels = fetch_dm_channels(&self.http, &self.account.as_ref().unwrap().token);
 
-            thread::spawn(move || loop {
-                let socket_msg = sock.read_message().unwrap().into_text().unwrap();
+            self.presence = fetch_presence(&self.http, &self.account.as_ref().unwrap().token);
 
-                frame.lock().unwrap().repaint_signal.request_repaint();
-
-                trace!("Received socket message: {}", socket_msg);
-                s.send(socket_msg).unwrap();
-            });
+            self.gateway = Some(Gateway::connect(
+                &self.account.as_ref().unwrap().token,
+                frame,
+            ));
         }
     }
 
     fn save(&mut self, storage: &mut dyn epi::Storage) {
-        info!("Saving token to storage");
+        info!("Saving refresh token to storage");
         if let Some(account) = &self.account {
-            storage.set_string("token", account.token.to_owned());
+            storage.set_string("refresh_token", account.refresh_token.to_owned());
         }
     }
 
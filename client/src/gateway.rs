@@ -0,0 +1,321 @@
+use std::{
+    io::ErrorKind,
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use eframe::epi;
+use flume::{unbounded, Receiver, Sender};
+use log::{debug, error, info, trace, warn};
+use protocol::{Frame, GatewayEvent, PROTOCOL_VERSION};
+use tungstenite::{
+    client::IntoClientRequest,
+    connect,
+    http::{HeaderValue, StatusCode},
+    stream::MaybeTlsStream,
+    Message, WebSocket,
+};
+
+const GATEWAY_URL: &str = "ws://localhost:3000/ws";
+
+/// How long a read blocks before the thread checks whether a heartbeat is due.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+pub enum GatewayUpdate {
+    Event(GatewayEvent),
+    /// The session couldn't be resumed after a disconnect, so events may have
+    /// been missed and everything should be refetched.
+    Resync,
+}
+
+/// A gateway connection kept alive by a background thread, which heartbeats,
+/// reconnects and resumes the session as needed.
+pub struct Gateway {
+    updates: Receiver<GatewayUpdate>,
+    /// frames for the thread to send once it has a connection
+    outgoing: Sender<Frame>,
+    token: Arc<Mutex<String>>,
+}
+
+/// The current session, kept across reconnects so it can be resumed.
+struct Session {
+    id: String,
+    seq: u64,
+}
+
+enum Disconnect {
+    /// the connection dropped, try again
+    Retry(String),
+    /// reconnecting can't help, or the UI is gone
+    Stop,
+}
+
+impl Gateway {
+    pub fn connect(token: &str, frame: &epi::Frame) -> Self {
+        let (sender, updates) = unbounded();
+        let (outgoing, frames) = unbounded();
+        let token = Arc::new(Mutex::new(token.to_owned()));
+
+        let frame = frame.0.clone();
+        let repaint = move || frame.lock().unwrap().repaint_signal.request_repaint();
+
+        let thread_token = token.clone();
+        thread::spawn(move || run(thread_token, sender, frames, repaint));
+
+        Self {
+            updates,
+            outgoing,
+            token,
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<GatewayUpdate> {
+        self.updates.try_recv().ok()
+    }
+
+    /// Reconnects use the latest access token, so this has to be called after refreshing it.
+    pub fn set_token(&self, token: &str) {
+        *self.token.lock().unwrap() = token.to_owned();
+    }
+
+    /// Tells others in the channel that the user is typing. Dropped if the
+    /// gateway is disconnected, as it would be outdated by the time it reconnects.
+    pub fn start_typing(&self, channel_id: &str) {
+        self.outgoing
+            .send(Frame::TypingStart {
+                channel_id: channel_id.to_owned(),
+            })
+            .ok();
+    }
+}
+
+fn run(
+    token: Arc<Mutex<String>>,
+    sender: Sender<GatewayUpdate>,
+    frames: Receiver<Frame>,
+    repaint: impl Fn(),
+) {
+    let mut session = None;
+    let mut identified_before = false;
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        let token = token.lock().unwrap().clone();
+
+        match run_connection(
+            &token,
+            &mut session,
+            &mut identified_before,
+            &mut delay,
+            &sender,
+            &frames,
+            &repaint,
+        ) {
+            Disconnect::Retry(reason) => {
+                warn!("Gateway disconnected ({}), retrying in {:?}", reason, delay)
+            }
+            Disconnect::Stop => return,
+        }
+
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+
+        // whatever was queued while disconnected is stale by now
+        while frames.try_recv().is_ok() {}
+    }
+}
+
+fn send_frame(sock: &mut Socket, frame: &Frame) -> Result<(), Disconnect> {
+    let text = serde_json::to_string(frame).unwrap();
+
+    sock.write_message(Message::Text(text))
+        .map_err(|err| Disconnect::Retry(err.to_string()))
+}
+
+fn run_connection(
+    token: &str,
+    session: &mut Option<Session>,
+    identified_before: &mut bool,
+    delay: &mut Duration,
+    sender: &Sender<GatewayUpdate>,
+    frames: &Receiver<Frame>,
+    repaint: &impl Fn(),
+) -> Disconnect {
+    let mut request = format!("{}?v={}", GATEWAY_URL, PROTOCOL_VERSION)
+        .into_client_request()
+        .unwrap();
+
+    request
+        .headers_mut()
+        .append("Authorization", HeaderValue::from_str(token).unwrap());
+
+    let mut sock = match connect(request) {
+        Ok((sock, _response)) => sock,
+        Err(tungstenite::Error::Http(response)) if response.status() == StatusCode::BAD_REQUEST => {
+            error!("The server refused gateway protocol v{}", PROTOCOL_VERSION);
+            return Disconnect::Stop;
+        }
+        Err(err) => return Disconnect::Retry(err.to_string()),
+    };
+
+    info!("Established websocket connection");
+
+    if let MaybeTlsStream::Plain(stream) = sock.get_mut() {
+        stream.set_read_timeout(Some(POLL_INTERVAL)).ok();
+    }
+
+    let result = handle_connection(
+        &mut sock,
+        session,
+        identified_before,
+        delay,
+        sender,
+        frames,
+        repaint,
+    );
+
+    sock.close(None).ok();
+
+    match result {
+        Ok(()) => Disconnect::Stop,
+        Err(disconnect) => disconnect,
+    }
+}
+
+fn handle_connection(
+    sock: &mut Socket,
+    session: &mut Option<Session>,
+    identified_before: &mut bool,
+    delay: &mut Duration,
+    sender: &Sender<GatewayUpdate>,
+    frames: &Receiver<Frame>,
+    repaint: &impl Fn(),
+) -> Result<(), Disconnect> {
+    // known once the server said hello
+    let mut heartbeat_interval = None;
+    let mut next_heartbeat = Instant::now();
+    let mut awaiting_ack = false;
+
+    loop {
+        if let Some(interval) = heartbeat_interval {
+            if Instant::now() >= next_heartbeat {
+                // the previous heartbeat was never answered, the connection is probably dead
+                if awaiting_ack {
+                    return Err(Disconnect::Retry("heartbeat timed out".to_owned()));
+                }
+
+                let seq = session.as_ref().map(|session| session.seq);
+                send_frame(sock, &Frame::Heartbeat { seq })?;
+
+                awaiting_ack = true;
+                next_heartbeat = Instant::now() + interval;
+            }
+        }
+
+        // only once a session is running, the server ignores them before that
+        if session.is_some() {
+            for frame in frames.try_iter() {
+                send_frame(sock, &frame)?;
+            }
+        }
+
+        let text = match sock.read_message() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => return Err(Disconnect::Retry("closed by server".to_owned())),
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(err))
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                continue
+            }
+            Err(err) => return Err(Disconnect::Retry(err.to_string())),
+        };
+
+        trace!("Received socket message: {}", text);
+
+        let frame = match serde_json::from_str(&text) {
+            Ok(frame) => frame,
+            Err(err) => {
+                warn!("Ignoring malformed gateway frame: {}", err);
+                continue;
+            }
+        };
+
+        match frame {
+            Frame::Hello {
+                protocol_version,
+                heartbeat_interval_ms,
+            } => {
+                if protocol_version != PROTOCOL_VERSION {
+                    error!(
+                        "Server speaks gateway protocol v{}, expected v{}",
+                        protocol_version, PROTOCOL_VERSION
+                    );
+                    return Err(Disconnect::Stop);
+                }
+
+                let interval = Duration::from_millis(heartbeat_interval_ms);
+                heartbeat_interval = Some(interval);
+                next_heartbeat = Instant::now() + interval;
+
+                let reply = match session {
+                    Some(session) => Frame::Resume {
+                        session_id: session.id.to_owned(),
+                        seq: session.seq,
+                    },
+                    None => Frame::Identify,
+                };
+                send_frame(sock, &reply)?;
+            }
+            Frame::Ready { session_id } => {
+                debug!("Started gateway session {}", session_id);
+
+                *session = Some(Session {
+                    id: session_id,
+                    seq: 0,
+                });
+                *delay = MIN_RECONNECT_DELAY;
+
+                // anything that happened since the last session is lost
+                if *identified_before && sender.send(GatewayUpdate::Resync).is_err() {
+                    return Ok(());
+                }
+                *identified_before = true;
+                repaint();
+            }
+            Frame::Resumed => {
+                debug!("Resumed gateway session");
+                *delay = MIN_RECONNECT_DELAY;
+            }
+            Frame::InvalidSession => {
+                debug!("Gateway session can't be resumed, starting a new one");
+                *session = None;
+                send_frame(sock, &Frame::Identify)?;
+            }
+            Frame::HeartbeatAck => awaiting_ack = false,
+            Frame::Dispatch { seq, event } => {
+                if let Some(session) = session {
+                    session.seq = seq;
+                }
+
+                // the UI is gone
+                if sender.send(GatewayUpdate::Event(event)).is_err() {
+                    return Ok(());
+                }
+                repaint();
+            }
+            // only ever sent by clients
+            Frame::Identify
+            | Frame::Resume { .. }
+            | Frame::Heartbeat { .. }
+            | Frame::TypingStart { .. } => {}
+        }
+    }
+}
@@ -1,2 +1,3 @@
 mod app;
+mod gateway;
 pub use app::RustCord;
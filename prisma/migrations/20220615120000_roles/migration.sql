@@ -0,0 +1,26 @@
+-- CreateTable
+CREATE TABLE "Role" (
+    "id" TEXT NOT NULL PRIMARY KEY,
+    "name" TEXT NOT NULL,
+    "permissions" INTEGER NOT NULL DEFAULT 0,
+    "position" INTEGER NOT NULL DEFAULT 0,
+    "createdAt" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "guildId" TEXT NOT NULL,
+    CONSTRAINT "Role_guildId_fkey" FOREIGN KEY ("guildId") REFERENCES "Guild" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateTable
+CREATE TABLE "MemberRole" (
+    "createdAt" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "roleId" TEXT NOT NULL,
+    "userId" TEXT NOT NULL,
+    "guildId" TEXT NOT NULL,
+
+    PRIMARY KEY ("roleId", "userId"),
+    CONSTRAINT "MemberRole_roleId_fkey" FOREIGN KEY ("roleId") REFERENCES "Role" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "MemberRole_userId_guildId_fkey" FOREIGN KEY ("userId", "guildId") REFERENCES "GuildMembership" ("userId", "guildId") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- Give every existing guild an @everyone role (CREATE_INVITES | MENTION_EVERYONE)
+INSERT INTO "Role" ("id", "name", "permissions", "position", "guildId")
+SELECT "id", '@everyone', 68, 0, "id" FROM "Guild";
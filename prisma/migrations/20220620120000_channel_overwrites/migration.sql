@@ -0,0 +1,16 @@
+-- CreateTable
+CREATE TABLE "ChannelOverwrite" (
+    "id" TEXT NOT NULL PRIMARY KEY,
+    "targetType" TEXT NOT NULL,
+    "targetId" TEXT NOT NULL,
+    "allow" INTEGER NOT NULL DEFAULT 0,
+    "deny" INTEGER NOT NULL DEFAULT 0,
+    "channelId" TEXT NOT NULL,
+    CONSTRAINT "ChannelOverwrite_channelId_fkey" FOREIGN KEY ("channelId") REFERENCES "Channel" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- CreateIndex
+CREATE UNIQUE INDEX "ChannelOverwrite_channelId_targetId_key" ON "ChannelOverwrite"("channelId", "targetId");
+
+-- Channels were visible to every member so far (VIEW_CHANNEL)
+UPDATE "Role" SET "permissions" = "permissions" | 128 WHERE "id" = "guildId";
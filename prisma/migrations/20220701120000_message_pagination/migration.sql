@@ -0,0 +1,2 @@
+-- CreateIndex
+CREATE INDEX "Message_channelId_createdAt_idx" ON "Message"("channelId", "createdAt");
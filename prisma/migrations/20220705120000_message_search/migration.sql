@@ -0,0 +1,28 @@
+-- CreateVirtualTable
+-- Full-text index over "Message"."content". It stores no copy of the text
+-- itself; the triggers below keep it in sync with the "Message" table.
+CREATE VIRTUAL TABLE "MessageSearch" USING fts5(
+    "content",
+    content='Message',
+    content_rowid='rowid',
+    tokenize='unicode61 remove_diacritics 2'
+);
+
+-- Index existing messages
+INSERT INTO "MessageSearch"("MessageSearch") VALUES ('rebuild');
+
+-- CreateTrigger
+CREATE TRIGGER "Message_search_insert" AFTER INSERT ON "Message" BEGIN
+    INSERT INTO "MessageSearch"("rowid", "content") VALUES (new."rowid", new."content");
+END;
+
+-- CreateTrigger
+CREATE TRIGGER "Message_search_delete" AFTER DELETE ON "Message" BEGIN
+    INSERT INTO "MessageSearch"("MessageSearch", "rowid", "content") VALUES ('delete', old."rowid", old."content");
+END;
+
+-- CreateTrigger
+CREATE TRIGGER "Message_search_update" AFTER UPDATE OF "content" ON "Message" BEGIN
+    INSERT INTO "MessageSearch"("MessageSearch", "rowid", "content") VALUES ('delete', old."rowid", old."content");
+    INSERT INTO "MessageSearch"("rowid", "content") VALUES (new."rowid", new."content");
+END;
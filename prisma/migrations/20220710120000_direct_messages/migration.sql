@@ -0,0 +1,26 @@
+-- CreateTable
+CREATE TABLE "ChannelRecipient" (
+    "createdAt" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "channelId" TEXT NOT NULL,
+    "userId" TEXT NOT NULL,
+
+    PRIMARY KEY ("channelId", "userId"),
+    CONSTRAINT "ChannelRecipient_channelId_fkey" FOREIGN KEY ("channelId") REFERENCES "Channel" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "ChannelRecipient_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+-- RedefineTables
+PRAGMA foreign_keys=OFF;
+CREATE TABLE "new_Channel" (
+    "id" TEXT NOT NULL PRIMARY KEY,
+    "kind" TEXT NOT NULL DEFAULT 'text',
+    "name" TEXT NOT NULL,
+    "createdAt" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "guildId" TEXT,
+    CONSTRAINT "Channel_guildId_fkey" FOREIGN KEY ("guildId") REFERENCES "Guild" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
+INSERT INTO "new_Channel" ("createdAt", "guildId", "id", "name") SELECT "createdAt", "guildId", "id", "name" FROM "Channel";
+DROP TABLE "Channel";
+ALTER TABLE "new_Channel" RENAME TO "Channel";
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
@@ -0,0 +1,3 @@
+-- AlterTable
+ALTER TABLE "User" ADD COLUMN "customStatus" TEXT;
+ALTER TABLE "User" ADD COLUMN "status" TEXT NOT NULL DEFAULT 'online';
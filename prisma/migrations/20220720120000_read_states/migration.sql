@@ -0,0 +1,12 @@
+-- CreateTable
+CREATE TABLE "ReadState" (
+    "lastReadMessageId" TEXT,
+    "lastReadAt" DATETIME,
+    "mentionCount" INTEGER NOT NULL DEFAULT 0,
+    "userId" TEXT NOT NULL,
+    "channelId" TEXT NOT NULL,
+
+    PRIMARY KEY ("userId", "channelId"),
+    CONSTRAINT "ReadState_userId_fkey" FOREIGN KEY ("userId") REFERENCES "User" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "ReadState_channelId_fkey" FOREIGN KEY ("channelId") REFERENCES "Channel" ("id") ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,27 @@
+-- AlterTable
+-- added in place rather than redefining "Message", which would drop the
+-- message search triggers
+ALTER TABLE "Message" ADD COLUMN "replyToId" TEXT REFERENCES "Message" ("id") ON DELETE SET NULL ON UPDATE CASCADE;
+
+-- RedefineTables
+PRAGMA foreign_keys=OFF;
+CREATE TABLE "new_Channel" (
+    "id" TEXT NOT NULL PRIMARY KEY,
+    "kind" TEXT NOT NULL DEFAULT 'text',
+    "name" TEXT NOT NULL,
+    "createdAt" DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "archived" BOOLEAN NOT NULL DEFAULT false,
+    "ownerId" TEXT,
+    "guildId" TEXT,
+    "parentChannelId" TEXT,
+    "parentMessageId" TEXT,
+    CONSTRAINT "Channel_guildId_fkey" FOREIGN KEY ("guildId") REFERENCES "Guild" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "Channel_parentChannelId_fkey" FOREIGN KEY ("parentChannelId") REFERENCES "Channel" ("id") ON DELETE CASCADE ON UPDATE CASCADE,
+    CONSTRAINT "Channel_parentMessageId_fkey" FOREIGN KEY ("parentMessageId") REFERENCES "Message" ("id") ON DELETE SET NULL ON UPDATE CASCADE
+);
+INSERT INTO "new_Channel" ("createdAt", "guildId", "id", "kind", "name") SELECT "createdAt", "guildId", "id", "kind", "name" FROM "Channel";
+DROP TABLE "Channel";
+ALTER TABLE "new_Channel" RENAME TO "Channel";
+CREATE UNIQUE INDEX "Channel_parentMessageId_key" ON "Channel"("parentMessageId");
+PRAGMA foreign_key_check;
+PRAGMA foreign_keys=ON;
@@ -0,0 +1,7 @@
+-- AlterTable
+-- added in place rather than redefining "Message", which would drop the
+-- message search triggers
+ALTER TABLE "Message" ADD COLUMN "pinnedAt" DATETIME;
+
+-- CreateIndex
+CREATE INDEX "Message_channelId_pinnedAt_idx" ON "Message"("channelId", "pinnedAt");
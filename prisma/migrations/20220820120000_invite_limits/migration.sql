@@ -0,0 +1,9 @@
+-- AlterTable
+ALTER TABLE "Invite" ADD COLUMN "maxAge" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "Invite" ADD COLUMN "expiresAt" DATETIME;
+ALTER TABLE "Invite" ADD COLUMN "maxUses" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "Invite" ADD COLUMN "uses" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "Invite" ADD COLUMN "temporary" BOOLEAN NOT NULL DEFAULT false;
+
+-- AlterTable
+ALTER TABLE "GuildMembership" ADD COLUMN "temporary" BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+-- AlterTable
+ALTER TABLE "Guild" ADD COLUMN "description" TEXT;
+ALTER TABLE "Guild" ADD COLUMN "icon" TEXT;
+ALTER TABLE "Guild" ADD COLUMN "iconContentType" TEXT;
+ALTER TABLE "Guild" ADD COLUMN "defaultChannelId" TEXT;
+ALTER TABLE "Guild" ADD COLUMN "systemChannelId" TEXT;
@@ -0,0 +1,7 @@
+-- CreateTrigger
+-- owners are never removed for having joined with a temporary invite, so their
+-- membership is made permanent in the same statement that hands them the guild
+CREATE TRIGGER "Guild_owner_membership" AFTER UPDATE OF "ownerId" ON "Guild" BEGIN
+    UPDATE "GuildMembership" SET "temporary" = false
+    WHERE "guildId" = new."id" AND "userId" = new."ownerId";
+END;
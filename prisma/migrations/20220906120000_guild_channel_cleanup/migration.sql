@@ -0,0 +1,9 @@
+-- CreateTrigger
+-- "defaultChannelId" and "systemChannelId" aren't relations, so they are
+-- cleared here when the channel they point at is deleted
+CREATE TRIGGER "Channel_guild_settings_delete" AFTER DELETE ON "Channel" BEGIN
+    UPDATE "Guild" SET "defaultChannelId" = NULL
+    WHERE "id" = old."guildId" AND "defaultChannelId" = old."id";
+    UPDATE "Guild" SET "systemChannelId" = NULL
+    WHERE "id" = old."guildId" AND "systemChannelId" = old."id";
+END;
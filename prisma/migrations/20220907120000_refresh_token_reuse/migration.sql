@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE "Session" ADD COLUMN "previousRefreshTokenHash" TEXT;
+
+-- CreateIndex
+CREATE INDEX "Session_previousRefreshTokenHash_idx" ON "Session"("previousRefreshTokenHash");
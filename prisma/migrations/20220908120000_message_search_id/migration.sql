@@ -0,0 +1,35 @@
+-- DropTrigger
+DROP TRIGGER "Message_search_insert";
+DROP TRIGGER "Message_search_delete";
+DROP TRIGGER "Message_search_update";
+
+-- DropVirtualTable
+DROP TABLE "MessageSearch";
+
+-- CreateVirtualTable
+-- Full-text index over "Message"."content", keyed by the message id. It keeps
+-- its own copy of the text: an external content table would have to be keyed
+-- by "Message"."rowid", which VACUUM is free to renumber.
+CREATE VIRTUAL TABLE "MessageSearch" USING fts5(
+    "content",
+    "messageId" UNINDEXED,
+    tokenize='unicode61 remove_diacritics 2'
+);
+
+-- Index existing messages
+INSERT INTO "MessageSearch"("content", "messageId") SELECT "content", "id" FROM "Message";
+
+-- CreateTrigger
+CREATE TRIGGER "Message_search_insert" AFTER INSERT ON "Message" BEGIN
+    INSERT INTO "MessageSearch"("content", "messageId") VALUES (new."content", new."id");
+END;
+
+-- CreateTrigger
+CREATE TRIGGER "Message_search_delete" AFTER DELETE ON "Message" BEGIN
+    DELETE FROM "MessageSearch" WHERE "messageId" = old."id";
+END;
+
+-- CreateTrigger
+CREATE TRIGGER "Message_search_update" AFTER UPDATE OF "content" ON "Message" BEGIN
+    UPDATE "MessageSearch" SET "content" = new."content" WHERE "messageId" = old."id";
+END;
@@ -0,0 +1,10 @@
+-- CreateTrigger
+-- every guild starts out with its @everyone role (CREATE_INVITES |
+-- MENTION_EVERYONE | VIEW_CHANNEL, see `Permissions::DEFAULT`) and the owner as
+-- a member, created in the same statement as the guild itself
+CREATE TRIGGER "Guild_defaults" AFTER INSERT ON "Guild" BEGIN
+    INSERT INTO "Role" ("id", "name", "permissions", "position", "createdAt", "guildId")
+    VALUES (new."id", '@everyone', 196, 0, new."createdAt", new."id");
+    INSERT INTO "GuildMembership" ("userId", "guildId", "createdAt", "temporary")
+    VALUES (new."ownerId", new."id", new."createdAt", false);
+END;
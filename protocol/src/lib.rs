@@ -0,0 +1,378 @@
+//! The gateway protocol spoken over `/ws`, shared by the server and the client
+//! so both sides always agree on the shape of every frame.
+
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever a frame or event changes shape. Clients pass it as the `v`
+/// query parameter when connecting, and the server refuses other versions.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// How long a `TypingStart` lasts. Clients keep sending it while the user is
+/// still typing, and stop showing the indicator once it runs out.
+pub const TYPING_TIMEOUT_MS: u64 = 10_000;
+
+/// Every frame sent over the gateway, tagged by its op code:
+/// `{"op": "dispatch", "d": {"seq": 1, "event": {"type": "message_create", ...}}}`.
+///
+/// A connection starts with the server's `Hello`, to which the client answers
+/// with `Identify` for a new session or `Resume` to pick up an old one. After
+/// that the client sends a `Heartbeat` every `heartbeat_interval_ms`, and the
+/// server drops connections that stop doing so.
+// almost every frame is a dispatch, boxing the event would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "d", rename_all = "snake_case")]
+pub enum Frame {
+    /// server: first frame of every connection
+    Hello {
+        protocol_version: u32,
+        heartbeat_interval_ms: u64,
+    },
+    /// client: start a new session
+    Identify,
+    /// client: continue a session after reconnecting, replaying every event
+    /// after `seq`
+    Resume { session_id: String, seq: u64 },
+    /// server: a new session was started
+    Ready { session_id: String },
+    /// server: the session was resumed and missed events were replayed
+    Resumed,
+    /// server: the session can't be resumed (expired, or too many events were
+    /// missed), the client should `Identify` again and refetch its state
+    InvalidSession,
+    /// client: keeps the connection alive, with the last `seq` received
+    Heartbeat { seq: Option<u64> },
+    /// server: reply to every `Heartbeat`
+    HeartbeatAck,
+    /// server: an event the session is subscribed to. `seq` increases by one
+    /// for every event in a session, except ephemeral ones like `TypingStart`
+    /// that repeat the previous `seq` and are never replayed on resume.
+    Dispatch { seq: u64, event: GatewayEvent },
+    /// client: the user is typing in a channel. Rate limited by the server.
+    TypingStart { channel_id: String },
+}
+
+/// Events dispatched to clients.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GatewayEvent {
+    MessageCreate(Message),
+    MessageUpdate(Message),
+    MessageDelete {
+        channel_id: String,
+        id: String,
+    },
+    /// Something about the guild changed (channels, roles, overwrites, ...) or
+    /// it was deleted; clients should refetch it.
+    GuildDataUpdate {
+        guild_id: String,
+    },
+    /// The current user's guild list or roles changed.
+    UserGuildDataUpdate,
+    /// A direct message channel including the current user was opened.
+    ChannelCreate(Channel),
+    /// A user sharing a guild with the current user (or the current user
+    /// themselves) came online, went offline or changed their status.
+    PresenceUpdate(Presence),
+    /// Someone else started typing in a channel, lasting `TYPING_TIMEOUT_MS`
+    /// unless repeated or ended early by a message from them.
+    TypingStart {
+        channel_id: String,
+        user: User,
+    },
+    /// The current user read a channel, possibly from another session.
+    ReadStateUpdate(ReadState),
+    /// A message mentioning the current user, directly, through one of their
+    /// roles or with `@everyone`. Sent even for channels the session isn't
+    /// subscribed to, alongside the usual `MessageCreate`.
+    MessageMention(Message),
+    ReactionAdd(Reaction),
+    ReactionRemove(Reaction),
+    /// A message in the channel was pinned or unpinned; clients should refetch
+    /// its pins.
+    ChannelPinsUpdate {
+        channel_id: String,
+        /// RFC 3339, when the most recent remaining pin was made
+        last_pin_timestamp: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub id: String,
+    pub channel_id: String,
+    pub content: String,
+    pub author: User,
+    /// RFC 3339
+    pub created_at: String,
+    /// RFC 3339, unset until the message is first edited
+    pub edited_at: Option<String>,
+    /// Ids of the users mentioned with `<@user_id>`
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    /// Ids of the roles mentioned with `<@&role_id>`
+    #[serde(default)]
+    pub mention_roles: Vec<String>,
+    #[serde(default)]
+    pub mention_everyone: bool,
+    /// The message this one replies to, unless there is none or it was deleted
+    #[serde(default)]
+    pub reply_to: Option<MessageReference>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub filename: String,
+    pub content_type: String,
+    /// in bytes
+    pub size: u64,
+    /// path to download it from, relative to the instance url
+    pub url: String,
+}
+
+/// A single user's reaction to a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub channel_id: String,
+    pub message_id: String,
+    pub user_id: String,
+    pub emoji: String,
+}
+
+/// Enough of a message to quote it above a reply.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageReference {
+    pub id: String,
+    pub content: String,
+    pub author: User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Idle,
+    /// do not disturb
+    Dnd,
+    Offline,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub user_id: String,
+    pub status: Status,
+    pub custom_status: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadState {
+    pub channel_id: String,
+    pub last_read_message_id: Option<String>,
+    /// mentions of the user after the last read message
+    pub mention_count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Channel {
+    pub id: String,
+    /// "text", "thread", "dm" or "group_dm"
+    pub kind: String,
+    pub name: String,
+    /// only set for direct message channels
+    #[serde(default)]
+    pub recipients: Vec<User>,
+    // the thread fields are also read from the REST API, which spells them in camelCase
+    /// threads only: the channel the thread was started in
+    #[serde(default, alias = "parentChannelId")]
+    pub parent_channel_id: Option<String>,
+    /// threads only: the message the thread was started from, unless it was deleted
+    #[serde(default, alias = "parentMessageId")]
+    pub parent_message_id: Option<String>,
+    #[serde(default)]
+    pub archived: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn user() -> User {
+        User {
+            id: "user".to_owned(),
+            username: "alice".to_owned(),
+        }
+    }
+
+    fn message() -> Message {
+        Message {
+            id: "message".to_owned(),
+            channel_id: "channel".to_owned(),
+            content: "hi <@user>".to_owned(),
+            author: user(),
+            created_at: "2022-06-01T12:00:00+00:00".to_owned(),
+            edited_at: None,
+            mentions: vec!["user".to_owned()],
+            mention_roles: vec![],
+            mention_everyone: false,
+            reply_to: Some(MessageReference {
+                id: "parent".to_owned(),
+                content: "hello".to_owned(),
+                author: user(),
+            }),
+            attachments: vec![Attachment {
+                id: "attachment".to_owned(),
+                filename: "cat.png".to_owned(),
+                content_type: "image/png".to_owned(),
+                size: 1024,
+                url: "/channels/channel/attachments/attachment".to_owned(),
+            }],
+        }
+    }
+
+    fn round_trip(frame: Frame) {
+        let text = serde_json::to_string(&frame).unwrap();
+
+        assert_eq!(serde_json::from_str::<Frame>(&text).unwrap(), frame);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        round_trip(Frame::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            heartbeat_interval_ms: 30_000,
+        });
+        round_trip(Frame::Identify);
+        round_trip(Frame::Resume {
+            session_id: "session".to_owned(),
+            seq: 42,
+        });
+        round_trip(Frame::Ready {
+            session_id: "session".to_owned(),
+        });
+        round_trip(Frame::Resumed);
+        round_trip(Frame::InvalidSession);
+        round_trip(Frame::Heartbeat { seq: None });
+        round_trip(Frame::Heartbeat { seq: Some(7) });
+        round_trip(Frame::HeartbeatAck);
+        round_trip(Frame::TypingStart {
+            channel_id: "channel".to_owned(),
+        });
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let reaction = Reaction {
+            channel_id: "channel".to_owned(),
+            message_id: "message".to_owned(),
+            user_id: "user".to_owned(),
+            emoji: "👍".to_owned(),
+        };
+
+        let events = [
+            GatewayEvent::MessageCreate(message()),
+            GatewayEvent::MessageUpdate(message()),
+            GatewayEvent::MessageDelete {
+                channel_id: "channel".to_owned(),
+                id: "message".to_owned(),
+            },
+            GatewayEvent::GuildDataUpdate {
+                guild_id: "guild".to_owned(),
+            },
+            GatewayEvent::UserGuildDataUpdate,
+            GatewayEvent::ChannelCreate(Channel {
+                id: "channel".to_owned(),
+                kind: "dm".to_owned(),
+                name: String::new(),
+                recipients: vec![user()],
+                parent_channel_id: None,
+                parent_message_id: None,
+                archived: false,
+            }),
+            GatewayEvent::PresenceUpdate(Presence {
+                user_id: "user".to_owned(),
+                status: Status::Dnd,
+                custom_status: Some("busy".to_owned()),
+            }),
+            GatewayEvent::TypingStart {
+                channel_id: "channel".to_owned(),
+                user: user(),
+            },
+            GatewayEvent::ReadStateUpdate(ReadState {
+                channel_id: "channel".to_owned(),
+                last_read_message_id: Some("message".to_owned()),
+                mention_count: 3,
+            }),
+            GatewayEvent::MessageMention(message()),
+            GatewayEvent::ReactionAdd(reaction.clone()),
+            GatewayEvent::ReactionRemove(reaction),
+            GatewayEvent::ChannelPinsUpdate {
+                channel_id: "channel".to_owned(),
+                last_pin_timestamp: None,
+            },
+        ];
+
+        for (seq, event) in events.into_iter().enumerate() {
+            round_trip(Frame::Dispatch {
+                seq: seq as u64,
+                event,
+            });
+        }
+    }
+
+    #[test]
+    fn frames_are_tagged_by_op() {
+        let frame = Frame::Dispatch {
+            seq: 1,
+            event: GatewayEvent::MessageDelete {
+                channel_id: "channel".to_owned(),
+                id: "message".to_owned(),
+            },
+        };
+
+        assert_eq!(
+            serde_json::to_value(&frame).unwrap(),
+            json!({
+                "op": "dispatch",
+                "d": {
+                    "seq": 1,
+                    "event": {
+                        "type": "message_delete",
+                        "channel_id": "channel",
+                        "id": "message",
+                    },
+                },
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(&Frame::Identify).unwrap(),
+            json!({ "op": "identify" })
+        );
+    }
+
+    #[test]
+    fn channels_accept_rest_field_names() {
+        let channel: Channel = serde_json::from_value(json!({
+            "id": "thread",
+            "kind": "thread",
+            "name": "release",
+            "parentChannelId": "channel",
+            "parentMessageId": "message",
+        }))
+        .unwrap();
+
+        assert_eq!(channel.parent_channel_id.as_deref(), Some("channel"));
+        assert_eq!(channel.parent_message_id.as_deref(), Some("message"));
+        assert!(channel.recipients.is_empty() && !channel.archived);
+    }
+}
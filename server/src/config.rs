@@ -0,0 +1,299 @@
+use std::{
+    env, fmt, fs,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::Args;
+
+const DEFAULT_CONFIG_PATH: &str = "rustcord.toml";
+
+/// Server settings, layered from the TOML config file, then environment
+/// variables, then command line arguments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Secret used to sign new access tokens.
+    pub jwt_secret: String,
+    /// Previously used secrets that are still accepted when verifying tokens,
+    /// so the signing secret can be rotated without logging everyone out.
+    pub old_jwt_secrets: Vec<String>,
+    /// Overrides the database url from `schema.prisma` when set.
+    pub database_url: Option<String>,
+    /// How many published events can wait for a gateway session before it
+    /// counts as lagging and its client has to resync.
+    pub broadcast_capacity: usize,
+    pub access_token_ttl_secs: i64,
+    pub refresh_token_ttl_secs: i64,
+    pub listen: Vec<SocketAddr>,
+    /// Internal address `/gateway/metrics` is served on, kept off the public
+    /// listeners. Metrics aren't served at all when unset.
+    pub metrics_listen: Option<SocketAddr>,
+    pub argon2: Argon2Config,
+    pub gateway: GatewayConfig,
+    pub storage: StorageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+    /// How often clients are asked to heartbeat. Connections that miss two
+    /// heartbeats in a row are closed.
+    pub heartbeat_interval_secs: u64,
+    /// How long a disconnected session is kept around to be resumed.
+    pub resume_timeout_secs: u64,
+    /// How many events each session keeps for replaying on resume.
+    pub replay_buffer_size: usize,
+    /// How many frames can wait to be sent on a connection. Clients that fall
+    /// further behind are disconnected and have to resume.
+    pub outbound_queue_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    Local,
+    S3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Directory attachments are kept in by the local backend.
+    pub local_path: String,
+    pub s3: S3Config,
+    /// Largest file that can be attached, in bytes. Guilds can lower it for
+    /// themselves but never raise it.
+    pub max_attachment_size: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// Set for S3-compatible services other than AWS, like a local MinIO.
+    pub endpoint: Option<String>,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Addresses the bucket as `endpoint/bucket` instead of `bucket.endpoint`.
+    pub path_style: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    InvalidVar(&'static str, String),
+    MissingSecret,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            jwt_secret: String::new(),
+            old_jwt_secrets: vec![],
+            database_url: None,
+            broadcast_capacity: 100,
+            access_token_ttl_secs: 15 * 60,
+            refresh_token_ttl_secs: 30 * 86400,
+            listen: vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 3000))],
+            metrics_listen: None,
+            argon2: Argon2Config::default(),
+            gateway: GatewayConfig::default(),
+            storage: StorageConfig::default(),
+        }
+    }
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: 30,
+            resume_timeout_secs: 60,
+            replay_buffer_size: 256,
+            outbound_queue_size: 64,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Local,
+            local_path: "attachments".to_owned(),
+            s3: S3Config::default(),
+            max_attachment_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.apply_args(args);
+
+        if config.jwt_secret.is_empty() {
+            return Err(ConfigError::MissingSecret);
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?;
+
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(secret) = var("RUSTCORD_JWT_SECRET").or_else(|| var("JWT_SECRET")) {
+            self.jwt_secret = secret;
+        }
+
+        if let Some(secrets) = var("RUSTCORD_OLD_JWT_SECRETS") {
+            self.old_jwt_secrets = split_list(&secrets).map(str::to_owned).collect();
+        }
+
+        if let Some(url) = var("DATABASE_URL") {
+            self.database_url = Some(url);
+        }
+
+        if let Some(capacity) = parse_var("RUSTCORD_BROADCAST_CAPACITY")? {
+            self.broadcast_capacity = capacity;
+        }
+
+        if let Some(ttl) = parse_var("RUSTCORD_ACCESS_TOKEN_TTL_SECS")? {
+            self.access_token_ttl_secs = ttl;
+        }
+
+        if let Some(ttl) = parse_var("RUSTCORD_REFRESH_TOKEN_TTL_SECS")? {
+            self.refresh_token_ttl_secs = ttl;
+        }
+
+        if let Some(access_key) = var("RUSTCORD_S3_ACCESS_KEY") {
+            self.storage.s3.access_key = Some(access_key);
+        }
+
+        if let Some(secret_key) = var("RUSTCORD_S3_SECRET_KEY") {
+            self.storage.s3.secret_key = Some(secret_key);
+        }
+
+        if let Some(listen) = var("RUSTCORD_LISTEN") {
+            self.listen = split_list(&listen)
+                .map(|addr| {
+                    SocketAddr::from_str(addr)
+                        .map_err(|_| ConfigError::InvalidVar("RUSTCORD_LISTEN", addr.to_owned()))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(addr) = var("RUSTCORD_METRICS_LISTEN") {
+            self.metrics_listen = Some(SocketAddr::from_str(&addr).map_err(|_| {
+                ConfigError::InvalidVar("RUSTCORD_METRICS_LISTEN", addr.to_owned())
+            })?);
+        }
+
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if args.host.is_some() || args.port.is_some() {
+            let default = self
+                .listen
+                .first()
+                .copied()
+                .unwrap_or_else(|| SocketAddr::from((Ipv6Addr::LOCALHOST, 3000)));
+
+            let ip = args
+                .host
+                .as_deref()
+                .map(|host| IpAddr::from_str(host).unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST)))
+                .unwrap_or_else(|| default.ip());
+
+            self.listen = vec![SocketAddr::new(
+                ip,
+                args.port.unwrap_or_else(|| default.port()),
+            )];
+        }
+
+        if let Some(memory_kib) = args.argon2_memory_kib {
+            self.argon2.memory_kib = memory_kib;
+        }
+
+        if let Some(iterations) = args.argon2_iterations {
+            self.argon2.iterations = iterations;
+        }
+
+        if let Some(parallelism) = args.argon2_parallelism {
+            self.argon2.parallelism = parallelism;
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "could not read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "could not parse {}: {}", path, err),
+            ConfigError::InvalidVar(name, value) => write!(f, "invalid {}: {:?}", name, value),
+            ConfigError::MissingSecret => write!(
+                f,
+                "no JWT secret configured; set `jwt_secret` in {} or the JWT_SECRET variable",
+                DEFAULT_CONFIG_PATH
+            ),
+        }
+    }
+}
+
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_var<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| ConfigError::InvalidVar(name, value))
+        })
+        .transpose()
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
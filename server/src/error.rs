@@ -0,0 +1,164 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::error;
+use serde_json::json;
+
+use crate::{permissions::Permissions, storage::StorageError};
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// Kinds of rows a request can refer to, used for `unknown_*` error codes.
+#[derive(Debug, Clone, Copy)]
+pub enum Resource {
+    User,
+    Session,
+    Guild,
+    Channel,
+    Message,
+    Invite,
+    Role,
+    Member,
+    Reaction,
+    Attachment,
+    Ban,
+    ReadState,
+    Overwrite,
+}
+
+/// Every error a handler can return. Each variant maps to a status code and a
+/// stable machine-readable `code`; the `error` message is for humans only.
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(Resource),
+    Unauthorized,
+    InvalidCredentials,
+    Forbidden(&'static str),
+    MissingPermissions(Permissions),
+    BadRequest(String),
+    Conflict(&'static str),
+    /// A file or a whole upload request went over its size limit, in bytes.
+    PayloadTooLarge(u64),
+    Database(prisma_client_rust::queries::Error),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound(resource) => match resource {
+                Resource::User => "unknown_user",
+                Resource::Session => "unknown_session",
+                Resource::Guild => "unknown_guild",
+                Resource::Channel => "unknown_channel",
+                Resource::Message => "unknown_message",
+                Resource::Invite => "unknown_invite",
+                Resource::Role => "unknown_role",
+                Resource::Member => "unknown_member",
+                Resource::Reaction => "unknown_reaction",
+                Resource::Attachment => "unknown_attachment",
+                Resource::Ban => "unknown_ban",
+                Resource::ReadState => "unknown_read_state",
+                Resource::Overwrite => "unknown_overwrite",
+            },
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::MissingPermissions(_) => "missing_permissions",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::Database(_) | ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::InvalidCredentials
+            | ApiError::Forbidden(_)
+            | ApiError::MissingPermissions(_) => StatusCode::FORBIDDEN,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::NotFound(resource) => format!("{:?} not found.", resource),
+            ApiError::Unauthorized => "Missing or invalid access token.".to_owned(),
+            ApiError::InvalidCredentials => "Incorrect username or password.".to_owned(),
+            ApiError::Forbidden(message) | ApiError::Conflict(message) => (*message).to_owned(),
+            ApiError::MissingPermissions(missing) => {
+                format!("Missing permissions: {:?}.", missing)
+            }
+            ApiError::BadRequest(message) => message.to_owned(),
+            ApiError::PayloadTooLarge(limit) => {
+                format!("Uploads can be at most {} bytes.", limit)
+            }
+            // don't leak query or internal details to clients
+            ApiError::Database(_) | ApiError::Internal(_) => "An error occured.".to_owned(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match &self {
+            ApiError::Database(err) => error!("Database error: {:?}", err),
+            ApiError::Internal(err) => error!("Internal error: {}", err),
+            _ => {}
+        }
+
+        (
+            self.status(),
+            Json(json!({
+                "code": self.code(),
+                "error": self.message(),
+            })),
+        )
+            .into_response()
+    }
+}
+
+impl From<prisma_client_rust::queries::Error> for ApiError {
+    fn from(err: prisma_client_rust::queries::Error) -> Self {
+        if is_unique_violation(&err) {
+            return ApiError::Conflict("A resource with these details already exists.");
+        }
+
+        ApiError::Database(err)
+    }
+}
+
+/// Whether the query failed on a unique constraint, prisma's known error P2002.
+fn is_unique_violation(err: &prisma_client_rust::queries::Error) -> bool {
+    match err {
+        prisma_client_rust::queries::Error::Execute(err) => err
+            .as_known()
+            .map_or(false, |known| known.error_code == "P2002"),
+        _ => false,
+    }
+}
+
+impl From<StorageError> for ApiError {
+    fn from(err: StorageError) -> Self {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+/// Turns a missing row into the matching `unknown_*` error.
+pub trait OrNotFound<T> {
+    fn or_not_found(self, resource: Resource) -> ApiResult<T>;
+}
+
+impl<T> OrNotFound<T> for Option<T> {
+    fn or_not_found(self, resource: Resource) -> ApiResult<T> {
+        self.ok_or(ApiError::NotFound(resource))
+    }
+}
@@ -0,0 +1,645 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use protocol::{Frame, GatewayEvent, Presence, Status};
+use rand::RngCore;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    config::GatewayConfig,
+    routes::socket::{SocketMessageType, SocketPayload},
+};
+
+/// How often a user can announce typing in the same channel.
+const TYPING_RATE_LIMIT: Duration = Duration::from_secs(5);
+
+/// Gateway sessions outlive the connections they were started on, so a client
+/// that reconnects shortly after dropping can resume where it left off.
+pub struct GatewaySessions {
+    config: GatewayConfig,
+    inbox_capacity: usize,
+    sessions: Mutex<HashMap<String, Arc<GatewaySession>>>,
+    registry: Mutex<Registry>,
+    /// the chosen presence of every user with at least one session
+    presences: Mutex<HashMap<String, Presence>>,
+    /// when each (user, channel) last announced typing
+    typing: Mutex<HashMap<(String, String), Instant>>,
+    metrics: Arc<GatewayMetrics>,
+    /// gets the id of every user whose last session went away
+    departures: mpsc::UnboundedSender<String>,
+}
+
+/// What a session is allowed to receive: the guilds its user is in, the
+/// channels they can see in them and their direct message channels.
+#[derive(Default)]
+pub struct Subscriptions {
+    pub guild_ids: HashSet<String>,
+    pub channel_ids: HashSet<String>,
+}
+
+/// Which sessions are interested in which guild, channel and user, so that
+/// published events only reach the sessions that will actually use them.
+#[derive(Default)]
+struct Registry {
+    /// where published events for each session go
+    inboxes: HashMap<String, mpsc::Sender<SocketPayload>>,
+    by_user: HashMap<String, HashSet<String>>,
+    by_guild: HashMap<String, HashSet<String>>,
+    by_channel: HashMap<String, HashSet<String>>,
+    /// what each session is currently registered under, so it can be unregistered
+    subscriptions: HashMap<String, Subscriptions>,
+}
+
+/// Counters for events that never made it to a client, served at `/gateway/metrics`
+/// on the internal `metrics_listen` address.
+#[derive(Default)]
+pub struct GatewayMetrics {
+    /// published events that didn't fit in a session's inbox
+    lagged_events: AtomicU64,
+    /// sessions invalidated because of lag, whose clients had to resync
+    invalidated_sessions: AtomicU64,
+    /// frames that didn't fit in a connection's outbound queue
+    dropped_frames: AtomicU64,
+    /// connections closed for not keeping up with their outbound queue
+    slow_connections: AtomicU64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    sessions: usize,
+    lagged_events: u64,
+    invalidated_sessions: u64,
+    dropped_frames: u64,
+    slow_connections: u64,
+}
+
+impl GatewayMetrics {
+    fn record_lag(&self) {
+        self.lagged_events.fetch_add(1, Ordering::Relaxed);
+        self.invalidated_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dropped_frame(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_slow_connection(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+        self.slow_connections.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn add_to(index: &mut HashMap<String, HashSet<String>>, key: &str, session_id: &str) {
+    index
+        .entry(key.to_owned())
+        .or_default()
+        .insert(session_id.to_owned());
+}
+
+fn remove_from(index: &mut HashMap<String, HashSet<String>>, key: &str, session_id: &str) {
+    if let Some(session_ids) = index.get_mut(key) {
+        session_ids.remove(session_id);
+
+        if session_ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+impl Registry {
+    fn subscribe(&mut self, session_id: &str, subscriptions: Subscriptions) {
+        self.unsubscribe(session_id);
+
+        for guild_id in &subscriptions.guild_ids {
+            add_to(&mut self.by_guild, guild_id, session_id);
+        }
+        for channel_id in &subscriptions.channel_ids {
+            add_to(&mut self.by_channel, channel_id, session_id);
+        }
+
+        self.subscriptions
+            .insert(session_id.to_owned(), subscriptions);
+    }
+
+    fn unsubscribe(&mut self, session_id: &str) {
+        if let Some(subscriptions) = self.subscriptions.remove(session_id) {
+            for guild_id in &subscriptions.guild_ids {
+                remove_from(&mut self.by_guild, guild_id, session_id);
+            }
+            for channel_id in &subscriptions.channel_ids {
+                remove_from(&mut self.by_channel, channel_id, session_id);
+            }
+        }
+    }
+
+    /// The sessions an event is meant for.
+    fn targets(&self, message: &SocketMessageType) -> HashSet<&String> {
+        let keys: Vec<(&HashMap<String, HashSet<String>>, &String)> = match message {
+            SocketMessageType::NewMessage(_, channel_id)
+            | SocketMessageType::MessageUpdate(_, channel_id)
+            | SocketMessageType::MessageDelete { channel_id, .. }
+            | SocketMessageType::TypingStart { channel_id, .. }
+            | SocketMessageType::ChannelPinsUpdate { channel_id, .. } => {
+                vec![(&self.by_channel, channel_id)]
+            }
+            SocketMessageType::ReactionAdd(reaction)
+            | SocketMessageType::ReactionRemove(reaction) => {
+                vec![(&self.by_channel, &reaction.channel_id)]
+            }
+            SocketMessageType::GuildDataUpdate(guild_id) => vec![(&self.by_guild, guild_id)],
+            SocketMessageType::UserGuildDataUpdate(user_id)
+            | SocketMessageType::ReadStateUpdate(_, user_id) => vec![(&self.by_user, user_id)],
+            SocketMessageType::ChannelCreate(_, user_ids)
+            | SocketMessageType::MessageMention(_, user_ids) => user_ids
+                .iter()
+                .map(|user_id| (&self.by_user, user_id))
+                .collect(),
+            // everyone sharing a guild, and the user's other sessions
+            SocketMessageType::PresenceUpdate(presence, guild_ids) => guild_ids
+                .iter()
+                .map(|guild_id| (&self.by_guild, guild_id))
+                .chain([(&self.by_user, &presence.user_id)])
+                .collect(),
+        };
+
+        keys.into_iter()
+            .filter_map(|(index, key)| index.get(key))
+            .flatten()
+            .collect()
+    }
+
+    /// Every guild any of a user's sessions is subscribed to.
+    fn guild_ids_of(&self, user_id: &str) -> Vec<String> {
+        let guild_ids: HashSet<&String> = self
+            .by_user
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|session_id| self.subscriptions.get(session_id))
+            .flat_map(|subscriptions| &subscriptions.guild_ids)
+            .collect();
+
+        guild_ids.into_iter().cloned().collect()
+    }
+}
+
+/// What others get to see: users who chose to appear offline look offline.
+fn visible(presence: &Presence) -> Presence {
+    match presence.status {
+        Status::Offline => offline(&presence.user_id),
+        _ => presence.clone(),
+    }
+}
+
+fn offline(user_id: &str) -> Presence {
+    Presence {
+        user_id: user_id.to_owned(),
+        status: Status::Offline,
+        custom_status: None,
+    }
+}
+
+impl GatewaySessions {
+    /// `inbox_capacity` is how many published events can wait for a session
+    /// before it counts as lagging. Also returns where the ids of users whose
+    /// last session went away arrive, however it went away.
+    pub fn new(
+        config: GatewayConfig,
+        inbox_capacity: usize,
+    ) -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (departures, departed) = mpsc::unbounded_channel();
+
+        let sessions = Self {
+            config,
+            inbox_capacity,
+            sessions: Mutex::default(),
+            registry: Mutex::default(),
+            presences: Mutex::default(),
+            typing: Mutex::default(),
+            metrics: Arc::default(),
+            departures,
+        };
+
+        (sessions, departed)
+    }
+
+    /// Starts a session subscribed to `subscriptions`, returning it along with
+    /// the inbox its published events arrive in. The user's first session
+    /// brings them online with their chosen `presence`.
+    pub fn create(
+        &self,
+        user_id: String,
+        login_session_id: String,
+        subscriptions: Subscriptions,
+        presence: Presence,
+    ) -> (Arc<GatewaySession>, mpsc::Receiver<SocketPayload>) {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+
+        let session = Arc::new(GatewaySession {
+            id: hex::encode(bytes),
+            user_id,
+            login_session_id,
+            replay_buffer_size: self.config.replay_buffer_size,
+            outbound_queue_size: self.config.outbound_queue_size,
+            metrics: self.metrics.clone(),
+            // counts as detached until the first connection attaches
+            inner: Mutex::new(SessionInner {
+                detached_at: Some(Instant::now()),
+                ..SessionInner::default()
+            }),
+        });
+
+        let (sender, inbox) = mpsc::channel(self.inbox_capacity);
+
+        let guild_ids: Vec<String> = subscriptions.guild_ids.iter().cloned().collect();
+
+        let mut registry = self.registry.lock().unwrap();
+        registry.inboxes.insert(session.id.to_owned(), sender);
+        add_to(&mut registry.by_user, &session.user_id, &session.id);
+        registry.subscribe(&session.id, subscriptions);
+        drop(registry);
+
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.id.to_owned(), session.clone());
+
+        let came_online = {
+            let mut presences = self.presences.lock().unwrap();
+
+            if presences.contains_key(&session.user_id) {
+                None
+            } else {
+                presences.insert(session.user_id.to_owned(), presence.clone());
+                Some(visible(&presence))
+            }
+        };
+
+        if let Some(presence) = came_online.filter(|presence| presence.status != Status::Offline) {
+            self.publish(SocketPayload {
+                message: SocketMessageType::PresenceUpdate(presence, guild_ids),
+            });
+        }
+
+        (session, inbox)
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<Arc<GatewaySession>> {
+        self.sessions.lock().unwrap().get(session_id).cloned()
+    }
+
+    /// Replaces what a session is subscribed to, after its user's guilds or
+    /// channel permissions changed.
+    pub fn set_subscriptions(&self, session_id: &str, subscriptions: Subscriptions) {
+        let mut registry = self.registry.lock().unwrap();
+
+        // the session may have been removed while its subscriptions were loading
+        if registry.inboxes.contains_key(session_id) {
+            registry.subscribe(session_id, subscriptions);
+        }
+    }
+
+    /// Ends every session started with a revoked login session. Their clients
+    /// are told to start over, which takes a valid token.
+    pub fn close_login_session(&self, login_session_id: &str) {
+        let sessions: Vec<Arc<GatewaySession>> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.login_session_id == login_session_id)
+            .cloned()
+            .collect();
+
+        for session in sessions {
+            session.invalidate();
+            self.remove(&session.id);
+        }
+    }
+
+    /// Forgets a session. Its inbox is closed, which stops its pump. The
+    /// user's last session going away takes them offline and reports them as
+    /// departed.
+    pub fn remove(&self, session_id: &str) {
+        let session = match self.sessions.lock().unwrap().remove(session_id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        let mut registry = self.registry.lock().unwrap();
+
+        let guild_ids = registry.guild_ids_of(&session.user_id);
+
+        registry.inboxes.remove(session_id);
+        registry.unsubscribe(session_id);
+        remove_from(&mut registry.by_user, &session.user_id, session_id);
+
+        let last_session = !registry.by_user.contains_key(&session.user_id);
+        drop(registry);
+
+        if !last_session {
+            return;
+        }
+
+        self.departures.send(session.user_id.to_owned()).ok();
+
+        let presence = self.presences.lock().unwrap().remove(&session.user_id);
+
+        if let Some(presence) = presence.filter(|presence| presence.status != Status::Offline) {
+            self.publish(SocketPayload {
+                message: SocketMessageType::PresenceUpdate(offline(&presence.user_id), guild_ids),
+            });
+        }
+    }
+
+    /// Whether the user has any session, attached or waiting to be resumed.
+    pub fn is_connected(&self, user_id: &str) -> bool {
+        self.registry.lock().unwrap().by_user.contains_key(user_id)
+    }
+
+    /// The presence other users see for `user_id`.
+    pub fn presence(&self, user_id: &str) -> Presence {
+        self.presences
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .map(visible)
+            .unwrap_or_else(|| offline(user_id))
+    }
+
+    /// Changes the chosen presence of a connected user, and tells everyone
+    /// sharing a guild with them. Offline users stay offline until they connect.
+    pub fn set_presence(&self, presence: Presence) {
+        let previous = {
+            let mut presences = self.presences.lock().unwrap();
+
+            match presences.get_mut(&presence.user_id) {
+                Some(current) => std::mem::replace(current, presence.clone()),
+                None => return,
+            }
+        };
+
+        if visible(&previous) == visible(&presence) {
+            return;
+        }
+
+        let guild_ids = self
+            .registry
+            .lock()
+            .unwrap()
+            .guild_ids_of(&presence.user_id);
+
+        self.publish(SocketPayload {
+            message: SocketMessageType::PresenceUpdate(visible(&presence), guild_ids),
+        });
+    }
+
+    /// Hands an event to every session subscribed to it. Sessions whose inbox
+    /// is full have fallen too far behind, so they are invalidated and their
+    /// clients resync instead of carrying on with events silently missing.
+    pub fn publish(&self, payload: SocketPayload) {
+        let mut lagging = vec![];
+
+        {
+            let registry = self.registry.lock().unwrap();
+
+            for session_id in registry.targets(&payload.message) {
+                let inbox = match registry.inboxes.get(session_id) {
+                    Some(inbox) => inbox,
+                    None => continue,
+                };
+
+                if let Err(TrySendError::Full(_)) = inbox.try_send(payload.clone()) {
+                    lagging.push(session_id.to_owned());
+                }
+            }
+        }
+
+        for session_id in lagging {
+            warn!("Session {} lagged behind, invalidating it", session_id);
+            self.metrics.record_lag();
+
+            if let Some(session) = self.get(&session_id) {
+                session.invalidate();
+            }
+            self.remove(&session_id);
+        }
+    }
+
+    /// Whether a session may announce typing in a channel right now. It has to
+    /// be able to see the channel, and is rate limited per user and channel.
+    pub fn allow_typing(&self, session: &GatewaySession, channel_id: &str) -> bool {
+        let can_see = self
+            .registry
+            .lock()
+            .unwrap()
+            .subscriptions
+            .get(&session.id)
+            .map_or(false, |subscriptions| {
+                subscriptions.channel_ids.contains(channel_id)
+            });
+
+        if !can_see {
+            return false;
+        }
+
+        let mut typing = self.typing.lock().unwrap();
+
+        // forget whatever ran out, so this doesn't grow forever
+        typing.retain(|_, started_at| started_at.elapsed() < TYPING_RATE_LIMIT);
+
+        match typing.entry((session.user_id.to_owned(), channel_id.to_owned())) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Instant::now());
+                true
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let metrics = &self.metrics;
+
+        MetricsSnapshot {
+            sessions: self.sessions.lock().unwrap().len(),
+            lagged_events: metrics.lagged_events.load(Ordering::Relaxed),
+            invalidated_sessions: metrics.invalidated_sessions.load(Ordering::Relaxed),
+            dropped_frames: metrics.dropped_frames.load(Ordering::Relaxed),
+            slow_connections: metrics.slow_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct GatewaySession {
+    pub id: String,
+    pub user_id: String,
+    /// the login `Session` whose access token started it
+    pub login_session_id: String,
+    replay_buffer_size: usize,
+    outbound_queue_size: usize,
+    metrics: Arc<GatewayMetrics>,
+    inner: Mutex<SessionInner>,
+}
+
+#[derive(Default)]
+struct SessionInner {
+    /// `seq` of the last dispatched event
+    seq: u64,
+    /// the most recent events, oldest first
+    buffer: VecDeque<(u64, GatewayEvent)>,
+    /// where frames go while a connection is attached
+    connection: Option<mpsc::Sender<Frame>>,
+    /// bumped on every attach, so a replaced connection can't detach its successor
+    generation: u64,
+    detached_at: Option<Instant>,
+    /// set once events were lost, after which the session can't be resumed
+    invalidated: bool,
+}
+
+/// Handed to the connection that is currently attached to a session.
+pub struct Attachment {
+    /// the connection's outbound queue, closed when it should disconnect
+    pub frames: mpsc::Receiver<Frame>,
+    pub generation: u64,
+}
+
+impl GatewaySession {
+    /// Numbers and buffers an event, and forwards it to the attached connection.
+    pub fn dispatch(&self, event: GatewayEvent) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.seq += 1;
+        let seq = inner.seq;
+
+        if inner.buffer.len() == self.replay_buffer_size {
+            inner.buffer.pop_front();
+        }
+        inner.buffer.push_back((seq, event.clone()));
+
+        if let Some(connection) = &inner.connection {
+            let frame = Frame::Dispatch { seq, event };
+
+            if let Err(TrySendError::Full(_)) = connection.try_send(frame) {
+                // the client isn't reading fast enough. Closing the queue disconnects it, and
+                // it can resume from the replay buffer once it catches up.
+                warn!("Session {} is not keeping up, disconnecting it", self.id);
+                self.metrics.record_slow_connection();
+
+                inner.connection = None;
+                inner.detached_at = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Forwards an event only worth something right now, like typing, to the
+    /// attached connection. It isn't buffered for replay and repeats the last
+    /// `seq`, and is dropped rather than disconnecting a slow client.
+    pub fn dispatch_ephemeral(&self, event: GatewayEvent) {
+        let inner = self.inner.lock().unwrap();
+
+        if let Some(connection) = &inner.connection {
+            let frame = Frame::Dispatch {
+                seq: inner.seq,
+                event,
+            };
+
+            if let Err(TrySendError::Full(_)) = connection.try_send(frame) {
+                self.metrics.record_dropped_frame();
+            }
+        }
+    }
+
+    /// Tells the attached connection that events were lost and disconnects it.
+    /// The client has to start a new session and refetch everything.
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(connection) = inner.connection.take() {
+            connection.try_send(Frame::InvalidSession).ok();
+        }
+
+        inner.invalidated = true;
+        inner.detached_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Attaches a new connection, replacing the previous one if it is still around.
+    /// Resuming (`resume_from` set) first replays every event after that `seq`, and
+    /// fails if some of them have already been dropped from the buffer.
+    pub fn attach(&self, resume_from: Option<u64>) -> Option<Attachment> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.invalidated {
+            return None;
+        }
+
+        let mut queued = vec![];
+
+        match resume_from {
+            Some(seq) => {
+                let missed = inner.seq.checked_sub(seq)? as usize;
+
+                if missed > inner.buffer.len() {
+                    return None;
+                }
+
+                let replay_start = inner.buffer.len() - missed;
+                queued.extend(inner.buffer.range(replay_start..).map(|(seq, event)| {
+                    Frame::Dispatch {
+                        seq: *seq,
+                        event: event.clone(),
+                    }
+                }));
+                queued.push(Frame::Resumed);
+            }
+            None => queued.push(Frame::Ready {
+                session_id: self.id.to_owned(),
+            }),
+        }
+
+        // the replay has to fit in the queue on top of the usual headroom
+        let (sender, frames) = mpsc::channel(self.outbound_queue_size + queued.len());
+
+        for frame in queued {
+            sender.try_send(frame).ok();
+        }
+
+        inner.generation += 1;
+        inner.connection = Some(sender);
+        inner.detached_at = None;
+
+        Some(Attachment {
+            frames,
+            generation: inner.generation,
+        })
+    }
+
+    /// Called when a connection goes away. Events keep being buffered until the
+    /// session is resumed or expires.
+    pub fn detach(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.generation == generation {
+            inner.connection = None;
+            inner.detached_at = Some(Instant::now());
+        }
+    }
+
+    /// Whether the session has gone without a connection for longer than `timeout`.
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        inner
+            .detached_at
+            .map_or(false, |detached_at| detached_at.elapsed() > timeout)
+    }
+}
@@ -1,84 +1,246 @@
+mod config;
+mod error;
+mod gateway;
+mod mentions;
 mod middleware;
+mod password;
+mod permissions;
 mod prisma;
 mod routes;
+mod session;
+mod storage;
 
 use axum::{
     extract::Extension,
-    routing::{delete, get, post},
+    handler::Handler,
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use clap::Parser;
-use log::info;
+use config::Config;
+use gateway::GatewaySessions;
+use log::{error, info};
+use password::PasswordHasher;
 use prisma::PrismaClient;
-use routes::socket::SocketPayload;
 use serde::{Deserialize, Serialize};
-use std::{
-    net::{IpAddr, Ipv6Addr, SocketAddr},
-    str::FromStr,
-    sync::Arc,
-};
-use tokio::sync::broadcast;
+use std::{process, sync::Arc};
+use storage::Storage;
+use tokio::sync::RwLock;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, default_value = "::1")]
-    host: String,
+    /// Path to the TOML config file (defaults to rustcord.toml if present)
+    #[clap(short, long)]
+    config: Option<String>,
+
+    #[clap(short, long)]
+    host: Option<String>,
+
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// Argon2id memory cost in KiB
+    #[clap(long)]
+    argon2_memory_kib: Option<u32>,
+
+    /// Argon2id iteration count
+    #[clap(long)]
+    argon2_iterations: Option<u32>,
 
-    #[clap(short, long, default_value = "3000")]
-    port: u16,
+    /// Argon2id degree of parallelism
+    #[clap(long)]
+    argon2_parallelism: Option<u32>,
 }
 
 pub struct State {
+    config: Config,
     prisma: PrismaClient,
-    tx: broadcast::Sender<SocketPayload>,
+    passwords: PasswordHasher,
+    gateway: GatewaySessions,
+    storage: Box<dyn Storage>,
+    /// held for reading while blobs are stored but not used by any row yet,
+    /// and for writing while unused ones are swept
+    blob_sweep: RwLock<()>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Claims {
     username: String,
     id: String,
+    /// id of the `Session` the token was issued for
+    sid: String,
     exp: usize,
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct User {
-    id: String,
-    username: String,
-}
-
 #[tokio::main]
 async fn main() {
+    dotenv::dotenv().ok();
+
     pretty_env_logger::init();
 
     let args = Args::parse();
 
-    let client = prisma::new_client().await;
+    let config = Config::load(&args).unwrap_or_else(|err| {
+        error!("Invalid configuration: {}", err);
+        process::exit(1);
+    });
+
+    let client = match &config.database_url {
+        Some(url) => prisma::new_client_with_url(url).await,
+        None => prisma::new_client().await,
+    };
     let prisma = client.unwrap();
 
-    let (tx, _rx) = broadcast::channel(100);
+    let passwords = PasswordHasher::new(
+        config.argon2.memory_kib,
+        config.argon2.iterations,
+        config.argon2.parallelism,
+    )
+    .expect("invalid Argon2 parameters");
+
+    let listen = config.listen.clone();
+    let metrics_listen = config.metrics_listen;
+
+    let (gateway, departures) =
+        GatewaySessions::new(config.gateway.clone(), config.broadcast_capacity);
+
+    let storage = storage::from_config(&config.storage)
+        .await
+        .unwrap_or_else(|err| {
+            error!("Could not set up attachment storage: {}", err);
+            process::exit(1);
+        });
+
+    let shared_state = Arc::new(State {
+        config,
+        prisma,
+        passwords,
+        gateway,
+        storage,
+        blob_sweep: RwLock::default(),
+    });
 
-    let shared_state = Arc::new(State { prisma, tx });
+    tokio::spawn(routes::attachments::sweep_unused_blobs(
+        shared_state.clone(),
+    ));
+
+    tokio::spawn(routes::invites::remove_departed_temporary_members(
+        shared_state.clone(),
+        departures,
+    ));
 
     let router = Router::new()
         .route("/", get(root))
         .route("/register", post(routes::auth::register))
-        .route("/login", post(routes::auth::login));
+        .route("/login", post(routes::auth::login))
+        .route("/token/refresh", post(routes::auth::refresh))
+        // previews are public, revoking one needs an account
+        .route(
+            "/invites/:code",
+            get(routes::invites::get_invite).delete(
+                routes::invites::delete_invite
+                    .layer(axum::middleware::from_fn(middleware::auth::auth)),
+            ),
+        );
 
     let authenticated_user_router = Router::new()
         .route("/ws", get(routes::socket::upgrade))
+        .route("/logout", post(routes::auth::logout))
         .route("/users/me", get(routes::users::me))
+        .route("/users/me/sessions", get(routes::users::get_user_sessions))
+        .route(
+            "/users/me/sessions/:session_id",
+            delete(routes::users::revoke_user_session),
+        )
         .route("/users/me/guilds", get(routes::users::get_user_guilds))
+        .route(
+            "/users/me/guilds/:guild_id",
+            delete(routes::members::leave_guild),
+        )
+        .route(
+            "/users/me/presence",
+            get(routes::users::get_user_presence).patch(routes::users::update_user_presence),
+        )
+        .route(
+            "/users/me/channels",
+            get(routes::users::get_user_channels).post(routes::users::create_user_channel),
+        )
         .route(
             "/channels/:channel_id/messages",
             get(routes::channels::get_channel_messages),
         )
         .route(
             "/channels/:channel_id/messages",
-            post(routes::channels::post_channel_messages),
+            post(
+                routes::channels::post_channel_messages
+                    .layer(axum::middleware::from_fn(middleware::limit::limit_body)),
+            ),
+        )
+        .route(
+            "/channels/:channel_id/messages/:message_id",
+            patch(routes::channels::edit_channel_message)
+                .delete(routes::channels::delete_channel_message),
+        )
+        .route(
+            "/channels/:channel_id/messages/:message_id/ack",
+            post(routes::read_states::ack_message),
+        )
+        .route(
+            "/channels/:channel_id/messages/:message_id/reactions/:emoji",
+            get(routes::reactions::get_reactors),
+        )
+        .route(
+            "/channels/:channel_id/messages/:message_id/reactions/:emoji/@me",
+            put(routes::reactions::add_reaction).delete(routes::reactions::remove_reaction),
+        )
+        .route(
+            "/channels/:channel_id/attachments/:attachment_id",
+            get(routes::attachments::get_attachment),
+        )
+        .route(
+            "/channels/:channel_id/overwrites",
+            get(routes::channels::get_channel_overwrites),
+        )
+        .route(
+            "/channels/:channel_id/overwrites/:target_id",
+            put(routes::channels::put_channel_overwrite)
+                .delete(routes::channels::delete_channel_overwrite),
+        )
+        .route("/channels/:channel_id/pins", get(routes::pins::get_pins))
+        .route(
+            "/channels/:channel_id/pins/:message_id",
+            put(routes::pins::pin_message).delete(routes::pins::unpin_message),
+        )
+        .route(
+            "/channels/:channel_id/threads",
+            get(routes::threads::get_threads).post(routes::threads::create_thread),
+        )
+        .route(
+            "/channels/:channel_id/archive",
+            put(routes::threads::archive_thread).delete(routes::threads::unarchive_thread),
         )
         .route("/guilds/create", post(routes::guilds::create_guild))
+        .route(
+            "/guilds/:guild_id",
+            patch(
+                routes::guilds::update_guild
+                    .layer(axum::middleware::from_fn(middleware::limit::limit_body)),
+            ),
+        )
+        .route(
+            "/guilds/:guild_id/attachment-limit",
+            put(routes::attachments::set_attachment_limit),
+        )
+        .route(
+            "/guilds/:guild_id/transfer",
+            post(routes::guilds::transfer_guild),
+        )
+        .route(
+            "/guilds/:guild_id/icon",
+            get(routes::guilds::get_guild_icon),
+        )
         .route(
             "/guilds/:guild_id/delete",
             delete(routes::guilds::delete_guild),
@@ -87,29 +249,76 @@ async fn main() {
             "/guilds/:guild_id/channels/create",
             post(routes::guilds::create_channel),
         )
+        .route(
+            "/guilds/:guild_id/members",
+            get(routes::guilds::get_guild_members),
+        )
+        .route(
+            "/guilds/:guild_id/members/:user_id",
+            delete(routes::members::kick_member),
+        )
+        .route("/guilds/:guild_id/bans", get(routes::members::get_bans))
+        .route(
+            "/guilds/:guild_id/bans/:user_id",
+            put(routes::members::ban_member).delete(routes::members::unban_member),
+        )
+        .route(
+            "/guilds/:guild_id/invites",
+            get(routes::invites::get_guild_invites),
+        )
         .route(
             "/guilds/:guild_id/invites/create",
-            post(routes::guilds::create_invite),
+            post(routes::invites::create_invite),
+        )
+        .route(
+            "/guilds/:guild_id/messages/search",
+            get(routes::search::search_guild_messages),
+        )
+        .route(
+            "/guilds/:guild_id/roles",
+            get(routes::roles::get_roles).post(routes::roles::create_role),
         )
-        .route("/guilds/join", post(routes::guilds::join_guild))
+        .route(
+            "/guilds/:guild_id/roles/:role_id",
+            patch(routes::roles::update_role).delete(routes::roles::delete_role),
+        )
+        .route(
+            "/guilds/:guild_id/members/:user_id/roles/:role_id",
+            put(routes::roles::add_member_role).delete(routes::roles::remove_member_role),
+        )
+        .route("/guilds/join", post(routes::invites::join_guild))
         .route_layer(axum::middleware::from_fn(middleware::auth::auth));
 
+    // only reachable on the internal address, never next to the public api
+    let metrics_router = Router::new()
+        .route("/gateway/metrics", get(routes::socket::gateway_metrics))
+        .layer(Extension(shared_state.clone()));
+
     let app = Router::new()
         .merge(router)
         .merge(authenticated_user_router)
         .layer(Extension(shared_state));
 
-    let addr = SocketAddr::from((
-        IpAddr::from_str(args.host.as_str()).unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST)),
-        args.port,
-    ));
+    let mut servers: Vec<_> = listen
+        .iter()
+        .map(|addr| {
+            info!("Listening on {}", addr);
 
-    info!("Listening on {}:{}", args.host, args.port);
+            tokio::spawn(axum::Server::bind(addr).serve(app.clone().into_make_service()))
+        })
+        .collect();
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    if let Some(addr) = metrics_listen {
+        info!("Serving metrics on {}", addr);
+
+        servers.push(tokio::spawn(
+            axum::Server::bind(&addr).serve(metrics_router.into_make_service()),
+        ));
+    }
+
+    for server in servers {
+        server.await.unwrap().unwrap();
+    }
 }
 
 async fn root() -> &'static str {
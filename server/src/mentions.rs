@@ -0,0 +1,355 @@
+use std::collections::BTreeSet;
+
+use prisma_client_rust::{raw, PrismaValue};
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    permissions::{self, Permissions},
+    prisma,
+};
+
+/// Messages can mention at most this many users and roles, to keep them from
+/// being used to ping half the guild one by one.
+const MAX_MENTIONS: usize = 25;
+
+/// Mentions written in a message: `<@user_id>`, `<@&role_id>` and `@everyone`.
+#[derive(Default)]
+struct ParsedMentions {
+    user_ids: BTreeSet<String>,
+    role_ids: BTreeSet<String>,
+    everyone: bool,
+}
+
+/// Who a message mentions, after checking they exist and may be mentioned.
+#[derive(Default)]
+pub struct Mentions {
+    pub user_ids: Vec<String>,
+    pub role_ids: Vec<String>,
+    pub everyone: bool,
+}
+
+const EVERYONE: &str = "@everyone";
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether `@everyone` appears as a word of its own, rather than as part of an
+/// email address or a longer word.
+fn mentions_everyone(content: &str) -> bool {
+    content.match_indices(EVERYONE).any(|(start, _)| {
+        let before = content[..start].chars().next_back();
+        let after = content[start + EVERYONE.len()..].chars().next();
+
+        !before.map_or(false, is_word_char) && !after.map_or(false, is_word_char)
+    })
+}
+
+fn parse(content: &str) -> ParsedMentions {
+    let mut parsed = ParsedMentions {
+        everyone: mentions_everyone(content),
+        ..ParsedMentions::default()
+    };
+
+    let mut rest = content;
+
+    while let Some(start) = rest.find("<@") {
+        rest = &rest[start + 2..];
+
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+
+        let (id, is_role) = match rest[..end].strip_prefix('&') {
+            Some(id) => (id, true),
+            None => (&rest[..end], false),
+        };
+
+        // anything else is just text that happens to contain `<@`
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            continue;
+        }
+
+        if is_role {
+            parsed.role_ids.insert(id.to_owned());
+        } else {
+            parsed.user_ids.insert(id.to_owned());
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    parsed
+}
+
+/// Parses the mentions in `content` and keeps the valid ones: users have to be
+/// in the channel, and roles have to belong to its guild. `@everyone` and role
+/// mentions need `MENTION_EVERYONE`, without it they stay plain text.
+pub async fn resolve(
+    prisma: &prisma::PrismaClient,
+    channel_data: &prisma::channel::Data,
+    author_permissions: Permissions,
+    content: &str,
+) -> ApiResult<Mentions> {
+    let mut parsed = parse(content);
+
+    let guild_id = match &channel_data.guild_id {
+        Some(guild_id) => guild_id,
+        // direct messages only have their recipients to mention
+        None => {
+            let recipients = prisma
+                .channel_recipient()
+                .find_many(vec![
+                    prisma::channel_recipient::channel_id::equals(channel_data.id.to_owned()),
+                    prisma::channel_recipient::user_id::in_vec(
+                        parsed.user_ids.into_iter().collect(),
+                    ),
+                ])
+                .exec()
+                .await?;
+
+            return Ok(Mentions {
+                user_ids: recipients
+                    .into_iter()
+                    .map(|recipient| recipient.user_id)
+                    .collect(),
+                ..Mentions::default()
+            });
+        }
+    };
+
+    // `<@&guild_id>` is the @everyone role
+    if parsed
+        .role_ids
+        .remove(&permissions::everyone_role_id(guild_id))
+    {
+        parsed.everyone = true;
+    }
+
+    if parsed.user_ids.len() + parsed.role_ids.len() > MAX_MENTIONS {
+        return Err(ApiError::BadRequest(format!(
+            "Messages can mention at most {} users and roles.",
+            MAX_MENTIONS
+        )));
+    }
+
+    let can_mention_everyone = author_permissions.contains(Permissions::MENTION_EVERYONE);
+
+    let members = prisma
+        .guild_membership()
+        .find_many(vec![
+            prisma::guild_membership::guild_id::equals(guild_id.to_owned()),
+            prisma::guild_membership::user_id::in_vec(parsed.user_ids.into_iter().collect()),
+        ])
+        .exec()
+        .await?;
+
+    let role_ids = if can_mention_everyone && !parsed.role_ids.is_empty() {
+        prisma
+            .role()
+            .find_many(vec![
+                prisma::role::guild_id::equals(guild_id.to_owned()),
+                prisma::role::id::in_vec(parsed.role_ids.into_iter().collect()),
+            ])
+            .exec()
+            .await?
+            .into_iter()
+            .map(|role| role.id)
+            .collect()
+    } else {
+        vec![]
+    };
+
+    Ok(Mentions {
+        user_ids: members.into_iter().map(|member| member.user_id).collect(),
+        role_ids,
+        everyone: parsed.everyone && can_mention_everyone,
+    })
+}
+
+/// Replaces the stored user and role mentions of a message. `mentionEveryone`
+/// lives on the message itself and is set along with its content.
+pub async fn store(
+    prisma: &prisma::PrismaClient,
+    message_id: &str,
+    mentions: &Mentions,
+) -> ApiResult<()> {
+    prisma
+        .message_mention()
+        .find_many(vec![prisma::message_mention::message_id::equals(
+            message_id.to_owned(),
+        )])
+        .delete()
+        .exec()
+        .await?;
+
+    prisma
+        .message_role_mention()
+        .find_many(vec![prisma::message_role_mention::message_id::equals(
+            message_id.to_owned(),
+        )])
+        .delete()
+        .exec()
+        .await?;
+
+    // one statement each for all user and role mentions
+    if !mentions.user_ids.is_empty() {
+        prisma
+            ._execute_raw(raw!(
+                r#"INSERT INTO "MessageMention" ("messageId", "userId")
+                SELECT {}, "value" FROM json_each({})"#,
+                PrismaValue::String(message_id.to_owned()),
+                PrismaValue::String(json!(mentions.user_ids).to_string())
+            ))
+            .await?;
+    }
+
+    if !mentions.role_ids.is_empty() {
+        prisma
+            ._execute_raw(raw!(
+                r#"INSERT INTO "MessageRoleMention" ("messageId", "roleId")
+                SELECT {}, "value" FROM json_each({})"#,
+                PrismaValue::String(message_id.to_owned()),
+                PrismaValue::String(json!(mentions.role_ids).to_string())
+            ))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Everyone to notify about a message's mentions: the mentioned users, members
+/// of the mentioned roles or everyone in the guild, as long as they can see
+/// the channel. The author is never notified of their own message.
+pub async fn notified_users(
+    prisma: &prisma::PrismaClient,
+    channel_data: &prisma::channel::Data,
+    author_id: &str,
+    mentions: &Mentions,
+) -> ApiResult<Vec<String>> {
+    let guild_id = match &channel_data.guild_id {
+        Some(guild_id) => guild_id,
+        // mentions in direct messages were already narrowed down to recipients
+        None => {
+            return Ok(mentions
+                .user_ids
+                .iter()
+                .filter(|user_id| *user_id != author_id)
+                .cloned()
+                .collect())
+        }
+    };
+
+    let mut filters = vec![prisma::guild_membership::guild_id::equals(
+        guild_id.to_owned(),
+    )];
+
+    // @everyone needs every member anyway, otherwise only the mentioned ones are loaded
+    if !mentions.everyone {
+        let mut user_ids: BTreeSet<String> = mentions.user_ids.iter().cloned().collect();
+
+        if !mentions.role_ids.is_empty() {
+            let role_members = prisma
+                .member_role()
+                .find_many(vec![
+                    prisma::member_role::guild_id::equals(guild_id.to_owned()),
+                    prisma::member_role::role_id::in_vec(mentions.role_ids.clone()),
+                ])
+                .exec()
+                .await?;
+
+            user_ids.extend(role_members.into_iter().map(|member| member.user_id));
+        }
+
+        if user_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        filters.push(prisma::guild_membership::user_id::in_vec(
+            user_ids.into_iter().collect(),
+        ));
+    }
+
+    let memberships_data = prisma
+        .guild_membership()
+        .find_many(filters)
+        .with(prisma::guild_membership::WithParam::Roles(vec![]))
+        .exec()
+        .await?;
+
+    let mut notified =
+        permissions::channel_viewers(prisma, guild_id, channel_data, memberships_data).await?;
+    notified.retain(|user_id| user_id != author_id);
+
+    Ok(notified)
+}
+
+/// Bumps the mention count in each user's read state of the channel.
+pub async fn count_mentions(
+    prisma: &prisma::PrismaClient,
+    channel_id: &str,
+    user_ids: &[String],
+) -> ApiResult<()> {
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+
+    prisma
+        ._execute_raw(raw!(
+            r#"INSERT INTO "ReadState" ("userId", "channelId", "mentionCount")
+            SELECT "value", {}, 1 FROM json_each({}) WHERE true
+            ON CONFLICT ("userId", "channelId")
+            DO UPDATE SET "mentionCount" = "mentionCount" + 1"#,
+            PrismaValue::String(channel_id.to_owned()),
+            PrismaValue::String(json!(user_ids).to_string())
+        ))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &BTreeSet<String>) -> Vec<&str> {
+        ids.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn parses_user_and_role_mentions() {
+        let parsed = parse("hi <@alice-1> and <@&mods>, also <@alice-1>");
+
+        assert_eq!(ids(&parsed.user_ids), ["alice-1"]);
+        assert_eq!(ids(&parsed.role_ids), ["mods"]);
+        assert!(!parsed.everyone);
+    }
+
+    #[test]
+    fn parses_everyone() {
+        assert!(parse("hey @everyone").everyone);
+        assert!(parse("@everyone, look").everyone);
+        assert!(parse("(@everyone)").everyone);
+        assert!(!parse("hey everyone").everyone);
+        assert!(!parse("mail foo@everyone.com").everyone);
+        assert!(!parse("hey @everyones").everyone);
+        assert!(!parse("hey @everyone_else").everyone);
+    }
+
+    #[test]
+    fn ignores_text_that_looks_like_mentions() {
+        let parsed = parse("a <@ b> <@> <@&> <@bad!id> <@unclosed");
+
+        assert!(parsed.user_ids.is_empty());
+        assert!(parsed.role_ids.is_empty());
+    }
+
+    #[test]
+    fn keeps_parsing_after_invalid_mentions() {
+        let parsed = parse("<@not valid> <@bob>");
+
+        assert_eq!(ids(&parsed.user_ids), ["bob"]);
+    }
+}
@@ -1,56 +1,59 @@
 use std::sync::Arc;
 
 use axum::{
-    http::{self, Request, StatusCode},
+    http::{self, Request},
     middleware::Next,
-    response::IntoResponse,
-    Json,
+    response::{IntoResponse, Response},
 };
-use jsonwebtoken::{Algorithm, DecodingKey, Validation};
-use load_dotenv::load_dotenv;
-use serde_json::json;
 
-use crate::{prisma, Claims, State};
+use crate::{
+    error::{ApiError, OrNotFound, Resource},
+    prisma, session, State,
+};
 
-load_dotenv!();
+pub async fn auth<B>(req: Request<B>, next: Next<B>) -> Response {
+    match authenticate(req).await {
+        Ok(req) => next.run(req).await,
+        Err(err) => err.into_response(),
+    }
+}
 
-pub async fn auth<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
+async fn authenticate<B>(mut req: Request<B>) -> Result<Request<B>, ApiError> {
     let auth_header = req
         .headers()
         .get(http::header::AUTHORIZATION)
         .and_then(|header| header.to_str().ok());
 
-    let prisma = &req.extensions().get::<Arc<State>>().unwrap().prisma;
+    let state = req.extensions().get::<Arc<State>>().unwrap().clone();
+    let prisma = &state.prisma;
 
-    let jwt_data = jsonwebtoken::decode::<Claims>(
+    let data = session::decode_access_token(
+        &state.config,
         auth_header.unwrap_or("").replace("Bearer ", "").as_str(),
-        &DecodingKey::from_secret(env!("JWT_SECRET").as_ref()),
-        &Validation::new(Algorithm::HS256),
-    );
-
-    match jwt_data {
-        Ok(data) => {
-            let user_query = prisma
-                .user()
-                .find_unique(prisma::user::id::equals(data.claims.id.to_owned()))
-                .with(prisma::user::memberships::fetch(vec![]))
-                .exec()
-                .await;
-
-            if user_query.is_err() {
-                return (
-                    StatusCode::NOT_FOUND,
-                    Json(json!({"error": "User not found."})),
-                )
-                    .into_response();
-            }
-
-            let user_data = user_query.unwrap().unwrap();
-
-            req.extensions_mut().insert(user_data);
-
-            next.run(req).await
-        }
-        Err(_) => StatusCode::UNAUTHORIZED.into_response(),
-    }
+    )
+    .map_err(|_| ApiError::Unauthorized)?;
+
+    // tokens stop working as soon as their session is revoked, not when they expire
+    prisma
+        .session()
+        .find_unique(prisma::session::id::equals(data.claims.sid.to_owned()))
+        .exec()
+        .await?
+        .filter(|session_data| {
+            session_data.user_id == data.claims.id && session::is_active(session_data)
+        })
+        .ok_or(ApiError::Unauthorized)?;
+
+    let user_data = prisma
+        .user()
+        .find_unique(prisma::user::id::equals(data.claims.id.to_owned()))
+        .with(prisma::user::memberships::fetch(vec![]))
+        .exec()
+        .await?
+        .or_not_found(Resource::User)?;
+
+    req.extensions_mut().insert(user_data);
+    req.extensions_mut().insert(data.claims);
+
+    Ok(req)
 }
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use futures::StreamExt;
+
+use crate::{error::ApiError, routes::attachments::max_body_size, State};
+
+/// Caps the body of routes taking uploads, see `attachments::max_body_size`.
+/// Bodies announcing a larger length are turned away before they are read,
+/// ones without a length fail once they go over.
+pub async fn limit_body(req: Request<Body>, next: Next<Body>) -> Response {
+    let state = req.extensions().get::<Arc<State>>().unwrap().clone();
+    let limit = max_body_size(&state.config);
+
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+
+    if content_length.map_or(false, |length| length > limit) {
+        return ApiError::PayloadTooLarge(limit).into_response();
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let mut read = 0;
+    let body = body.map(move |chunk| -> Result<Bytes, BoxError> {
+        let chunk = chunk?;
+
+        read += chunk.len() as u64;
+        if read > limit {
+            return Err(format!("Requests can be at most {} bytes.", limit).into());
+        }
+
+        Ok(chunk)
+    });
+
+    next.run(Request::from_parts(parts, Body::wrap_stream(body)))
+        .await
+}
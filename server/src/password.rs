@@ -0,0 +1,208 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher as _, PasswordVerifier, Version,
+};
+use hmac_sha256::Hash;
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password matched, but the stored hash is a legacy SHA-256 digest or
+    /// uses different Argon2 parameters and should be replaced.
+    NeedsRehash,
+}
+
+/// Hashes passwords with Argon2id and verifies both Argon2 (PHC string) and
+/// legacy unsalted SHA-256 (hex) hashes.
+#[derive(Clone)]
+pub struct PasswordHasher {
+    argon2: Argon2<'static>,
+    params: Params,
+    /// verified against whenever there is no Argon2 hash to check, so every
+    /// attempt takes as long as a real one
+    dummy_hash: String,
+}
+
+impl PasswordHasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, argon2::Error> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+
+        let dummy_hash = argon2
+            .hash_password(b"", &SaltString::generate(&mut OsRng))
+            .expect("hashing with validated Argon2 parameters")
+            .to_string();
+
+        Ok(Self {
+            argon2,
+            params,
+            dummy_hash,
+        })
+    }
+
+    /// Produces a PHC-format Argon2id hash with a random salt.
+    pub async fn hash(&self, password: String) -> Result<String, argon2::password_hash::Error> {
+        let argon2 = self.argon2.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+
+            argon2
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        })
+        .await
+        .expect("password hashing task panicked")
+    }
+
+    /// Verifies against a throwaway Argon2 hash, taking as long as a real
+    /// attempt. Used when there's no user to check the password of.
+    pub async fn verify_dummy(&self, password: String) {
+        self.verify_argon2(password, self.dummy_hash.clone()).await;
+    }
+
+    pub async fn verify(&self, password: String, stored: String) -> Verification {
+        if is_legacy_hash(&stored) {
+            let mut hasher = Hash::new();
+            hasher.update(&password);
+            let matches =
+                constant_time_eq(hex::encode(hasher.finalize()).as_bytes(), stored.as_bytes());
+
+            // SHA-256 alone is so fast that the timing would give away which
+            // accounts still have a legacy hash
+            self.verify_dummy(password).await;
+
+            return if matches {
+                Verification::NeedsRehash
+            } else {
+                Verification::Invalid
+            };
+        }
+
+        self.verify_argon2(password, stored).await
+    }
+
+    async fn verify_argon2(&self, password: String, stored: String) -> Verification {
+        let argon2 = self.argon2.clone();
+        let params = self.params.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let parsed = match PasswordHash::new(&stored) {
+                Ok(parsed) => parsed,
+                Err(_) => return Verification::Invalid,
+            };
+
+            if argon2
+                .verify_password(password.as_bytes(), &parsed)
+                .is_err()
+            {
+                return Verification::Invalid;
+            }
+
+            let up_to_date = parsed.algorithm == Algorithm::Argon2id.ident()
+                && Params::try_from(&parsed)
+                    .map(|stored_params| {
+                        stored_params.m_cost() == params.m_cost()
+                            && stored_params.t_cost() == params.t_cost()
+                            && stored_params.p_cost() == params.p_cost()
+                    })
+                    .unwrap_or(false);
+
+            if up_to_date {
+                Verification::Valid
+            } else {
+                Verification::NeedsRehash
+            }
+        })
+        .await
+        .unwrap_or(Verification::Invalid)
+    }
+}
+
+/// Hashes written before Argon2 was introduced are bare hex SHA-256 digests.
+fn is_legacy_hash(stored: &str) -> bool {
+    stored.len() == 64 && stored.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of "hunter2", as stored before Argon2
+    const LEGACY_HASH: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+    fn hasher() -> PasswordHasher {
+        PasswordHasher::new(8, 1, 1).unwrap()
+    }
+
+    #[tokio::test]
+    async fn argon2_round_trip() {
+        let hasher = hasher();
+        let hash = hasher.hash("hunter2".to_owned()).await.unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(
+            hasher.verify("hunter2".to_owned(), hash).await,
+            Verification::Valid
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_passwords() {
+        let hasher = hasher();
+        let hash = hasher.hash("hunter2".to_owned()).await.unwrap();
+
+        assert_eq!(
+            hasher.verify("hunter3".to_owned(), hash).await,
+            Verification::Invalid
+        );
+        assert_eq!(
+            hasher
+                .verify("hunter3".to_owned(), LEGACY_HASH.to_owned())
+                .await,
+            Verification::Invalid
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_hashes_need_rehashing() {
+        assert_eq!(
+            hasher()
+                .verify("hunter2".to_owned(), LEGACY_HASH.to_owned())
+                .await,
+            Verification::NeedsRehash
+        );
+    }
+
+    #[tokio::test]
+    async fn outdated_parameters_need_rehashing() {
+        let hash = PasswordHasher::new(16, 1, 1)
+            .unwrap()
+            .hash("hunter2".to_owned())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            hasher().verify("hunter2".to_owned(), hash).await,
+            Verification::NeedsRehash
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_hashes() {
+        let hasher = hasher();
+
+        for stored in ["", "not a hash", "$argon2id$v=19$m=8,t=1,p=1$broken"] {
+            assert_eq!(
+                hasher.verify("hunter2".to_owned(), stored.to_owned()).await,
+                Verification::Invalid
+            );
+        }
+    }
+}
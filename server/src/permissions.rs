@@ -0,0 +1,608 @@
+use bitflags::bitflags;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    prisma,
+};
+
+bitflags! {
+    /// Guild-wide permissions, stored as a bitfield on each `Role`.
+    pub struct Permissions: i32 {
+        const MANAGE_CHANNELS = 1 << 0;
+        const MANAGE_GUILD = 1 << 1;
+        const CREATE_INVITES = 1 << 2;
+        const KICK_MEMBERS = 1 << 3;
+        const BAN_MEMBERS = 1 << 4;
+        const MANAGE_MESSAGES = 1 << 5;
+        const MENTION_EVERYONE = 1 << 6;
+        const VIEW_CHANNEL = 1 << 7;
+    }
+}
+
+/// `ChannelOverwrite.targetType` values.
+pub const OVERWRITE_ROLE: &str = "role";
+pub const OVERWRITE_MEMBER: &str = "member";
+
+impl Permissions {
+    /// Granted to the `@everyone` role of new guilds by the `Guild_defaults`
+    /// trigger, which has to be updated along with it.
+    pub const DEFAULT: Permissions = Permissions::CREATE_INVITES
+        .union(Permissions::MENTION_EVERYONE)
+        .union(Permissions::VIEW_CHANNEL);
+
+    /// Fails with `missing_permissions` unless every permission in `required` is granted.
+    pub fn require(self, required: Permissions) -> ApiResult<()> {
+        if self.contains(required) {
+            Ok(())
+        } else {
+            Err(ApiError::MissingPermissions(required - self))
+        }
+    }
+}
+
+/// The `@everyone` role of a guild shares the guild's id.
+pub fn everyone_role_id(guild_id: &str) -> String {
+    guild_id.to_owned()
+}
+
+/// The owner holds every permission in their guild and outranks every role.
+pub fn is_owner(guild_data: &prisma::guild::Data, user_id: &str) -> bool {
+    guild_data.owner_id == user_id
+}
+
+/// Fails with `forbidden` unless `user_id` owns the guild, for what no role can
+/// grant: deleting the guild and handing it to someone else.
+pub fn require_owner(guild_data: &prisma::guild::Data, user_id: &str) -> ApiResult<()> {
+    if is_owner(guild_data, user_id) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden("You are not the owner of this guild."))
+    }
+}
+
+/// A member's roles in a guild, along with the guild-level permissions they add up to.
+pub struct MemberRoles {
+    pub is_owner: bool,
+    pub role_ids: Vec<String>,
+    /// position of the member's highest role, 0 (`@everyone`) if they have none
+    pub highest_position: i32,
+    pub permissions: Permissions,
+}
+
+impl MemberRoles {
+    /// Fails with `forbidden` unless the member ranks strictly above a role at
+    /// `position`. The owner outranks every role.
+    pub fn require_above(&self, position: i32) -> ApiResult<()> {
+        if self.is_owner || self.highest_position > position {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(
+                "You can only manage roles and members below your highest role.",
+            ))
+        }
+    }
+}
+
+/// Looks up `user_id`'s roles in `guild_id`: the owner gets everything,
+/// everyone else gets the union of `@everyone` and their assigned roles.
+/// Fails if the user is not a member of the guild.
+pub async fn member_roles(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    user_id: &str,
+) -> ApiResult<MemberRoles> {
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    let membership = prisma
+        .guild_membership()
+        .find_unique(
+            prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                user_id.to_owned(),
+                guild_id.to_owned(),
+            ),
+        )
+        .exec()
+        .await?;
+
+    if membership.is_none() {
+        return Err(ApiError::Forbidden("User is not a member of the guild."));
+    }
+
+    let roles_data = prisma
+        .role()
+        .find_many(vec![
+            prisma::role::guild_id::equals(guild_id.to_owned()),
+            prisma::role::members::some(vec![prisma::member_role::user_id::equals(
+                user_id.to_owned(),
+            )]),
+        ])
+        .exec()
+        .await?;
+
+    let everyone_role = prisma
+        .role()
+        .find_unique(prisma::role::id::equals(everyone_role_id(guild_id)))
+        .exec()
+        .await?;
+
+    Ok(combine_roles(
+        is_owner(&guild_data, user_id),
+        &roles_data.iter().collect::<Vec<_>>(),
+        everyone_role.as_ref(),
+    ))
+}
+
+/// Adds up a member's assigned roles and the `@everyone` role.
+fn combine_roles(
+    is_owner: bool,
+    roles_data: &[&prisma::role::Data],
+    everyone_role: Option<&prisma::role::Data>,
+) -> MemberRoles {
+    let permissions = if is_owner {
+        Permissions::all()
+    } else {
+        roles_data
+            .iter()
+            .copied()
+            .chain(everyone_role)
+            .fold(Permissions::empty(), |permissions, role| {
+                permissions | Permissions::from_bits_truncate(role.permissions)
+            })
+    };
+
+    MemberRoles {
+        is_owner,
+        highest_position: roles_data
+            .iter()
+            .map(|role| role.position)
+            .max()
+            .unwrap_or(0),
+        role_ids: roles_data.iter().map(|role| role.id.to_owned()).collect(),
+        permissions,
+    }
+}
+
+/// Resolves what `user_id` may do in `guild_id`. Fails if they are not a member.
+pub async fn resolve(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    user_id: &str,
+) -> ApiResult<Permissions> {
+    Ok(member_roles(prisma, guild_id, user_id).await?.permissions)
+}
+
+/// Shorthand for `resolve` followed by `Permissions::require`.
+pub async fn require(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    user_id: &str,
+    required: Permissions,
+) -> ApiResult<Permissions> {
+    let permissions = resolve(prisma, guild_id, user_id).await?;
+    permissions.require(required)?;
+    Ok(permissions)
+}
+
+/// Like `require`, but returns the member's roles too, for hierarchy checks.
+pub async fn require_member(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    user_id: &str,
+    required: Permissions,
+) -> ApiResult<MemberRoles> {
+    let member = member_roles(prisma, guild_id, user_id).await?;
+    member.permissions.require(required)?;
+    Ok(member)
+}
+
+/// Fails with `forbidden` unless `actor` ranks strictly above the highest role
+/// of `target_id`, who doesn't have to be a member. Nobody outranks the owner.
+pub async fn require_above_member(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    actor: &MemberRoles,
+    target_id: &str,
+) -> ApiResult<()> {
+    if actor.is_owner {
+        return Ok(());
+    }
+
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    if is_owner(&guild_data, target_id) {
+        return Err(ApiError::Forbidden(
+            "You can only manage roles and members below your highest role.",
+        ));
+    }
+
+    let target_position = prisma
+        .role()
+        .find_many(vec![
+            prisma::role::guild_id::equals(guild_id.to_owned()),
+            prisma::role::members::some(vec![prisma::member_role::user_id::equals(
+                target_id.to_owned(),
+            )]),
+        ])
+        .exec()
+        .await?
+        .iter()
+        .map(|role| role.position)
+        .max()
+        .unwrap_or(0);
+
+    actor.require_above(target_position)
+}
+
+/// Applies a channel's overwrites on top of guild-level permissions, in order:
+/// `@everyone`, then the member's roles (combined), then the member themselves.
+pub fn apply_overwrites(
+    member: &MemberRoles,
+    guild_id: &str,
+    user_id: &str,
+    overwrites: &[&prisma::channel_overwrite::Data],
+) -> Permissions {
+    if member.is_owner {
+        return Permissions::all();
+    }
+
+    let mut permissions = member.permissions;
+
+    let mut apply = |allow: i32, deny: i32| {
+        permissions = (permissions - Permissions::from_bits_truncate(deny))
+            | Permissions::from_bits_truncate(allow);
+    };
+
+    if let Some(everyone) = overwrites
+        .iter()
+        .find(|overwrite| overwrite.target_id == everyone_role_id(guild_id))
+    {
+        apply(everyone.allow, everyone.deny);
+    }
+
+    let (role_allow, role_deny) = overwrites
+        .iter()
+        .filter(|overwrite| {
+            overwrite.target_type == OVERWRITE_ROLE
+                && member.role_ids.contains(&overwrite.target_id)
+        })
+        .fold((0, 0), |(allow, deny), overwrite| {
+            (allow | overwrite.allow, deny | overwrite.deny)
+        });
+    apply(role_allow, role_deny);
+
+    if let Some(own) = overwrites.iter().find(|overwrite| {
+        overwrite.target_type == OVERWRITE_MEMBER && overwrite.target_id == user_id
+    }) {
+        apply(own.allow, own.deny);
+    }
+
+    permissions
+}
+
+/// Granted to every recipient of a direct message channel.
+pub const DM_RECIPIENT: Permissions = Permissions::VIEW_CHANNEL;
+
+/// Resolves what `user_id` may do in a direct message channel: recipients get
+/// `DM_RECIPIENT`, everyone else gets nothing.
+async fn resolve_dm_channel(
+    prisma: &prisma::PrismaClient,
+    channel_data: &prisma::channel::Data,
+    user_id: &str,
+) -> ApiResult<Permissions> {
+    let recipient = prisma
+        .channel_recipient()
+        .find_unique(
+            prisma::channel_recipient::UniqueWhereParam::ChannelIdUserIdEquals(
+                channel_data.id.to_owned(),
+                user_id.to_owned(),
+            ),
+        )
+        .exec()
+        .await?;
+
+    Ok(match recipient {
+        Some(_) => DM_RECIPIENT,
+        None => Permissions::empty(),
+    })
+}
+
+/// The channel whose overwrites apply to `channel_data`: threads have none of
+/// their own and use their parent channel's.
+pub fn overwrites_channel_id(channel_data: &prisma::channel::Data) -> &String {
+    channel_data
+        .parent_channel_id
+        .as_ref()
+        .unwrap_or(&channel_data.id)
+}
+
+/// Resolves what `user_id` may do in a single channel, overwrites included.
+pub async fn resolve_channel(
+    prisma: &prisma::PrismaClient,
+    channel_data: &prisma::channel::Data,
+    user_id: &str,
+) -> ApiResult<Permissions> {
+    let guild_id = match &channel_data.guild_id {
+        Some(guild_id) => guild_id,
+        None => return resolve_dm_channel(prisma, channel_data, user_id).await,
+    };
+
+    let member = member_roles(prisma, guild_id, user_id).await?;
+
+    let overwrites = prisma
+        .channel_overwrite()
+        .find_many(vec![prisma::channel_overwrite::channel_id::equals(
+            overwrites_channel_id(channel_data).to_owned(),
+        )])
+        .exec()
+        .await?;
+
+    Ok(apply_overwrites(
+        &member,
+        guild_id,
+        user_id,
+        &overwrites.iter().collect::<Vec<_>>(),
+    ))
+}
+
+/// Shorthand for `resolve_channel` followed by `Permissions::require`. Channels the
+/// user can't see always fail with `VIEW_CHANNEL`, whatever else was asked for.
+pub async fn require_channel(
+    prisma: &prisma::PrismaClient,
+    channel_data: &prisma::channel::Data,
+    user_id: &str,
+    required: Permissions,
+) -> ApiResult<Permissions> {
+    let permissions = resolve_channel(prisma, channel_data, user_id).await?;
+    permissions.require(Permissions::VIEW_CHANNEL)?;
+    permissions.require(required)?;
+    Ok(permissions)
+}
+
+/// Filters members of a guild channel's guild down to the ones who can see it.
+/// Roles and overwrites are loaded once for all of them, instead of once per
+/// member as `resolve_channel` would. The memberships need their roles loaded.
+pub async fn channel_viewers(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    channel_data: &prisma::channel::Data,
+    memberships_data: Vec<prisma::guild_membership::Data>,
+) -> ApiResult<Vec<String>> {
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    let roles_data = prisma
+        .role()
+        .find_many(vec![prisma::role::guild_id::equals(guild_id.to_owned())])
+        .exec()
+        .await?;
+
+    let everyone_role = roles_data
+        .iter()
+        .find(|role| role.id == everyone_role_id(guild_id));
+
+    let overwrites = prisma
+        .channel_overwrite()
+        .find_many(vec![prisma::channel_overwrite::channel_id::equals(
+            overwrites_channel_id(channel_data).to_owned(),
+        )])
+        .exec()
+        .await?;
+    let overwrites: Vec<_> = overwrites.iter().collect();
+
+    Ok(memberships_data
+        .into_iter()
+        .filter(|membership_data| {
+            let member_roles: Vec<_> = membership_data
+                .roles()
+                .unwrap()
+                .iter()
+                .filter_map(|member_role| {
+                    roles_data
+                        .iter()
+                        .find(|role| role.id == member_role.role_id)
+                })
+                .collect();
+
+            let member = combine_roles(
+                is_owner(&guild_data, &membership_data.user_id),
+                &member_roles,
+                everyone_role,
+            );
+
+            apply_overwrites(&member, guild_id, &membership_data.user_id, &overwrites)
+                .contains(Permissions::VIEW_CHANNEL)
+        })
+        .map(|membership_data| membership_data.user_id)
+        .collect())
+}
+
+/// Filters `channels` of `guild_id` down to the ones `user_id` can see.
+pub async fn visible_channels(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    channels: Vec<prisma::channel::Data>,
+    user_id: &str,
+) -> ApiResult<Vec<prisma::channel::Data>> {
+    let member = member_roles(prisma, guild_id, user_id).await?;
+
+    let overwrites = prisma
+        .channel_overwrite()
+        .find_many(vec![prisma::channel_overwrite::channel_id::in_vec(
+            channels
+                .iter()
+                .map(|channel| overwrites_channel_id(channel).to_owned())
+                .collect(),
+        )])
+        .exec()
+        .await?;
+
+    Ok(channels
+        .into_iter()
+        .filter(|channel| {
+            let channel_overwrites: Vec<_> = overwrites
+                .iter()
+                .filter(|overwrite| &overwrite.channel_id == overwrites_channel_id(channel))
+                .collect();
+
+            apply_overwrites(&member, guild_id, user_id, &channel_overwrites)
+                .contains(Permissions::VIEW_CHANNEL)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const GUILD_ID: &str = "guild";
+    const USER_ID: &str = "user";
+
+    fn overwrite(
+        target_type: &str,
+        target_id: &str,
+        allow: Permissions,
+        deny: Permissions,
+    ) -> prisma::channel_overwrite::Data {
+        serde_json::from_value(json!({
+            "id": format!("{}-{}", target_type, target_id),
+            "targetType": target_type,
+            "targetId": target_id,
+            "allow": allow.bits(),
+            "deny": deny.bits(),
+            "channelId": "channel",
+        }))
+        .unwrap()
+    }
+
+    fn member(role_ids: &[&str], permissions: Permissions) -> MemberRoles {
+        MemberRoles {
+            is_owner: false,
+            role_ids: role_ids.iter().map(|id| id.to_string()).collect(),
+            highest_position: 0,
+            permissions,
+        }
+    }
+
+    fn apply(member: &MemberRoles, overwrites: &[prisma::channel_overwrite::Data]) -> Permissions {
+        let overwrites: Vec<&prisma::channel_overwrite::Data> = overwrites.iter().collect();
+
+        apply_overwrites(member, GUILD_ID, USER_ID, &overwrites)
+    }
+
+    #[test]
+    fn keeps_guild_permissions_without_overwrites() {
+        assert_eq!(
+            apply(&member(&[], Permissions::DEFAULT), &[]),
+            Permissions::DEFAULT
+        );
+    }
+
+    #[test]
+    fn owner_ignores_overwrites() {
+        let owner = MemberRoles {
+            is_owner: true,
+            ..member(&[], Permissions::empty())
+        };
+        let overwrites = [overwrite(
+            OVERWRITE_MEMBER,
+            USER_ID,
+            Permissions::empty(),
+            Permissions::all(),
+        )];
+
+        assert_eq!(apply(&owner, &overwrites), Permissions::all());
+    }
+
+    #[test]
+    fn roles_override_everyone() {
+        let overwrites = [
+            overwrite(
+                OVERWRITE_ROLE,
+                &everyone_role_id(GUILD_ID),
+                Permissions::empty(),
+                Permissions::VIEW_CHANNEL,
+            ),
+            overwrite(
+                OVERWRITE_ROLE,
+                "mods",
+                Permissions::VIEW_CHANNEL,
+                Permissions::empty(),
+            ),
+        ];
+
+        assert!(!apply(&member(&[], Permissions::DEFAULT), &overwrites)
+            .contains(Permissions::VIEW_CHANNEL));
+        assert!(apply(&member(&["mods"], Permissions::DEFAULT), &overwrites)
+            .contains(Permissions::VIEW_CHANNEL));
+    }
+
+    #[test]
+    fn role_allows_win_over_role_denies() {
+        let overwrites = [
+            overwrite(
+                OVERWRITE_ROLE,
+                "muted",
+                Permissions::empty(),
+                Permissions::MENTION_EVERYONE,
+            ),
+            overwrite(
+                OVERWRITE_ROLE,
+                "mods",
+                Permissions::MENTION_EVERYONE,
+                Permissions::empty(),
+            ),
+        ];
+
+        assert!(apply(
+            &member(&["muted", "mods"], Permissions::DEFAULT),
+            &overwrites
+        )
+        .contains(Permissions::MENTION_EVERYONE));
+    }
+
+    #[test]
+    fn member_overwrites_come_last() {
+        let overwrites = [
+            overwrite(
+                OVERWRITE_ROLE,
+                "mods",
+                Permissions::MANAGE_MESSAGES,
+                Permissions::empty(),
+            ),
+            overwrite(
+                OVERWRITE_MEMBER,
+                USER_ID,
+                Permissions::empty(),
+                Permissions::MANAGE_MESSAGES,
+            ),
+            // someone else's overwrite doesn't apply
+            overwrite(
+                OVERWRITE_MEMBER,
+                "other",
+                Permissions::KICK_MEMBERS,
+                Permissions::empty(),
+            ),
+        ];
+
+        let permissions = apply(&member(&["mods"], Permissions::DEFAULT), &overwrites);
+
+        assert!(!permissions.contains(Permissions::MANAGE_MESSAGES));
+        assert!(!permissions.contains(Permissions::KICK_MEMBERS));
+    }
+}
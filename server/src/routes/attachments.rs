@@ -0,0 +1,522 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequest, Multipart, Path, RequestParts},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use log::warn;
+use prisma_client_rust::raw;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use tempfile::TempPath;
+use tokio::{fs::File, io::AsyncWriteExt, sync::RwLockReadGuard, time::interval};
+
+use crate::{
+    config::Config,
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma,
+    storage::BlobKey,
+    State,
+};
+
+use super::socket::{SocketMessageType, SocketPayload};
+
+/// Messages can carry at most this many files.
+const MAX_ATTACHMENTS: usize = 10;
+const MAX_FILENAME_LENGTH: usize = 255;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Shown inline by clients and browsers, everything else is only offered as a download.
+pub const INLINE_CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// The multipart field holding the JSON payload, next to the uploaded files.
+const PAYLOAD_FIELD: &str = "payload_json";
+
+/// The payload is read into memory whole, so it is capped on its own.
+const MAX_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// Room for the boundary and headers around each multipart field.
+const MAX_FIELD_OVERHEAD: u64 = 4 * 1024;
+
+/// How often storage is swept for blobs nothing uses anymore.
+const BLOB_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A request body that is either plain JSON, or `multipart/form-data` with the
+/// JSON in a `payload_json` field and files in any other fields. Uploads are
+/// read by `read_uploads` once the size limit is known.
+pub enum MessageBody<T> {
+    Json(T),
+    Multipart(Multipart),
+}
+
+#[async_trait]
+impl<T> FromRequest<Body> for MessageBody<T>
+where
+    T: DeserializeOwned + Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        let is_multipart = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map_or(false, |content_type| {
+                content_type.starts_with("multipart/form-data")
+            });
+
+        if is_multipart {
+            Multipart::from_request(req)
+                .await
+                .map(MessageBody::Multipart)
+                .map_err(|err| ApiError::BadRequest(err.to_string()))
+        } else {
+            Json::<T>::from_request(req)
+                .await
+                .map(|Json(payload)| MessageBody::Json(payload))
+                .map_err(|err| ApiError::BadRequest(err.to_string()))
+        }
+    }
+}
+
+/// A file read from a multipart request, not stored yet.
+pub struct Upload {
+    pub filename: String,
+    pub content_type: String,
+    /// in bytes, capped far below `i32::MAX` by `max_attachment_size`
+    pub size: i32,
+    pub hash: String,
+    /// where the file was spooled to, deleted once this is dropped
+    pub file: TempPath,
+}
+
+/// Keeps the last path component and drops anything that could break a
+/// `Content-Disposition` header.
+fn sanitize_filename(filename: &str) -> String {
+    let name = filename
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default();
+
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+
+    match name.trim() {
+        "" => "unknown".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+fn sanitize_content_type(content_type: Option<String>) -> String {
+    content_type
+        .filter(|content_type| {
+            content_type.len() <= 127
+                && content_type.contains('/')
+                && content_type.chars().all(|c| c.is_ascii_graphic())
+        })
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned())
+}
+
+/// Splits a `MessageBody` into its payload and uploaded files, failing as soon
+/// as a file goes over `max_size` bytes. Files are spooled to disk as they come
+/// in rather than kept in memory.
+pub async fn read_uploads<T: DeserializeOwned>(
+    body: MessageBody<T>,
+    max_size: u64,
+) -> ApiResult<(T, Vec<Upload>)> {
+    let mut multipart = match body {
+        MessageBody::Json(payload) => return Ok((payload, vec![])),
+        MessageBody::Multipart(multipart) => multipart,
+    };
+
+    let multipart_error =
+        |err: axum::extract::multipart::MultipartError| ApiError::BadRequest(err.to_string());
+    let spool_error =
+        |err: std::io::Error| ApiError::Internal(format!("could not spool upload: {}", err));
+
+    let mut payload = None;
+    let mut uploads = vec![];
+
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        if field.name() == Some(PAYLOAD_FIELD) {
+            let mut text = vec![];
+
+            while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                if text.len() + chunk.len() > MAX_PAYLOAD_SIZE {
+                    return Err(ApiError::BadRequest(format!(
+                        "The {} field can be at most {} bytes.",
+                        PAYLOAD_FIELD, MAX_PAYLOAD_SIZE
+                    )));
+                }
+
+                text.extend_from_slice(&chunk);
+            }
+
+            payload = Some(
+                serde_json::from_slice(&text)
+                    .map_err(|err| ApiError::BadRequest(err.to_string()))?,
+            );
+            continue;
+        }
+
+        if uploads.len() == MAX_ATTACHMENTS {
+            return Err(ApiError::BadRequest(format!(
+                "Messages can have at most {} attachments.",
+                MAX_ATTACHMENTS
+            )));
+        }
+
+        let filename = sanitize_filename(field.file_name().unwrap_or_default());
+        let content_type = sanitize_content_type(field.content_type().map(|mime| mime.to_string()));
+
+        let spooled = tempfile::NamedTempFile::new()
+            .map_err(spool_error)?
+            .into_temp_path();
+        let mut file = File::create(&spooled).await.map_err(spool_error)?;
+        let mut key = BlobKey::default();
+        let mut size = 0;
+
+        while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+            size += chunk.len() as u64;
+            if size > max_size {
+                return Err(ApiError::PayloadTooLarge(max_size));
+            }
+
+            key.update(&chunk);
+            file.write_all(&chunk).await.map_err(spool_error)?;
+        }
+
+        file.flush().await.map_err(spool_error)?;
+
+        uploads.push(Upload {
+            filename,
+            content_type,
+            size: size as i32,
+            hash: key.finish(),
+            file: spooled,
+        });
+    }
+
+    let payload = payload
+        .ok_or_else(|| ApiError::BadRequest(format!("Missing the {} field.", PAYLOAD_FIELD)))?;
+
+    Ok((payload, uploads))
+}
+
+/// The largest request body a `MessageBody` can come in: every attachment at
+/// the server's size limit, the payload, and the multipart framing around them.
+pub fn max_body_size(config: &Config) -> u64 {
+    let fields = MAX_ATTACHMENTS as u64 + 1;
+
+    fields * MAX_FIELD_OVERHEAD
+        + MAX_ATTACHMENTS as u64 * config.storage.max_attachment_size
+        + MAX_PAYLOAD_SIZE as u64
+}
+
+/// The largest file that can be attached in a channel: the server's limit,
+/// lowered by the guild's own if it has one.
+pub async fn max_attachment_size(
+    state: &State,
+    channel_data: &prisma::channel::Data,
+) -> ApiResult<u64> {
+    let server_limit = state.config.storage.max_attachment_size;
+
+    let guild_id = match &channel_data.guild_id {
+        Some(guild_id) => guild_id,
+        None => return Ok(server_limit),
+    };
+
+    let guild_data = state
+        .prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    Ok(match guild_data.max_attachment_size {
+        Some(guild_limit) => server_limit.min(guild_limit.max(0) as u64),
+        None => server_limit,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentLimitPayload {
+    /// in bytes, `null` or 0 to fall back to the server's limit
+    max_attachment_size: Option<i32>,
+}
+
+/// Sets the largest attachment members of a guild can upload. Guilds can only
+/// lower the server's limit, anything above it has no effect.
+pub async fn set_attachment_limit(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+    Json(payload): Json<AttachmentLimitPayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    permissions::require(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD).await?;
+
+    if payload.max_attachment_size.map_or(false, |size| size < 0) {
+        return Err(ApiError::BadRequest(
+            "maxAttachmentSize can't be negative.".to_owned(),
+        ));
+    }
+
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id))
+        .update(vec![prisma::guild::max_attachment_size::set(
+            payload.max_attachment_size.filter(|size| *size > 0),
+        )])
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_data.id.to_owned()),
+    });
+
+    Ok((StatusCode::OK, Json(json!(guild_data))))
+}
+
+/// Writes the uploads to storage, before the rows referencing them exist. The
+/// returned guard keeps sweeps from deleting the blobs in the meantime, so it
+/// has to be held until those rows are created.
+pub async fn store_uploads<'a>(
+    state: &'a State,
+    uploads: &[Upload],
+) -> ApiResult<RwLockReadGuard<'a, ()>> {
+    let unswept = state.blob_sweep.read().await;
+
+    for upload in uploads {
+        state.storage.put(&upload.hash, &upload.file).await?;
+    }
+
+    Ok(unswept)
+}
+
+pub async fn create_attachments(
+    prisma: &prisma::PrismaClient,
+    message_id: &str,
+    uploads: Vec<Upload>,
+) -> ApiResult<Vec<prisma::attachment::Data>> {
+    let mut attachments_data = vec![];
+
+    for upload in uploads {
+        attachments_data.push(
+            prisma
+                .attachment()
+                .create(
+                    prisma::attachment::filename::set(upload.filename),
+                    prisma::attachment::content_type::set(upload.content_type),
+                    prisma::attachment::size::set(upload.size),
+                    prisma::attachment::hash::set(upload.hash),
+                    prisma::attachment::message::link(prisma::message::UniqueWhereParam::IdEquals(
+                        message_id.to_owned(),
+                    )),
+                    vec![],
+                )
+                .exec()
+                .await?,
+        );
+    }
+
+    Ok(attachments_data)
+}
+
+/// The shape attachments are returned in, both by the REST API and over the gateway.
+pub fn attachment_json(
+    attachment_data: &prisma::attachment::Data,
+    channel_id: &str,
+) -> protocol::Attachment {
+    protocol::Attachment {
+        id: attachment_data.id.to_owned(),
+        filename: attachment_data.filename.to_owned(),
+        content_type: attachment_data.content_type.to_owned(),
+        size: attachment_data.size as u64,
+        url: format!(
+            "/channels/{}/attachments/{}",
+            channel_id, attachment_data.id
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct BlobReference {
+    hash: String,
+}
+
+/// Deletes every blob that no attachment or guild icon uses. Uploads that are
+/// still waiting for their rows are left alone.
+async fn delete_unused_blobs(state: &State) -> ApiResult<()> {
+    let _sweeping = state.blob_sweep.write().await;
+
+    let used: HashSet<String> = state
+        .prisma
+        ._query_raw::<BlobReference>(raw!(
+            r#"SELECT "hash" FROM "Attachment"
+            UNION SELECT "icon" AS "hash" FROM "Guild" WHERE "icon" IS NOT NULL"#
+        ))
+        .await?
+        .into_iter()
+        .map(|reference| reference.hash)
+        .collect();
+
+    for key in state.storage.list().await? {
+        if !used.contains(&key) {
+            state.storage.delete(&key).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Periodically deletes blobs left behind by deleted messages, channels and
+/// guilds, replaced icons and uploads whose message never got created.
+pub async fn sweep_unused_blobs(state: Arc<State>) {
+    let mut sweep = interval(BLOB_SWEEP_INTERVAL);
+
+    loop {
+        sweep.tick().await;
+
+        if let Err(err) = delete_unused_blobs(&state).await {
+            warn!("Could not sweep unused blobs: {:?}", err);
+        }
+    }
+}
+
+/// Attachments can be downloaded by anyone who can see the channel they were sent in.
+pub async fn get_attachment(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, attachment_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let attachment_data = prisma
+        .attachment()
+        .find_unique(prisma::attachment::id::equals(attachment_id))
+        .with(prisma::attachment::WithParam::Message)
+        .exec()
+        .await?
+        .filter(|attachment_data| attachment_data.message().unwrap().channel_id == channel_id)
+        .or_not_found(Resource::Attachment)?;
+
+    let data = state
+        .storage
+        .get(&attachment_data.hash)
+        .await?
+        .or_not_found(Resource::Attachment)?;
+
+    // anything that isn't a known image is served as an opaque download, so
+    // uploaded html or scripts never run in a browser
+    let inline = INLINE_CONTENT_TYPES.contains(&attachment_data.content_type.as_str());
+
+    // header values have to be plain ascii
+    let filename: String = attachment_data
+        .filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let (content_type, disposition) = if inline {
+        (attachment_data.content_type.to_owned(), "inline")
+    } else {
+        (DEFAULT_CONTENT_TYPE.to_owned(), "attachment")
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("{}; filename=\"{}\"", disposition, filename),
+            ),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        ],
+        data,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_last_path_component() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\Users\\me\\cat.png"), "cat.png");
+    }
+
+    #[test]
+    fn strips_characters_that_break_headers() {
+        assert_eq!(sanitize_filename("a\"b\r\nc.txt"), "abc.txt");
+    }
+
+    #[test]
+    fn names_empty_files_unknown() {
+        assert_eq!(sanitize_filename(""), "unknown");
+        assert_eq!(sanitize_filename("dir/"), "unknown");
+        assert_eq!(sanitize_filename("  \t "), "unknown");
+    }
+
+    #[test]
+    fn truncates_long_names() {
+        let name = "é".repeat(MAX_FILENAME_LENGTH + 10);
+
+        assert_eq!(
+            sanitize_filename(&name).chars().count(),
+            MAX_FILENAME_LENGTH
+        );
+    }
+
+    #[test]
+    fn falls_back_to_octet_stream() {
+        assert_eq!(
+            sanitize_content_type(Some("image/png".to_owned())),
+            "image/png"
+        );
+        assert_eq!(sanitize_content_type(None), DEFAULT_CONTENT_TYPE);
+        assert_eq!(
+            sanitize_content_type(Some("text/html\r\nx: y".to_owned())),
+            DEFAULT_CONTENT_TYPE
+        );
+        assert_eq!(
+            sanitize_content_type(Some("png".to_owned())),
+            DEFAULT_CONTENT_TYPE
+        );
+    }
+}
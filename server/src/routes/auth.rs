@@ -1,12 +1,20 @@
 use std::sync::Arc;
 
-use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
-use hmac_sha256::Hash;
-use jsonwebtoken::{EncodingKey, Header};
+use axum::{
+    extract::TypedHeader, headers::UserAgent, http::StatusCode, response::IntoResponse, Extension,
+    Json,
+};
+use chrono::Utc;
+use log::warn;
+use prisma_client_rust::{raw, PrismaValue};
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::{prisma, Claims, State};
+use crate::{
+    error::{ApiError, ApiResult},
+    password::Verification,
+    prisma, session, Claims, State,
+};
 
 #[derive(Deserialize)]
 pub struct AuthPayload {
@@ -14,98 +22,212 @@ pub struct AuthPayload {
     password: String,
 }
 
+#[derive(Deserialize)]
+pub struct RefreshPayload {
+    refresh_token: String,
+}
+
 pub async fn register(
     Extension(state): Extension<Arc<State>>,
     Json(payload): Json<AuthPayload>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let mut hasher = Hash::new();
+    let password_hash = state
+        .passwords
+        .hash(payload.password)
+        .await
+        .map_err(|err| ApiError::Internal(err.to_string()))?;
 
-    hasher.update(payload.password);
-
-    let created_user = prisma
+    let created_user_data = prisma
         .user()
         .create(
             prisma::user::username::set(payload.username),
-            prisma::user::password::set(hex::encode(hasher.finalize())),
+            prisma::user::password::set(password_hash),
             vec![],
         )
         .exec()
-        .await;
+        .await?;
 
-    if created_user.is_err() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "An error occured."})),
-        );
-    }
-
-    let created_user_data = created_user.unwrap();
-
-    (
+    Ok((
         StatusCode::CREATED,
         Json(json!({
             "id": created_user_data.id,
             "username": created_user_data.username,
             "createdAt": created_user_data.created_at.to_string(),
         })),
-    )
+    ))
 }
 
 pub async fn login(
     Extension(state): Extension<Arc<State>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
     Json(payload): Json<AuthPayload>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let mut hasher = Hash::new();
-
-    hasher.update(payload.password);
-
-    let password_hash = hex::encode(hasher.finalize());
-
-    let user_query = prisma
+    let user_data = prisma
         .user()
         .find_unique(prisma::user::username::equals(payload.username))
         .exec()
+        .await?;
+
+    // unknown usernames fail like wrong passwords, and take as long, so
+    // accounts can't be enumerated
+    let user_data = match user_data {
+        Some(user_data) => user_data,
+        None => {
+            state.passwords.verify_dummy(payload.password).await;
+            return Err(ApiError::InvalidCredentials);
+        }
+    };
+
+    let verification = state
+        .passwords
+        .verify(payload.password.to_owned(), user_data.password.to_owned())
         .await;
 
-    if user_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "User not found"})),
-        );
+    if verification == Verification::Invalid {
+        return Err(ApiError::InvalidCredentials);
     }
 
-    let user_data = user_query.unwrap().unwrap();
-
-    if user_data.password != password_hash {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(json!({
-                "error": "Incorrect password"
-            })),
-        );
+    // upgrade legacy or outdated hashes while we still have the plaintext
+    if verification == Verification::NeedsRehash {
+        match state.passwords.hash(payload.password).await {
+            Ok(new_hash) => {
+                prisma
+                    .user()
+                    .find_unique(prisma::user::id::equals(user_data.id.to_owned()))
+                    .update(vec![prisma::user::password::set(new_hash)])
+                    .exec()
+                    .await
+                    .ok();
+            }
+            Err(err) => warn!("Failed to rehash password for {}: {}", user_data.id, err),
+        }
     }
 
-    let access_token = jsonwebtoken::encode(
-        &Header::default(),
-        &Claims {
-            username: user_data.username.to_owned(),
-            id: user_data.id.to_owned(),
-            exp: (chrono::offset::Utc::now().timestamp() + (7 * 86400)) as usize,
-        },
-        &EncodingKey::from_secret(env!("JWT_SECRET").as_ref()),
+    let (session_data, refresh_token) = session::create_session(
+        &state.config,
+        prisma,
+        user_data.id.to_owned(),
+        user_agent.map(|TypedHeader(user_agent)| user_agent.as_str().to_owned()),
     )
-    .unwrap();
+    .await?;
+
+    let (access_token, expires_at) =
+        session::issue_access_token(&state.config, &user_data, &session_data.id)
+            .map_err(|err| ApiError::Internal(err.to_string()))?;
 
-    (
+    Ok((
         StatusCode::OK,
         Json(json!({
             "id": user_data.id,
             "username": user_data.username,
-            "token": access_token
+            "token": access_token,
+            "refresh_token": refresh_token,
+            "expires_at": expires_at,
         })),
+    ))
+}
+
+pub async fn refresh(
+    Extension(state): Extension<Arc<State>>,
+    Json(payload): Json<RefreshPayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let old_hash = session::hash_refresh_token(&payload.refresh_token);
+
+    let session_data = prisma
+        .session()
+        .find_unique(prisma::session::refresh_token_hash::equals(
+            old_hash.to_owned(),
+        ))
+        .with(prisma::session::WithParam::User)
+        .exec()
+        .await?;
+
+    let session_data = match session_data {
+        Some(session_data) => session_data,
+        None => {
+            // a token that was already rotated away, which only a leak explains:
+            // end the session for whoever holds the current one too
+            let reused_data = prisma
+                .session()
+                .find_many(vec![prisma::session::previous_refresh_token_hash::equals(
+                    Some(old_hash),
+                )])
+                .exec()
+                .await?;
+
+            for session_data in reused_data {
+                session::revoke(&state, &session_data.id).await?;
+            }
+
+            return Err(ApiError::Unauthorized);
+        }
+    };
+
+    if !session::is_active(&session_data) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    // rotate the refresh token so a leaked one can only be used once. The swap
+    // only goes through while the old hash is still in place, so of two
+    // requests with the same token only one can win
+    let refresh_token = session::generate_refresh_token();
+
+    let rotated = prisma
+        ._execute_raw(raw!(
+            r#"UPDATE "Session" SET "refreshTokenHash" = {}, "previousRefreshTokenHash" = {}
+            WHERE "id" = {} AND "refreshTokenHash" = {}"#,
+            PrismaValue::String(session::hash_refresh_token(&refresh_token)),
+            PrismaValue::String(old_hash.to_owned()),
+            PrismaValue::String(session_data.id.to_owned()),
+            PrismaValue::String(old_hash)
+        ))
+        .await?;
+
+    // the token was used twice, so it may have leaked: end the session for both
+    if rotated != 1 {
+        session::revoke(&state, &session_data.id).await?;
+
+        return Err(ApiError::Unauthorized);
+    }
+
+    prisma
+        .session()
+        .find_unique(prisma::session::id::equals(session_data.id.to_owned()))
+        .update(vec![
+            prisma::session::last_used_at::set(Utc::now().into()),
+            prisma::session::expires_at::set(session::refresh_expiry(&state.config)),
+        ])
+        .exec()
+        .await?;
+
+    let (access_token, expires_at) = session::issue_access_token(
+        &state.config,
+        session_data.user().unwrap(),
+        &session_data.id,
     )
+    .map_err(|err| ApiError::Internal(err.to_string()))?;
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "token": access_token,
+            "refresh_token": refresh_token,
+            "expires_at": expires_at,
+        })),
+    ))
+}
+
+pub async fn logout(
+    Extension(state): Extension<Arc<State>>,
+    Extension(claims): Extension<Claims>,
+) -> ApiResult<impl IntoResponse> {
+    session::revoke(&state, &claims.sid).await?;
+
+    Ok((StatusCode::OK, Json(json!({}))))
 }
@@ -1,162 +1,880 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::Utc;
+use prisma_client_rust::{
+    operator::{and, or},
+    Direction,
+};
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::{prisma, State, User};
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    mentions::{self, Mentions},
+    permissions::{self, Permissions, OVERWRITE_MEMBER, OVERWRITE_ROLE},
+    prisma, State,
+};
 
-use super::socket::{SocketMessagePayload, SocketMessageType, SocketPayload};
+use super::{
+    attachments::{
+        attachment_json, create_attachments, max_attachment_size, read_uploads, store_uploads,
+        MessageBody,
+    },
+    pins::publish_pins_update,
+    reactions::{reaction_counts, ReactionCount},
+    socket::{SocketMessageType, SocketPayload},
+};
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct MessagePayload {
     content: String,
+    /// only read when sending, replies can't be changed by editing
+    reply_to_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MessageQuery {
+    before: Option<String>,
+    after: Option<String>,
+    around: Option<String>,
+    limit: Option<i64>,
+}
+
+const DEFAULT_MESSAGE_LIMIT: i64 = 50;
+const MAX_MESSAGE_LIMIT: i64 = 100;
+
+/// `Channel.kind` values.
+pub const CHANNEL_TEXT: &str = "text";
+pub const CHANNEL_DM: &str = "dm";
+pub const CHANNEL_GROUP_DM: &str = "group_dm";
+pub const CHANNEL_THREAD: &str = "thread";
+
+#[derive(Deserialize)]
+pub struct OverwritePayload {
+    #[serde(rename = "type")]
+    target_type: String,
+    #[serde(default)]
+    allow: i32,
+    #[serde(default)]
+    deny: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PageDirection {
+    Before,
+    After,
+}
+
+/// Fetches up to `limit` messages strictly before or after `cursor` (or the
+/// newest ones without a cursor), ordered oldest first. Messages are ordered by
+/// `createdAt`, with the id breaking ties between messages sent the same instant.
+async fn fetch_message_page(
+    prisma: &prisma::PrismaClient,
+    channel_id: &str,
+    cursor: Option<&prisma::message::Data>,
+    direction: PageDirection,
+    limit: i64,
+) -> ApiResult<Vec<prisma::message::Data>> {
+    if limit <= 0 {
+        return Ok(vec![]);
+    }
+
+    let mut filters = vec![prisma::message::channel_id::equals(channel_id.to_owned())];
+
+    if let Some(cursor) = cursor {
+        filters.push(match direction {
+            PageDirection::Before => or(vec![
+                prisma::message::created_at::lt(cursor.created_at),
+                and(vec![
+                    prisma::message::created_at::equals(cursor.created_at),
+                    prisma::message::id::lt(cursor.id.to_owned()),
+                ]),
+            ]),
+            PageDirection::After => or(vec![
+                prisma::message::created_at::gt(cursor.created_at),
+                and(vec![
+                    prisma::message::created_at::equals(cursor.created_at),
+                    prisma::message::id::gt(cursor.id.to_owned()),
+                ]),
+            ]),
+        });
+    }
+
+    let order = match direction {
+        PageDirection::Before => Direction::Desc,
+        PageDirection::After => Direction::Asc,
+    };
+
+    let mut messages_data = prisma
+        .message()
+        .find_many(filters)
+        .with(prisma::message::WithParam::Author)
+        .with(prisma::message::WithParam::Mentions(vec![]))
+        .with(prisma::message::WithParam::RoleMentions(vec![]))
+        .with(prisma::message::WithParam::Attachments(vec![]))
+        .order_by(prisma::message::created_at::order(order))
+        .order_by(prisma::message::id::order(order))
+        .take(limit)
+        .exec()
+        .await?;
+
+    if direction == PageDirection::Before {
+        messages_data.reverse();
+    }
+
+    Ok(messages_data)
+}
+
+/// How a message is quoted above replies to it. Expects the author to be fetched.
+fn message_reference(message: &prisma::message::Data) -> protocol::MessageReference {
+    let author = message.author().unwrap();
+
+    protocol::MessageReference {
+        id: message.id.to_owned(),
+        content: message.content.to_owned(),
+        author: protocol::User {
+            id: author.id.to_owned(),
+            username: author.username.to_owned(),
+        },
+    }
+}
+
+/// Fetches the messages that `messages_data` reply to, keyed by id.
+async fn reply_references(
+    prisma: &prisma::PrismaClient,
+    messages_data: &[prisma::message::Data],
+) -> ApiResult<HashMap<String, protocol::MessageReference>> {
+    let reply_to_ids: Vec<String> = messages_data
+        .iter()
+        .filter_map(|message_data| message_data.reply_to_id.clone())
+        .collect();
+
+    if reply_to_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let replied_data = prisma
+        .message()
+        .find_many(vec![prisma::message::id::in_vec(reply_to_ids)])
+        .with(prisma::message::WithParam::Author)
+        .exec()
+        .await?;
+
+    Ok(replied_data
+        .iter()
+        .map(|message_data| (message_data.id.to_owned(), message_reference(message_data)))
+        .collect())
+}
+
+/// Everything `message_json` shows about a page of messages that isn't fetched with them.
+pub struct MessageRelations {
+    references: HashMap<String, protocol::MessageReference>,
+    reactions: HashMap<String, Vec<ReactionCount>>,
+}
+
+/// Loads the replied-to messages and reaction counts of `messages_data`, as
+/// seen by `user_id`.
+pub async fn message_relations(
+    prisma: &prisma::PrismaClient,
+    messages_data: &[prisma::message::Data],
+    user_id: &str,
+) -> ApiResult<MessageRelations> {
+    let message_ids = messages_data
+        .iter()
+        .map(|message_data| message_data.id.to_owned())
+        .collect();
+
+    Ok(MessageRelations {
+        references: reply_references(prisma, messages_data).await?,
+        reactions: reaction_counts(prisma, message_ids, user_id).await?,
+    })
+}
+
+/// The shape messages are returned in by the REST API. Expects the author, both
+/// kinds of mentions and attachments to be fetched, and `relations` to come from
+/// `message_relations`.
+pub fn message_json(
+    message: &prisma::message::Data,
+    relations: &MessageRelations,
+) -> serde_json::Value {
+    let author = message.author().unwrap();
+    let mentions: Vec<&String> = message
+        .mentions()
+        .unwrap()
+        .iter()
+        .map(|mention| &mention.user_id)
+        .collect();
+    let mention_roles: Vec<&String> = message
+        .role_mentions()
+        .unwrap()
+        .iter()
+        .map(|mention| &mention.role_id)
+        .collect();
+    let attachments: Vec<protocol::Attachment> = message
+        .attachments()
+        .unwrap()
+        .iter()
+        .map(|attachment_data| attachment_json(attachment_data, &message.channel_id))
+        .collect();
+
+    json!({
+        "id": message.id,
+        "content": message.content,
+        "created_at": message.created_at.to_rfc3339(),
+        "edited_at": message.edited_at.map(|edited_at| edited_at.to_rfc3339()),
+        "pinned_at": message.pinned_at.map(|pinned_at| pinned_at.to_rfc3339()),
+        "author": {
+            "id": author.id,
+            "username": author.username,
+        },
+        "mentions": mentions,
+        "mention_roles": mention_roles,
+        "mention_everyone": message.mention_everyone,
+        "reply_to": message
+            .reply_to_id
+            .as_ref()
+            .and_then(|reply_to_id| relations.references.get(reply_to_id)),
+        "reactions": relations.reactions.get(&message.id).unwrap_or(&vec![]),
+        "attachments": attachments,
+    })
+}
+
+/// The shape messages are dispatched in over the gateway.
+fn message_event(
+    message: &prisma::message::Data,
+    author_username: String,
+    mentions: &Mentions,
+    reply_to: Option<protocol::MessageReference>,
+    attachments_data: &[prisma::attachment::Data],
+) -> protocol::Message {
+    protocol::Message {
+        id: message.id.to_owned(),
+        channel_id: message.channel_id.to_owned(),
+        content: message.content.to_owned(),
+        author: protocol::User {
+            id: message.author_id.to_owned(),
+            username: author_username,
+        },
+        created_at: message.created_at.to_rfc3339(),
+        edited_at: message.edited_at.map(|edited_at| edited_at.to_rfc3339()),
+        mentions: mentions.user_ids.clone(),
+        mention_roles: mentions.role_ids.clone(),
+        mention_everyone: mentions.everyone,
+        reply_to,
+        attachments: attachments_data
+            .iter()
+            .map(|attachment_data| attachment_json(attachment_data, &message.channel_id))
+            .collect(),
+    }
 }
 
 pub async fn get_channel_messages(
     Path(channel_id): Path<String>,
+    Query(query): Query<MessageQuery>,
     Extension(user_data): Extension<prisma::user::Data>,
     Extension(state): Extension<Arc<State>>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let channel_query = prisma
+    let channel_data = prisma
         .channel()
         .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
         .exec()
-        .await;
+        .await?
+        .or_not_found(Resource::Channel)?;
 
-    if channel_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Channel not found."})),
-        );
-    }
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_MESSAGE_LIMIT)
+        .clamp(1, MAX_MESSAGE_LIMIT);
+
+    let messages_data = match (query.before, query.after, query.around) {
+        (None, None, None) => {
+            fetch_message_page(prisma, &channel_id, None, PageDirection::Before, limit).await?
+        }
+        (Some(before), None, None) => {
+            let cursor = find_channel_message(prisma, &channel_id, before).await?;
+            fetch_message_page(
+                prisma,
+                &channel_id,
+                Some(&cursor),
+                PageDirection::Before,
+                limit,
+            )
+            .await?
+        }
+        (None, Some(after), None) => {
+            let cursor = find_channel_message(prisma, &channel_id, after).await?;
+            fetch_message_page(
+                prisma,
+                &channel_id,
+                Some(&cursor),
+                PageDirection::After,
+                limit,
+            )
+            .await?
+        }
+        (None, None, Some(around)) => {
+            let cursor = prisma
+                .message()
+                .find_unique(prisma::message::id::equals(around))
+                .with(prisma::message::WithParam::Author)
+                .with(prisma::message::WithParam::Mentions(vec![]))
+                .with(prisma::message::WithParam::RoleMentions(vec![]))
+                .with(prisma::message::WithParam::Attachments(vec![]))
+                .exec()
+                .await?
+                .filter(|message_data| message_data.channel_id == channel_id)
+                .or_not_found(Resource::Message)?;
+
+            let before_limit = (limit - 1) / 2;
+            let after_limit = limit - 1 - before_limit;
+
+            let mut messages = fetch_message_page(
+                prisma,
+                &channel_id,
+                Some(&cursor),
+                PageDirection::Before,
+                before_limit,
+            )
+            .await?;
+            let after = fetch_message_page(
+                prisma,
+                &channel_id,
+                Some(&cursor),
+                PageDirection::After,
+                after_limit,
+            )
+            .await?;
 
-    let channel_data = channel_query.unwrap().unwrap();
+            messages.push(cursor);
+            messages.extend(after);
+            messages
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Only one of before, after and around can be used.".to_owned(),
+            ))
+        }
+    };
 
-    let user_memberships = user_data.memberships().unwrap();
+    let relations = message_relations(prisma, &messages_data, &user_data.id).await?;
 
-    let is_member = user_memberships
+    let messages_user_data: Vec<serde_json::Value> = messages_data
         .iter()
-        .any(|membership| membership.guild_id == channel_data.guild_id);
+        .map(|message_data| message_json(message_data, &relations))
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!(messages_user_data))))
+}
+
+pub async fn post_channel_messages(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(channel_id): Path<String>,
+    body: MessageBody<MessagePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
 
-    if !is_member {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(json!({"error": "User is not a member of the guild."})),
-        );
+    let user_permissions = permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    require_unarchived(&channel_data)?;
+
+    let max_size = max_attachment_size(&state, &channel_data).await?;
+    let (payload, uploads) = read_uploads(body, max_size).await?;
+
+    // replies have to stay within the channel
+    let reply_to = match payload.reply_to_id {
+        Some(reply_to_id) => Some(
+            prisma
+                .message()
+                .find_unique(prisma::message::id::equals(reply_to_id))
+                .with(prisma::message::WithParam::Author)
+                .exec()
+                .await?
+                .filter(|message_data| message_data.channel_id == channel_id)
+                .or_not_found(Resource::Message)?,
+        ),
+        None => None,
+    };
+
+    let mentions =
+        mentions::resolve(prisma, &channel_data, user_permissions, &payload.content).await?;
+
+    let mut optional_params = vec![prisma::message::mention_everyone::set(mentions.everyone)];
+
+    if let Some(reply_to) = &reply_to {
+        optional_params.push(prisma::message::reply_to::link(
+            prisma::message::UniqueWhereParam::IdEquals(reply_to.id.to_owned()),
+        ));
     }
 
-    let messages_query = prisma
+    let _unswept = store_uploads(&state, &uploads).await?;
+
+    let message_data = prisma
         .message()
-        .find_many(vec![prisma::message::channel_id::equals(channel_id)])
-        .with(prisma::message::WithParam::Author)
+        .create(
+            prisma::message::content::set(payload.content),
+            prisma::message::author::link(prisma::user::UniqueWhereParam::IdEquals(
+                user_data.id.to_owned(),
+            )),
+            prisma::message::channel::link(prisma::channel::UniqueWhereParam::IdEquals(channel_id)),
+            optional_params,
+        )
         .exec()
-        .await;
+        .await?;
 
-    if messages_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Channel not found."})),
-        );
+    mentions::store(prisma, &message_data.id, &mentions).await?;
+    let attachments_data = create_attachments(prisma, &message_data.id, uploads).await?;
+
+    let notified_ids =
+        mentions::notified_users(prisma, &channel_data, &user_data.id, &mentions).await?;
+    mentions::count_mentions(prisma, &channel_data.id, &notified_ids).await?;
+
+    let message = message_event(
+        &message_data,
+        user_data.username,
+        &mentions,
+        reply_to.as_ref().map(message_reference),
+        &attachments_data,
+    );
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::NewMessage(message.clone(), channel_data.id),
+    });
+
+    if !notified_ids.is_empty() {
+        state.gateway.publish(SocketPayload {
+            message: SocketMessageType::MessageMention(message, notified_ids),
+        });
     }
 
-    let messages_data = messages_query.unwrap();
-
-    let mut messages_user_data: Vec<serde_json::Value> = vec![];
-
-    for message in messages_data {
-        let author = message.author().unwrap();
-        messages_user_data.push(json!({
-            "id": message.id,
-            "content": message.content,
-            "created_at": message.created_at.to_rfc3339(),
-            "author": {
-                "id": author.id,
-                "username": author.username,
-            }
-        }));
+    Ok((StatusCode::OK, Json(json!(message_data))))
+}
+
+/// Overwrites only exist in guild channels, direct messages have no roles or
+/// members and threads use their parent channel's.
+fn overwrite_guild_id(channel_data: &prisma::channel::Data) -> ApiResult<String> {
+    if channel_data.kind == CHANNEL_THREAD {
+        return Err(ApiError::BadRequest(
+            "Threads use the overwrites of their parent channel.".to_owned(),
+        ));
     }
 
-    (StatusCode::OK, Json(json!(messages_user_data)))
+    channel_data.guild_id.clone().ok_or_else(|| {
+        ApiError::BadRequest("Direct message channels have no overwrites.".to_owned())
+    })
 }
 
-pub async fn post_channel_messages(
+/// Overwrites follow the role hierarchy: members can only set them for roles
+/// and members ranking below their own highest role.
+async fn require_above_target(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    user_id: &str,
+    target_type: &str,
+    target_id: &str,
+) -> ApiResult<()> {
+    let member = permissions::member_roles(prisma, guild_id, user_id).await?;
+
+    match target_type {
+        OVERWRITE_ROLE => {
+            let role_data = prisma
+                .role()
+                .find_unique(prisma::role::id::equals(target_id.to_owned()))
+                .exec()
+                .await?
+                .filter(|role_data| role_data.guild_id == guild_id)
+                .or_not_found(Resource::Role)?;
+
+            member.require_above(role_data.position)
+        }
+        OVERWRITE_MEMBER => {
+            permissions::require_above_member(prisma, guild_id, &member, target_id).await
+        }
+        _ => Err(ApiError::BadRequest(
+            "Overwrite type must be \"role\" or \"member\".".to_owned(),
+        )),
+    }
+}
+
+/// Archived threads are read-only until they are unarchived.
+pub fn require_unarchived(channel_data: &prisma::channel::Data) -> ApiResult<()> {
+    if channel_data.archived {
+        return Err(ApiError::BadRequest("This thread is archived.".to_owned()));
+    }
+
+    Ok(())
+}
+
+pub async fn find_channel_message(
+    prisma: &prisma::PrismaClient,
+    channel_id: &str,
+    message_id: String,
+) -> ApiResult<prisma::message::Data> {
+    prisma
+        .message()
+        .find_unique(prisma::message::id::equals(message_id))
+        .exec()
+        .await?
+        .filter(|message_data| message_data.channel_id == channel_id)
+        .or_not_found(Resource::Message)
+}
+
+pub async fn edit_channel_message(
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id)): Path<(String, String)>,
     Json(payload): Json<MessagePayload>,
-    Path(channel_id): Path<String>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let channel_query = prisma
+    let channel_data = prisma
         .channel()
         .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
         .exec()
-        .await;
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    let user_permissions = permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let message_data = find_channel_message(prisma, &channel_id, message_id).await?;
 
-    if channel_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Channel not found."})),
-        );
+    if message_data.author_id != user_data.id {
+        return Err(ApiError::Forbidden("Only the author can edit a message."));
     }
 
-    let channel_data = channel_query.unwrap().unwrap();
+    require_unarchived(&channel_data)?;
 
-    let user_memberships = user_data.memberships().unwrap();
+    let mentions =
+        mentions::resolve(prisma, &channel_data, user_permissions, &payload.content).await?;
 
-    let is_member = user_memberships
-        .iter()
-        .any(|membership| membership.guild_id == channel_data.guild_id);
+    let message_data = prisma
+        .message()
+        .find_unique(prisma::message::id::equals(message_data.id))
+        .update(vec![
+            prisma::message::content::set(payload.content),
+            prisma::message::edited_at::set(Some(Utc::now().into())),
+            prisma::message::mention_everyone::set(mentions.everyone),
+        ])
+        .exec()
+        .await?
+        .or_not_found(Resource::Message)?;
+
+    // edits keep the mentions up to date without notifying anyone again
+    mentions::store(prisma, &message_data.id, &mentions).await?;
+
+    let reply_to = reply_references(prisma, std::slice::from_ref(&message_data))
+        .await?
+        .into_values()
+        .next();
+
+    let attachments_data = prisma
+        .attachment()
+        .find_many(vec![prisma::attachment::message_id::equals(
+            message_data.id.to_owned(),
+        )])
+        .exec()
+        .await?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::MessageUpdate(
+            message_event(
+                &message_data,
+                user_data.username,
+                &mentions,
+                reply_to,
+                &attachments_data,
+            ),
+            channel_data.id,
+        ),
+    });
+
+    Ok((StatusCode::OK, Json(json!(message_data))))
+}
 
-    if !is_member {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(json!({"error": "User is not a member of the guild."})),
-        );
+pub async fn delete_channel_message(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    let user_permissions = permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let message_data = find_channel_message(prisma, &channel_id, message_id).await?;
+
+    // moderators can remove anyone's messages
+    if message_data.author_id != user_data.id {
+        user_permissions.require(Permissions::MANAGE_MESSAGES)?;
     }
 
-    let message_query = prisma
+    // attachments are removed along with the message, their blobs once swept
+    prisma
         .message()
-        .create(
-            prisma::message::content::set(payload.content),
-            prisma::message::author::link(prisma::user::UniqueWhereParam::IdEquals(user_data.id)),
-            prisma::message::channel::link(prisma::channel::UniqueWhereParam::IdEquals(channel_id)),
-            vec![],
-        )
+        .find_unique(prisma::message::id::equals(message_data.id.to_owned()))
+        .delete()
+        .exec()
+        .await?;
+
+    if message_data.pinned_at.is_some() {
+        publish_pins_update(&state, &channel_data.id).await?;
+    }
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::MessageDelete {
+            channel_id: channel_data.id,
+            id: message_data.id.to_owned(),
+        },
+    });
+
+    Ok((StatusCode::OK, Json(json!(message_data))))
+}
+
+pub async fn get_channel_overwrites(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(channel_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::MANAGE_CHANNELS,
+    )
+    .await?;
+
+    let overwrites_data = prisma
+        .channel_overwrite()
+        .find_many(vec![prisma::channel_overwrite::channel_id::equals(
+            channel_id,
+        )])
+        .exec()
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!(overwrites_data))))
+}
+
+pub async fn put_channel_overwrite(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, target_id)): Path<(String, String)>,
+    Json(payload): Json<OverwritePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
         .exec()
-        .await;
+        .await?
+        .or_not_found(Resource::Channel)?;
 
-    if message_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Message sending failed"})),
-        );
+    let guild_id = overwrite_guild_id(&channel_data)?;
+
+    let user_permissions = permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::MANAGE_CHANNELS,
+    )
+    .await?;
+
+    let (allow, deny) = match (
+        Permissions::from_bits(payload.allow),
+        Permissions::from_bits(payload.deny),
+    ) {
+        (Some(allow), Some(deny)) => (allow, deny),
+        _ => return Err(ApiError::BadRequest("Unknown permission bits.".to_owned())),
+    };
+
+    // members can only grant or take away permissions they hold themselves
+    user_permissions.require(allow | deny)?;
+
+    if payload.target_type == OVERWRITE_MEMBER {
+        prisma
+            .guild_membership()
+            .find_unique(
+                prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                    target_id.to_owned(),
+                    guild_id.to_owned(),
+                ),
+            )
+            .exec()
+            .await?
+            .or_not_found(Resource::Member)?;
     }
 
-    let message_data = message_query.unwrap();
-
-    state
-        .tx
-        .send(SocketPayload {
-            message: SocketMessageType::NewMessage(
-                SocketMessagePayload {
-                    msg_type: "new_message".to_owned(),
-                    author: User {
-                        id: message_data.clone().author_id,
-                        username: user_data.username,
-                    },
-                    content: message_data.clone().content,
-                    channel_id: message_data.clone().channel_id,
-                    created_at: message_data.created_at.to_rfc3339(),
-                    id: message_data.clone().id,
-                },
-                channel_data.id,
+    require_above_target(
+        prisma,
+        &guild_id,
+        &user_data.id,
+        &payload.target_type,
+        &target_id,
+    )
+    .await?;
+
+    let existing = prisma
+        .channel_overwrite()
+        .find_unique(
+            prisma::channel_overwrite::UniqueWhereParam::ChannelIdTargetIdEquals(
+                channel_id.to_owned(),
+                target_id.to_owned(),
             ),
-        })
-        .unwrap();
+        )
+        .exec()
+        .await?;
+
+    let overwrite_data = match existing {
+        Some(overwrite_data) => prisma
+            .channel_overwrite()
+            .find_unique(prisma::channel_overwrite::id::equals(overwrite_data.id))
+            .update(vec![
+                prisma::channel_overwrite::target_type::set(payload.target_type),
+                prisma::channel_overwrite::allow::set(allow.bits()),
+                prisma::channel_overwrite::deny::set(deny.bits()),
+            ])
+            .exec()
+            .await?
+            .or_not_found(Resource::Overwrite)?,
+        None => {
+            prisma
+                .channel_overwrite()
+                .create(
+                    prisma::channel_overwrite::target_type::set(payload.target_type),
+                    prisma::channel_overwrite::target_id::set(target_id),
+                    prisma::channel_overwrite::channel::link(
+                        prisma::channel::UniqueWhereParam::IdEquals(channel_id),
+                    ),
+                    vec![
+                        prisma::channel_overwrite::allow::set(allow.bits()),
+                        prisma::channel_overwrite::deny::set(deny.bits()),
+                    ],
+                )
+                .exec()
+                .await?
+        }
+    };
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(overwrite_data))))
+}
+
+pub async fn delete_channel_overwrite(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, target_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    let guild_id = overwrite_guild_id(&channel_data)?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::MANAGE_CHANNELS,
+    )
+    .await?;
+
+    let overwrite_data = prisma
+        .channel_overwrite()
+        .find_unique(
+            prisma::channel_overwrite::UniqueWhereParam::ChannelIdTargetIdEquals(
+                channel_id.to_owned(),
+                target_id.to_owned(),
+            ),
+        )
+        .exec()
+        .await?
+        .or_not_found(Resource::Overwrite)?;
+
+    require_above_target(
+        prisma,
+        &guild_id,
+        &user_data.id,
+        &overwrite_data.target_type,
+        &target_id,
+    )
+    .await?;
+
+    let overwrite_data = prisma
+        .channel_overwrite()
+        .find_unique(prisma::channel_overwrite::id::equals(overwrite_data.id))
+        .delete()
+        .exec()
+        .await?
+        .or_not_found(Resource::Overwrite)?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id),
+    });
 
-    (StatusCode::OK, Json(json!(message_data)))
+    Ok((StatusCode::OK, Json(json!(overwrite_data))))
 }
@@ -1,31 +1,97 @@
 use std::sync::Arc;
 
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use prisma_client_rust::{raw, PrismaValue};
 use serde::Deserialize;
 use serde_json::json;
 
-use crate::{prisma, State};
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    password::Verification,
+    permissions::{self, Permissions},
+    prisma, State,
+};
 
-use super::socket::{SocketMessageType, SocketPayload};
+use super::{
+    attachments::{read_uploads, store_uploads, MessageBody, INLINE_CONTENT_TYPES},
+    channels::CHANNEL_TEXT,
+    socket::{SocketMessageType, SocketPayload},
+};
+
+const MAX_GUILD_NAME_LENGTH: usize = 100;
+const MAX_GUILD_DESCRIPTION_LENGTH: usize = 1024;
+/// Icons are small images, whatever the attachment limit is.
+const MAX_ICON_SIZE: u64 = 1024 * 1024;
 
 #[derive(Deserialize)]
 pub struct ItemCreatePayload {
     name: String,
 }
 
+/// Fields left out stay as they are. Empty strings clear the optional ones. A
+/// new icon is uploaded as a file next to the payload, see `MessageBody`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuildUpdatePayload {
+    name: Option<String>,
+    description: Option<String>,
+    default_channel_id: Option<String>,
+    system_channel_id: Option<String>,
+    #[serde(default)]
+    remove_icon: bool,
+}
+
 #[derive(Deserialize)]
-pub struct InvitePayload {
-    code: String,
+#[serde(rename_all = "camelCase")]
+pub struct GuildTransferPayload {
+    /// the member who becomes the new owner
+    user_id: String,
+    /// the current owner's password
+    password: String,
+}
+
+/// An empty id clears the setting, anything else has to be a text channel of the guild.
+async fn guild_text_channel(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    channel_id: String,
+) -> ApiResult<Option<String>> {
+    if channel_id.is_empty() {
+        return Ok(None);
+    }
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id))
+        .exec()
+        .await?
+        .filter(|channel_data| channel_data.guild_id.as_deref() == Some(guild_id))
+        .or_not_found(Resource::Channel)?;
+
+    if channel_data.kind != CHANNEL_TEXT {
+        return Err(ApiError::BadRequest(
+            "Only text channels can be used here.".to_owned(),
+        ));
+    }
+
+    Ok(Some(channel_data.id))
 }
 
 pub async fn create_guild(
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
     Json(payload): Json<ItemCreatePayload>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let guild_query = prisma
+    // the @everyone role and the owner's membership come from a trigger, so a
+    // guild never exists without them
+    let guild_data = prisma
         .guild()
         .create(
             prisma::guild::name::set(payload.name),
@@ -35,71 +101,31 @@ pub async fn create_guild(
             vec![],
         )
         .exec()
-        .await;
+        .await?;
 
-    if guild_query.is_err() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "An error occured."})),
-        );
-    }
-
-    let guild_data = guild_query.unwrap();
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::UserGuildDataUpdate(user_data.clone().id),
+    });
 
-    prisma
-        .guild_membership()
-        .create(
-            prisma::guild_membership::user::link(prisma::user::UniqueWhereParam::IdEquals(
-                user_data.clone().id,
-            )),
-            prisma::guild_membership::guild::link(prisma::guild::UniqueWhereParam::IdEquals(
-                guild_data.clone().id,
-            )),
-            vec![],
-        )
-        .exec()
-        .await
-        .unwrap();
-
-    state
-        .tx
-        .send(SocketPayload {
-            message: SocketMessageType::UserGuildDataUpdate(user_data.clone().id),
-        })
-        .ok();
-
-    (StatusCode::CREATED, Json(json!(guild_data)))
+    Ok((StatusCode::CREATED, Json(json!(guild_data))))
 }
 
 pub async fn delete_guild(
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
     Path(guild_id): Path<String>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let guild_query = prisma
+    let guild_data = prisma
         .guild()
         .find_unique(prisma::guild::id::equals(guild_id.to_owned()))
         .with(prisma::guild::WithParam::Owner)
         .exec()
-        .await;
-
-    if guild_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Guild not found."})),
-        );
-    }
+        .await?
+        .or_not_found(Resource::Guild)?;
 
-    let guild_data = guild_query.unwrap().unwrap();
-
-    if guild_data.owner().unwrap().id != user_data.id {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(json!({"error": "You are not the owner of this guild."})),
-        );
-    }
+    permissions::require_owner(&guild_data, &user_data.id)?;
 
     prisma
         .guild()
@@ -108,17 +134,13 @@ pub async fn delete_guild(
         ))
         .delete()
         .exec()
-        .await
-        .unwrap();
+        .await?;
 
-    state
-        .tx
-        .send(SocketPayload {
-            message: SocketMessageType::GuildDataUpdate(guild_data.id.to_owned()),
-        })
-        .ok();
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_data.id.to_owned()),
+    });
 
-    (StatusCode::OK, Json(json!(guild_data)))
+    Ok((StatusCode::OK, Json(json!(guild_data))))
 }
 
 pub async fn create_channel(
@@ -126,150 +148,299 @@ pub async fn create_channel(
     Extension(user_data): Extension<prisma::user::Data>,
     Path(guild_id): Path<String>,
     Json(payload): Json<ItemCreatePayload>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let guild_query = prisma
+    let guild_data = prisma
         .guild()
         .find_unique(prisma::guild::id::equals(guild_id))
         .with(prisma::guild::WithParam::Channels(vec![]))
         .exec()
-        .await;
-
-    if guild_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Guild not found."})),
-        );
-    }
-
-    let guild_data = guild_query.unwrap().unwrap();
-
-    if guild_data.owner_id != user_data.id {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(
-                json!({"error": "You do not have management permissions in the specified guild."}),
-            ),
-        );
-    }
-
-    let channel_query = prisma
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    permissions::require(
+        prisma,
+        &guild_data.id,
+        &user_data.id,
+        Permissions::MANAGE_CHANNELS,
+    )
+    .await?;
+
+    let channel_data = prisma
         .channel()
         .create(
             prisma::channel::name::set(payload.name),
-            prisma::channel::guild::link(prisma::guild::UniqueWhereParam::IdEquals(
-                guild_data.clone().id,
-            )),
-            vec![],
+            vec![
+                prisma::channel::kind::set(CHANNEL_TEXT.to_owned()),
+                prisma::channel::guild::link(prisma::guild::UniqueWhereParam::IdEquals(
+                    guild_data.clone().id,
+                )),
+            ],
         )
         .exec()
-        .await;
+        .await?;
 
-    if channel_query.is_err() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "An error occured."})),
-        );
-    }
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_data.id.to_owned()),
+    });
+
+    Ok((StatusCode::OK, Json(json!(channel_data))))
+}
+
+/// Every member with their current presence, for member lists.
+pub async fn get_guild_members(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
 
-    let channel_data = channel_query.unwrap();
+    // fails for non-members
+    permissions::resolve(prisma, &guild_id, &user_data.id).await?;
 
-    state
-        .tx
-        .send(SocketPayload {
-            message: SocketMessageType::GuildDataUpdate(guild_data.id.to_owned()),
+    let members_data = prisma
+        .guild_membership()
+        .find_many(vec![prisma::guild_membership::guild_id::equals(guild_id)])
+        .with(prisma::guild_membership::WithParam::User)
+        .exec()
+        .await?;
+
+    let members: Vec<serde_json::Value> = members_data
+        .iter()
+        .map(|membership_data| {
+            let member_user = membership_data.user().unwrap();
+
+            json!({
+                "id": member_user.id,
+                "username": member_user.username,
+                "nickname": membership_data.nickname,
+                "presence": state.gateway.presence(&member_user.id),
+            })
         })
-        .ok();
+        .collect();
 
-    (StatusCode::OK, Json(json!(channel_data)))
+    Ok((StatusCode::OK, Json(json!(members))))
 }
 
-pub async fn create_invite(
+/// Changes a guild's settings. Accepts JSON, or a multipart form with the JSON
+/// in `payload_json` and the new icon as a file.
+pub async fn update_guild(
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
     Path(guild_id): Path<String>,
-) -> impl IntoResponse {
+    body: MessageBody<GuildUpdatePayload>,
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let guild_query = prisma
+    let guild_data = prisma
         .guild()
         .find_unique(prisma::guild::id::equals(guild_id))
-        .with(prisma::guild::WithParam::Channels(vec![]))
         .exec()
-        .await;
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    permissions::require(
+        prisma,
+        &guild_data.id,
+        &user_data.id,
+        Permissions::MANAGE_GUILD,
+    )
+    .await?;
+
+    let max_icon_size = MAX_ICON_SIZE.min(state.config.storage.max_attachment_size);
+    let (payload, mut uploads) = read_uploads(body, max_icon_size).await?;
+
+    if uploads.len() > 1 {
+        return Err(ApiError::BadRequest(
+            "Guilds can only have one icon.".to_owned(),
+        ));
+    }
 
-    if guild_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "Guild not found."})),
-        );
+    let mut updates = vec![];
+
+    if let Some(name) = payload.name {
+        let name = name.trim().to_owned();
+        if name.is_empty() || name.chars().count() > MAX_GUILD_NAME_LENGTH {
+            return Err(ApiError::BadRequest(format!(
+                "Guild names have to be between 1 and {} characters.",
+                MAX_GUILD_NAME_LENGTH
+            )));
+        }
+        updates.push(prisma::guild::name::set(name));
     }
 
-    let guild_data = guild_query.unwrap().unwrap();
+    if let Some(description) = payload.description {
+        let description = description.trim().to_owned();
+        if description.chars().count() > MAX_GUILD_DESCRIPTION_LENGTH {
+            return Err(ApiError::BadRequest(format!(
+                "Guild descriptions can be at most {} characters.",
+                MAX_GUILD_DESCRIPTION_LENGTH
+            )));
+        }
+        updates.push(prisma::guild::description::set(
+            Some(description).filter(|description| !description.is_empty()),
+        ));
+    }
 
-    let invite_query = prisma
-        .invite()
-        .create(
-            prisma::invite::creator::link(prisma::user::UniqueWhereParam::IdEquals(user_data.id)),
-            prisma::invite::guild::link(prisma::guild::UniqueWhereParam::IdEquals(guild_data.id)),
-            vec![],
-        )
+    if let Some(channel_id) = payload.default_channel_id {
+        let channel_id = guild_text_channel(prisma, &guild_data.id, channel_id).await?;
+        updates.push(prisma::guild::default_channel_id::set(channel_id));
+    }
+
+    if let Some(channel_id) = payload.system_channel_id {
+        let channel_id = guild_text_channel(prisma, &guild_data.id, channel_id).await?;
+        updates.push(prisma::guild::system_channel_id::set(channel_id));
+    }
+
+    let replaces_icon = payload.remove_icon || !uploads.is_empty();
+
+    if let Some(upload) = uploads.first() {
+        if !INLINE_CONTENT_TYPES.contains(&upload.content_type.as_str()) {
+            return Err(ApiError::BadRequest(
+                "Icons have to be PNG, JPEG, GIF or WebP images.".to_owned(),
+            ));
+        }
+    }
+
+    let _unswept = store_uploads(&state, &uploads).await?;
+
+    // the old icon's blob is swept once nothing else uses it
+    if replaces_icon {
+        let icon = uploads.pop();
+        updates.push(prisma::guild::icon::set(
+            icon.as_ref().map(|icon| icon.hash.to_owned()),
+        ));
+        updates.push(prisma::guild::icon_content_type::set(
+            icon.map(|icon| icon.content_type),
+        ));
+    }
+
+    let updated_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_data.id.to_owned()))
+        .update(updates)
         .exec()
-        .await;
+        .await?
+        .or_not_found(Resource::Guild)?;
 
-    let invite_data = invite_query.unwrap();
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(updated_data.id.to_owned()),
+    });
 
-    (StatusCode::OK, Json(json!(invite_data)))
+    Ok((StatusCode::OK, Json(json!(updated_data))))
 }
 
-pub async fn join_guild(
+/// Hands a guild to another member. Only the owner can, and has to confirm
+/// with their password.
+pub async fn transfer_guild(
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
-    Json(payload): Json<InvitePayload>,
-) -> impl IntoResponse {
+    Path(guild_id): Path<String>,
+    Json(payload): Json<GuildTransferPayload>,
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let invite_data = prisma
-        .invite()
-        .find_unique(prisma::invite::code::equals(payload.code))
-        .with(prisma::invite::WithParam::Guild)
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id))
         .exec()
-        .await
-        .unwrap()
-        .unwrap();
+        .await?
+        .or_not_found(Resource::Guild)?;
 
-    let guild_data = invite_data.guild().unwrap();
+    permissions::require_owner(&guild_data, &user_data.id)?;
 
-    let membership_query = prisma
-        .guild_membership()
-        .create(
-            prisma::guild_membership::user::link(prisma::user::UniqueWhereParam::IdEquals(
-                user_data.clone().id,
-            )),
-            prisma::guild_membership::guild::link(prisma::guild::UniqueWhereParam::IdEquals(
-                guild_data.clone().id,
-            )),
-            vec![],
-        )
-        .exec()
+    let verification = state
+        .passwords
+        .verify(payload.password, user_data.password.to_owned())
         .await;
 
-    if membership_query.is_err() {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": "An error occured."})),
-        );
+    if verification == Verification::Invalid {
+        return Err(ApiError::InvalidCredentials);
     }
 
-    let membership_data = membership_query.unwrap();
+    if payload.user_id == user_data.id {
+        return Err(ApiError::BadRequest(
+            "You already own this guild.".to_owned(),
+        ));
+    }
 
-    state
-        .tx
-        .send(SocketPayload {
-            message: SocketMessageType::UserGuildDataUpdate(user_data.clone().id),
-        })
-        .ok();
+    // one statement, so the guild never changes hands without the membership
+    // check. A trigger makes the new owner's membership permanent along with it.
+    let transferred = prisma
+        ._execute_raw(raw!(
+            r#"UPDATE "Guild" SET "ownerId" = {}
+            WHERE "id" = {} AND "ownerId" = {}
+                AND EXISTS (SELECT 1 FROM "GuildMembership"
+                    WHERE "GuildMembership"."guildId" = "Guild"."id"
+                        AND "GuildMembership"."userId" = {})"#,
+            PrismaValue::String(payload.user_id.to_owned()),
+            PrismaValue::String(guild_data.id.to_owned()),
+            PrismaValue::String(user_data.id.to_owned()),
+            PrismaValue::String(payload.user_id.to_owned())
+        ))
+        .await?;
+
+    // not a member, or the guild was handed to someone else in the meantime
+    if transferred == 0 {
+        return Err(ApiError::NotFound(Resource::Member));
+    }
 
-    (StatusCode::OK, Json(json!(membership_data)))
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_data.id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    // both of their permissions changed
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_data.id.to_owned()),
+    });
+    for user_id in [user_data.id, payload.user_id] {
+        state.gateway.publish(SocketPayload {
+            message: SocketMessageType::UserGuildDataUpdate(user_id),
+        });
+    }
+
+    Ok((StatusCode::OK, Json(json!(guild_data))))
+}
+
+/// A guild's icon, for its members.
+pub async fn get_guild_icon(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    // fails for non-members
+    permissions::resolve(prisma, &guild_id, &user_data.id).await?;
+
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    let (icon, content_type) = match (guild_data.icon, guild_data.icon_content_type) {
+        (Some(icon), Some(content_type)) => (icon, content_type),
+        _ => return Err(ApiError::NotFound(Resource::Attachment)),
+    };
+
+    let data = state
+        .storage
+        .get(&icon)
+        .await?
+        .or_not_found(Resource::Attachment)?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        ],
+        data,
+    ))
 }
@@ -0,0 +1,427 @@
+use std::{collections::BTreeSet, sync::Arc, time};
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use chrono::{Duration, Utc};
+use log::warn;
+use prisma_client_rust::{raw, PrismaValue};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{sync::mpsc, time::sleep};
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::{
+    members::{active_ban, remove_member},
+    socket::{SocketMessageType, SocketPayload},
+};
+
+/// Invites can last at most a week, or forever with a `maxAge` of 0.
+const MAX_INVITE_AGE_SECS: i32 = 7 * 24 * 60 * 60;
+const MAX_INVITE_USES: i32 = 100;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteCreatePayload {
+    /// seconds until the invite expires, 0 for never
+    #[serde(default)]
+    max_age: i32,
+    /// 0 for unlimited
+    #[serde(default)]
+    max_uses: i32,
+    /// members who joined with the invite are removed when they disconnect,
+    /// unless they were given a role in the meantime
+    #[serde(default)]
+    temporary: bool,
+}
+
+#[derive(Deserialize)]
+pub struct InvitePayload {
+    code: String,
+}
+
+/// Expired and used up invites behave as if they didn't exist.
+fn is_usable(invite_data: &prisma::invite::Data) -> bool {
+    let expired = invite_data
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= Utc::now());
+    let used_up = invite_data.max_uses > 0 && invite_data.uses >= invite_data.max_uses;
+
+    !expired && !used_up
+}
+
+async fn find_usable_invite(
+    prisma: &prisma::PrismaClient,
+    code: String,
+) -> ApiResult<prisma::invite::Data> {
+    prisma
+        .invite()
+        .find_unique(prisma::invite::code::equals(code))
+        .with(prisma::invite::WithParam::Guild)
+        .exec()
+        .await?
+        .filter(is_usable)
+        .or_not_found(Resource::Invite)
+}
+
+pub async fn create_invite(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+    payload: Option<Json<InviteCreatePayload>>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    // older clients send no body, for an invite that never runs out
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+
+    let guild_data = prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)?;
+
+    permissions::require(
+        prisma,
+        &guild_data.id,
+        &user_data.id,
+        Permissions::CREATE_INVITES,
+    )
+    .await?;
+
+    if !(0..=MAX_INVITE_AGE_SECS).contains(&payload.max_age) {
+        return Err(ApiError::BadRequest(format!(
+            "maxAge has to be between 0 and {} seconds.",
+            MAX_INVITE_AGE_SECS
+        )));
+    }
+
+    if !(0..=MAX_INVITE_USES).contains(&payload.max_uses) {
+        return Err(ApiError::BadRequest(format!(
+            "maxUses has to be between 0 and {}.",
+            MAX_INVITE_USES
+        )));
+    }
+
+    let expires_at = if payload.max_age > 0 {
+        Some((Utc::now() + Duration::seconds(payload.max_age.into())).into())
+    } else {
+        None
+    };
+
+    let invite_data = prisma
+        .invite()
+        .create(
+            prisma::invite::creator::link(prisma::user::UniqueWhereParam::IdEquals(user_data.id)),
+            prisma::invite::guild::link(prisma::guild::UniqueWhereParam::IdEquals(guild_data.id)),
+            vec![
+                prisma::invite::max_age::set(payload.max_age),
+                prisma::invite::max_uses::set(payload.max_uses),
+                prisma::invite::temporary::set(payload.temporary),
+                prisma::invite::expires_at::set(expires_at),
+            ],
+        )
+        .exec()
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!(invite_data))))
+}
+
+/// Every invite of a guild, including expired and used up ones.
+pub async fn get_guild_invites(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    permissions::require(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD).await?;
+
+    let invites_data = prisma
+        .invite()
+        .find_many(vec![prisma::invite::guild_id::equals(guild_id)])
+        .exec()
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!(invites_data))))
+}
+
+#[derive(Deserialize)]
+struct MemberCount {
+    count: i64,
+}
+
+/// What an invite leads to, shown before joining. Doesn't need an account.
+pub async fn get_invite(
+    Extension(state): Extension<Arc<State>>,
+    Path(code): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let invite_data = find_usable_invite(prisma, code).await?;
+    let guild_data = invite_data.guild().unwrap();
+
+    let member_count = prisma
+        ._query_raw::<MemberCount>(raw!(
+            r#"SELECT COUNT(*) AS "count" FROM "GuildMembership" WHERE "guildId" = {}"#,
+            PrismaValue::String(guild_data.id.to_owned())
+        ))
+        .await?
+        .first()
+        .map_or(0, |member_count| member_count.count);
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "code": invite_data.code,
+            "expiresAt": invite_data.expires_at,
+            "temporary": invite_data.temporary,
+            "guild": {
+                "id": guild_data.id,
+                "name": guild_data.name,
+            },
+            "memberCount": member_count,
+        })),
+    ))
+}
+
+/// Revokes an invite. Whoever created it can, and so can guild managers.
+pub async fn delete_invite(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(code): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let invite_data = prisma
+        .invite()
+        .find_unique(prisma::invite::code::equals(code))
+        .exec()
+        .await?
+        .or_not_found(Resource::Invite)?;
+
+    if invite_data.user_id != user_data.id {
+        permissions::require(
+            prisma,
+            &invite_data.guild_id,
+            &user_data.id,
+            Permissions::MANAGE_GUILD,
+        )
+        .await?;
+    }
+
+    prisma
+        .invite()
+        .find_unique(prisma::invite::code::equals(invite_data.code.to_owned()))
+        .delete()
+        .exec()
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!(invite_data))))
+}
+
+/// Gives back a use claimed by a join that didn't go through.
+async fn release_invite_use(prisma: &prisma::PrismaClient, code: PrismaValue) -> ApiResult<()> {
+    prisma
+        ._execute_raw(raw!(
+            r#"UPDATE "Invite" SET "uses" = "uses" - 1 WHERE "code" = {} AND "uses" > 0"#,
+            code
+        ))
+        .await?;
+
+    Ok(())
+}
+
+pub async fn join_guild(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Json(payload): Json<InvitePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let invite_data = find_usable_invite(prisma, payload.code).await?;
+    let guild_data = invite_data.guild().unwrap();
+
+    let code = PrismaValue::String(invite_data.code.to_owned());
+    let user_id = PrismaValue::String(user_data.id.to_owned());
+    let guild_id = PrismaValue::String(guild_data.id.to_owned());
+    let now = PrismaValue::DateTime(Utc::now().into());
+
+    // claims a use in a single statement that also checks everything else that
+    // keeps the user out, so concurrent joins can't go over maxUses and nothing
+    // changes between the checks and the claim
+    let claimed = prisma
+        ._execute_raw(raw!(
+            r#"UPDATE "Invite" SET "uses" = "uses" + 1
+            WHERE "code" = {}
+                AND ("maxUses" = 0 OR "uses" < "maxUses")
+                AND ("Invite"."expiresAt" IS NULL OR "Invite"."expiresAt" > {})
+                AND NOT EXISTS (SELECT 1 FROM "GuildMembership"
+                    WHERE "userId" = {} AND "guildId" = {})
+                AND NOT EXISTS (SELECT 1 FROM "Ban"
+                    WHERE "userId" = {} AND "guildId" = {}
+                        AND ("Ban"."expiresAt" IS NULL OR "Ban"."expiresAt" > {}))"#,
+            code.clone(),
+            now.clone(),
+            user_id.clone(),
+            guild_id.clone(),
+            user_id.clone(),
+            guild_id.clone(),
+            now.clone()
+        ))
+        .await?;
+
+    // nothing was claimed, work out why
+    if claimed == 0 {
+        let existing = prisma
+            .guild_membership()
+            .find_unique(
+                prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                    user_data.id.to_owned(),
+                    guild_data.id.to_owned(),
+                ),
+            )
+            .exec()
+            .await?;
+
+        if existing.is_some() {
+            return Err(ApiError::Conflict(
+                "You are already a member of this guild.",
+            ));
+        }
+
+        if active_ban(prisma, &guild_data.id, &user_data.id)
+            .await?
+            .is_some()
+        {
+            return Err(ApiError::Forbidden("You are banned from this guild."));
+        }
+
+        return Err(ApiError::NotFound(Resource::Invite));
+    }
+
+    // the ban check is repeated in the insert itself, so a ban landing after
+    // the claim still keeps the user out
+    let joined = prisma
+        ._execute_raw(raw!(
+            r#"INSERT INTO "GuildMembership" ("userId", "guildId", "createdAt", "temporary")
+            SELECT {}, {}, {}, {}
+            WHERE NOT EXISTS (SELECT 1 FROM "Ban"
+                WHERE "userId" = {} AND "guildId" = {}
+                    AND ("Ban"."expiresAt" IS NULL OR "Ban"."expiresAt" > {}))"#,
+            user_id.clone(),
+            guild_id.clone(),
+            now.clone(),
+            PrismaValue::Boolean(invite_data.temporary),
+            user_id,
+            guild_id,
+            now
+        ))
+        .await;
+
+    // nobody joined, so the use is given back
+    match joined {
+        Ok(0) => {
+            release_invite_use(prisma, code).await?;
+            return Err(ApiError::Forbidden("You are banned from this guild."));
+        }
+        Err(err) => {
+            release_invite_use(prisma, code).await?;
+            return Err(err.into());
+        }
+        Ok(_) => {}
+    }
+
+    let membership_data = prisma
+        .guild_membership()
+        .find_unique(
+            prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                user_data.id.to_owned(),
+                guild_data.id.to_owned(),
+            ),
+        )
+        .exec()
+        .await?
+        .or_not_found(Resource::Member)?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::UserGuildDataUpdate(user_data.id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(membership_data))))
+}
+
+/// Removes the user from guilds they joined with a temporary invite and weren't
+/// given a role in, unless they reconnected in the meantime.
+async fn remove_temporary_memberships(state: &State, user_id: &str) -> ApiResult<()> {
+    if state.gateway.is_connected(user_id) {
+        return Ok(());
+    }
+
+    let memberships_data = state
+        .prisma
+        .guild_membership()
+        .find_many(vec![
+            prisma::guild_membership::user_id::equals(user_id.to_owned()),
+            prisma::guild_membership::temporary::equals(true),
+        ])
+        .with(prisma::guild_membership::WithParam::Roles(vec![]))
+        .exec()
+        .await?;
+
+    for membership_data in memberships_data {
+        if !membership_data.roles().unwrap().is_empty() {
+            continue;
+        }
+
+        remove_member(state, &membership_data.guild_id, user_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Temporary members only stay while they are connected, so they are removed
+/// once their last gateway session is gone, as reported in `departures`.
+/// Nobody is connected after a restart: whoever hasn't reconnected by the time
+/// their session could have been resumed is removed too.
+pub async fn remove_departed_temporary_members(
+    state: Arc<State>,
+    mut departures: mpsc::UnboundedReceiver<String>,
+) {
+    sleep(time::Duration::from_secs(
+        state.config.gateway.resume_timeout_secs,
+    ))
+    .await;
+
+    let memberships_data = state
+        .prisma
+        .guild_membership()
+        .find_many(vec![prisma::guild_membership::temporary::equals(true)])
+        .exec()
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Could not look up temporary members: {:?}", err);
+            vec![]
+        });
+
+    let user_ids: BTreeSet<String> = memberships_data
+        .into_iter()
+        .map(|membership_data| membership_data.user_id)
+        .collect();
+
+    for user_id in user_ids {
+        if let Err(err) = remove_temporary_memberships(&state, &user_id).await {
+            warn!("Could not remove temporary memberships: {:?}", err);
+        }
+    }
+
+    while let Some(user_id) = departures.recv().await {
+        if let Err(err) = remove_temporary_memberships(&state, &user_id).await {
+            warn!("Could not remove temporary memberships: {:?}", err);
+        }
+    }
+}
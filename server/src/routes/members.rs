@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use prisma_client_rust::{raw, PrismaValue};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::socket::{SocketMessageType, SocketPayload};
+
+const MAX_BAN_REASON_LENGTH: usize = 512;
+/// Temporary bans last at most a year, longer ones should be permanent.
+const MAX_BAN_DURATION_SECS: i64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BanPayload {
+    reason: Option<String>,
+    /// seconds until the ban is lifted on its own, unset for a permanent ban
+    duration_secs: Option<i64>,
+}
+
+async fn find_guild(
+    prisma: &prisma::PrismaClient,
+    guild_id: String,
+) -> ApiResult<prisma::guild::Data> {
+    prisma
+        .guild()
+        .find_unique(prisma::guild::id::equals(guild_id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Guild)
+}
+
+/// Removes a member from a guild, and tells them and the rest of the guild.
+pub async fn remove_member(state: &State, guild_id: &str, user_id: &str) -> ApiResult<()> {
+    // their roles go with the membership
+    state
+        .prisma
+        .guild_membership()
+        .find_unique(
+            prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                user_id.to_owned(),
+                guild_id.to_owned(),
+            ),
+        )
+        .delete()
+        .exec()
+        .await?
+        .or_not_found(Resource::Member)?;
+
+    // read states aren't tied to the membership, but are of no use without it
+    state
+        .prisma
+        ._execute_raw(raw!(
+            r#"DELETE FROM "ReadState" WHERE "userId" = {}
+            AND "channelId" IN (SELECT "id" FROM "Channel" WHERE "guildId" = {})"#,
+            PrismaValue::String(user_id.to_owned()),
+            PrismaValue::String(guild_id.to_owned())
+        ))
+        .await?;
+
+    // the user's sessions refresh their guilds, which unsubscribes them
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::UserGuildDataUpdate(user_id.to_owned()),
+    });
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id.to_owned()),
+    });
+
+    Ok(())
+}
+
+/// Members can't act on themselves, the owner or anyone whose highest role
+/// isn't below theirs through moderation routes.
+async fn require_moderatable(
+    prisma: &prisma::PrismaClient,
+    guild_data: &prisma::guild::Data,
+    member: &permissions::MemberRoles,
+    user_data: &prisma::user::Data,
+    target_id: &str,
+) -> ApiResult<()> {
+    if target_id == user_data.id {
+        return Err(ApiError::BadRequest(
+            "You can't do this to yourself.".to_owned(),
+        ));
+    }
+
+    if permissions::is_owner(guild_data, target_id) {
+        return Err(ApiError::Forbidden(
+            "The owner of a guild can't be removed.",
+        ));
+    }
+
+    permissions::require_above_member(prisma, &guild_data.id, member, target_id).await
+}
+
+/// The ban keeping `user_id` out of `guild_id`, if any. Expired bans are cleaned up.
+pub async fn active_ban(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    user_id: &str,
+) -> ApiResult<Option<prisma::ban::Data>> {
+    let ban_data = prisma
+        .ban()
+        .find_unique(prisma::ban::UniqueWhereParam::GuildIdUserIdEquals(
+            guild_id.to_owned(),
+            user_id.to_owned(),
+        ))
+        .exec()
+        .await?;
+
+    let ban_data = match ban_data {
+        Some(ban_data) => ban_data,
+        None => return Ok(None),
+    };
+
+    let expired = ban_data
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= Utc::now());
+
+    if !expired {
+        return Ok(Some(ban_data));
+    }
+
+    prisma
+        .ban()
+        .find_unique(prisma::ban::UniqueWhereParam::GuildIdUserIdEquals(
+            guild_id.to_owned(),
+            user_id.to_owned(),
+        ))
+        .delete()
+        .exec()
+        .await?;
+
+    Ok(None)
+}
+
+/// The owner has to transfer the guild before they can leave it.
+pub async fn leave_guild(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let guild_data = find_guild(&state.prisma, guild_id).await?;
+
+    if permissions::is_owner(&guild_data, &user_data.id) {
+        return Err(ApiError::Forbidden(
+            "Transfer ownership of the guild before leaving it.",
+        ));
+    }
+
+    remove_member(&state, &guild_data.id, &user_data.id).await?;
+
+    Ok((StatusCode::OK, Json(json!(guild_data))))
+}
+
+pub async fn kick_member(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, member_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let guild_data = find_guild(prisma, guild_id).await?;
+
+    let member = permissions::require_member(
+        prisma,
+        &guild_data.id,
+        &user_data.id,
+        Permissions::KICK_MEMBERS,
+    )
+    .await?;
+
+    require_moderatable(prisma, &guild_data, &member, &user_data, &member_id).await?;
+
+    remove_member(&state, &guild_data.id, &member_id).await?;
+
+    Ok((StatusCode::OK, Json(json!({ "userId": member_id }))))
+}
+
+pub async fn get_bans(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    permissions::require(prisma, &guild_id, &user_data.id, Permissions::BAN_MEMBERS).await?;
+
+    let bans_data = prisma
+        .ban()
+        .find_many(vec![prisma::ban::guild_id::equals(guild_id)])
+        .with(prisma::ban::WithParam::User)
+        .exec()
+        .await?;
+
+    let bans: Vec<serde_json::Value> = bans_data
+        .iter()
+        .map(|ban_data| {
+            let banned_user = ban_data.user().unwrap();
+
+            json!({
+                "user": {
+                    "id": banned_user.id,
+                    "username": banned_user.username,
+                },
+                "reason": ban_data.reason,
+                "createdAt": ban_data.created_at,
+                "expiresAt": ban_data.expires_at,
+            })
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!(bans))))
+}
+
+/// Bans a user, removing them from the guild if they are a member. Users who
+/// aren't members can be banned too, to keep them from joining. Banning again
+/// replaces the reason and expiry.
+pub async fn ban_member(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, member_id)): Path<(String, String)>,
+    payload: Option<Json<BanPayload>>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+
+    let guild_data = find_guild(prisma, guild_id).await?;
+
+    let member = permissions::require_member(
+        prisma,
+        &guild_data.id,
+        &user_data.id,
+        Permissions::BAN_MEMBERS,
+    )
+    .await?;
+
+    require_moderatable(prisma, &guild_data, &member, &user_data, &member_id).await?;
+
+    let reason = payload
+        .reason
+        .map(|reason| reason.trim().to_owned())
+        .filter(|reason| !reason.is_empty());
+
+    if reason.as_ref().map_or(false, |reason| {
+        reason.chars().count() > MAX_BAN_REASON_LENGTH
+    }) {
+        return Err(ApiError::BadRequest(format!(
+            "Ban reasons can be at most {} characters.",
+            MAX_BAN_REASON_LENGTH
+        )));
+    }
+
+    let expires_at: Option<DateTime<FixedOffset>> = match payload.duration_secs {
+        Some(duration_secs) if !(1..=MAX_BAN_DURATION_SECS).contains(&duration_secs) => {
+            return Err(ApiError::BadRequest(format!(
+                "Ban durations have to be between 1 and {} seconds.",
+                MAX_BAN_DURATION_SECS
+            )))
+        }
+        Some(duration_secs) => Some((Utc::now() + Duration::seconds(duration_secs)).into()),
+        None => None,
+    };
+
+    prisma
+        .user()
+        .find_unique(prisma::user::id::equals(member_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::User)?;
+
+    // a single statement, so concurrent bans of the same user don't conflict
+    prisma
+        ._execute_raw(raw!(
+            r#"INSERT INTO "Ban" ("guildId", "userId", "reason", "createdAt", "expiresAt")
+            VALUES ({}, {}, {}, {}, {})
+            ON CONFLICT ("guildId", "userId")
+            DO UPDATE SET "reason" = excluded."reason", "expiresAt" = excluded."expiresAt""#,
+            PrismaValue::String(guild_data.id.to_owned()),
+            PrismaValue::String(member_id.to_owned()),
+            reason.map(PrismaValue::String).unwrap_or(PrismaValue::Null),
+            PrismaValue::DateTime(Utc::now().into()),
+            expires_at
+                .map(PrismaValue::DateTime)
+                .unwrap_or(PrismaValue::Null)
+        ))
+        .await?;
+
+    let ban_data = prisma
+        .ban()
+        .find_unique(prisma::ban::UniqueWhereParam::GuildIdUserIdEquals(
+            guild_data.id.to_owned(),
+            member_id.to_owned(),
+        ))
+        .exec()
+        .await?
+        .or_not_found(Resource::Ban)?;
+
+    let is_member = prisma
+        .guild_membership()
+        .find_unique(
+            prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                member_id.to_owned(),
+                guild_data.id.to_owned(),
+            ),
+        )
+        .exec()
+        .await?
+        .is_some();
+
+    if is_member {
+        remove_member(&state, &guild_data.id, &member_id).await?;
+    }
+
+    Ok((StatusCode::OK, Json(json!(ban_data))))
+}
+
+pub async fn unban_member(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, member_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    permissions::require(prisma, &guild_id, &user_data.id, Permissions::BAN_MEMBERS).await?;
+
+    let ban_data = prisma
+        .ban()
+        .find_unique(prisma::ban::UniqueWhereParam::GuildIdUserIdEquals(
+            guild_id, member_id,
+        ))
+        .delete()
+        .exec()
+        .await?
+        .or_not_found(Resource::Ban)?;
+
+    Ok((StatusCode::OK, Json(json!(ban_data))))
+}
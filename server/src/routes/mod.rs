@@ -1,5 +1,14 @@
+pub mod attachments;
 pub mod auth;
 pub mod channels;
 pub mod guilds;
+pub mod invites;
+pub mod members;
+pub mod pins;
+pub mod reactions;
+pub mod read_states;
+pub mod roles;
+pub mod search;
 pub mod socket;
+pub mod threads;
 pub mod users;
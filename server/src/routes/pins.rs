@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use chrono::Utc;
+use prisma_client_rust::{raw, Direction, PrismaValue};
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::{
+    channels::{find_channel_message, message_json, message_relations, require_unarchived},
+    socket::{SocketMessageType, SocketPayload},
+};
+
+/// Channels can have at most this many pinned messages.
+const MAX_PINS: i64 = 50;
+
+async fn find_channel(
+    prisma: &prisma::PrismaClient,
+    channel_id: String,
+) -> ApiResult<prisma::channel::Data> {
+    prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)
+}
+
+async fn find_pinned(
+    prisma: &prisma::PrismaClient,
+    channel_id: &str,
+) -> ApiResult<Vec<prisma::message::Data>> {
+    Ok(prisma
+        .message()
+        .find_many(vec![
+            prisma::message::channel_id::equals(channel_id.to_owned()),
+            prisma::message::pinned_at::not(None),
+        ])
+        .with(prisma::message::WithParam::Author)
+        .with(prisma::message::WithParam::Mentions(vec![]))
+        .with(prisma::message::WithParam::RoleMentions(vec![]))
+        .with(prisma::message::WithParam::Attachments(vec![]))
+        .order_by(prisma::message::pinned_at::order(Direction::Desc))
+        .exec()
+        .await?)
+}
+
+/// Tells everyone watching the channel to refetch its pins.
+pub async fn publish_pins_update(state: &State, channel_id: &str) -> ApiResult<()> {
+    let last_pin_timestamp = state
+        .prisma
+        .message()
+        .find_many(vec![
+            prisma::message::channel_id::equals(channel_id.to_owned()),
+            prisma::message::pinned_at::not(None),
+        ])
+        .order_by(prisma::message::pinned_at::order(Direction::Desc))
+        .take(1)
+        .exec()
+        .await?
+        .first()
+        .and_then(|message_data| message_data.pinned_at)
+        .map(|pinned_at| pinned_at.to_rfc3339());
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::ChannelPinsUpdate {
+            channel_id: channel_id.to_owned(),
+            last_pin_timestamp,
+        },
+    });
+
+    Ok(())
+}
+
+/// Pinned messages of a channel, most recently pinned first.
+pub async fn get_pins(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(channel_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = find_channel(prisma, channel_id).await?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let messages_data = find_pinned(prisma, &channel_data.id).await?;
+    let relations = message_relations(prisma, &messages_data, &user_data.id).await?;
+
+    let messages: Vec<serde_json::Value> = messages_data
+        .iter()
+        .map(|message_data| message_json(message_data, &relations))
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!(messages))))
+}
+
+/// Pinning an already pinned message does nothing.
+pub async fn pin_message(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = find_channel(prisma, channel_id).await?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::MANAGE_MESSAGES,
+    )
+    .await?;
+
+    require_unarchived(&channel_data)?;
+
+    let message_data = find_channel_message(prisma, &channel_data.id, message_id).await?;
+
+    if message_data.pinned_at.is_some() {
+        return Ok((StatusCode::OK, Json(json!(message_data))));
+    }
+
+    // the cap is checked in the same statement that pins, so concurrent pins
+    // can't go over it
+    let pinned = prisma
+        ._execute_raw(raw!(
+            r#"UPDATE "Message" SET "pinnedAt" = {}
+            WHERE "id" = {} AND "pinnedAt" IS NULL
+                AND (SELECT COUNT(*) FROM "Message"
+                    WHERE "channelId" = {} AND "pinnedAt" IS NOT NULL) < {}"#,
+            PrismaValue::DateTime(Utc::now().into()),
+            PrismaValue::String(message_data.id.to_owned()),
+            PrismaValue::String(channel_data.id.to_owned()),
+            PrismaValue::Int(MAX_PINS)
+        ))
+        .await?;
+
+    let message_data = prisma
+        .message()
+        .find_unique(prisma::message::id::equals(message_data.id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Message)?;
+
+    if pinned == 0 {
+        // somebody else pinned it in the meantime
+        if message_data.pinned_at.is_some() {
+            return Ok((StatusCode::OK, Json(json!(message_data))));
+        }
+
+        return Err(ApiError::BadRequest(format!(
+            "Channels can have at most {} pinned messages.",
+            MAX_PINS
+        )));
+    }
+
+    publish_pins_update(&state, &channel_data.id).await?;
+
+    Ok((StatusCode::OK, Json(json!(message_data))))
+}
+
+/// Unpinning a message that isn't pinned does nothing.
+pub async fn unpin_message(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = find_channel(prisma, channel_id).await?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::MANAGE_MESSAGES,
+    )
+    .await?;
+
+    let message_data = find_channel_message(prisma, &channel_data.id, message_id).await?;
+
+    if message_data.pinned_at.is_none() {
+        return Ok((StatusCode::OK, Json(json!(message_data))));
+    }
+
+    let message_data = prisma
+        .message()
+        .find_unique(prisma::message::id::equals(message_data.id))
+        .update(vec![prisma::message::pinned_at::set(None)])
+        .exec()
+        .await?
+        .or_not_found(Resource::Message)?;
+
+    publish_pins_update(&state, &channel_data.id).await?;
+
+    Ok((StatusCode::OK, Json(json!(message_data))))
+}
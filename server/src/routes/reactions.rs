@@ -0,0 +1,399 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use prisma_client_rust::{raw, Direction, PrismaValue};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::{
+    channels::{find_channel_message, require_unarchived},
+    socket::{SocketMessageType, SocketPayload},
+};
+
+/// Messages can have at most this many different emoji reacted with.
+const MAX_EMOJI_PER_MESSAGE: i64 = 20;
+const MAX_EMOJI_LENGTH: usize = 32;
+const MAX_REACTORS: i64 = 100;
+
+/// How many people reacted to a message with an emoji, in the REST API's message shape.
+#[derive(Serialize)]
+pub struct ReactionCount {
+    emoji: String,
+    count: u32,
+    /// whether the current user is one of them
+    me: bool,
+}
+
+/// One row of `reaction_counts`' aggregate query.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReactionCountRow {
+    message_id: String,
+    emoji: String,
+    count: i64,
+    me: i64,
+}
+
+/// Aggregated reactions for each of `message_ids`, keyed by message id. Emoji
+/// are ordered by when they were first reacted with.
+pub async fn reaction_counts(
+    prisma: &prisma::PrismaClient,
+    message_ids: Vec<String>,
+    user_id: &str,
+) -> ApiResult<HashMap<String, Vec<ReactionCount>>> {
+    let rows: Vec<ReactionCountRow> = prisma
+        ._query_raw(raw!(
+            r#"SELECT "messageId", "emoji", COUNT(*) AS "count", MAX("userId" = {}) AS "me"
+            FROM "Reaction"
+            WHERE "messageId" IN (SELECT "value" FROM json_each({}))
+            GROUP BY "messageId", "emoji"
+            ORDER BY MIN("createdAt") ASC"#,
+            PrismaValue::String(user_id.to_owned()),
+            PrismaValue::String(json!(message_ids).to_string())
+        ))
+        .await?;
+
+    let mut counts: HashMap<String, Vec<ReactionCount>> = HashMap::new();
+
+    for row in rows {
+        counts
+            .entry(row.message_id)
+            .or_default()
+            .push(ReactionCount {
+                emoji: row.emoji,
+                count: row.count as u32,
+                me: row.me != 0,
+            });
+    }
+
+    Ok(counts)
+}
+
+/// How many different emoji a message has, and whether one of them is being reacted with.
+#[derive(Deserialize)]
+struct EmojiUsage {
+    count: i64,
+    used: i64,
+}
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+const VARIATION_SELECTOR: char = '\u{FE0F}';
+const KEYCAP: char = '\u{20E3}';
+const BLACK_FLAG: char = '\u{1F3F4}';
+const CANCEL_TAG: char = '\u{E007F}';
+
+/// Whether `emoji` is a single Unicode emoji: a pictograph or a zero width
+/// joiner sequence of them, a keycap, or a flag.
+fn is_emoji(emoji: &str) -> bool {
+    let chars: Vec<char> = emoji.chars().collect();
+
+    // country flags are pairs of regional indicator letters
+    if chars.iter().any(|c| is_regional_indicator(*c)) {
+        return chars.len() == 2 && chars.iter().all(|c| is_regional_indicator(*c));
+    }
+
+    match chars.as_slice() {
+        [base, KEYCAP] | [base, VARIATION_SELECTOR, KEYCAP] => {
+            base.is_ascii_digit() || *base == '#' || *base == '*'
+        }
+        _ => emoji.split(ZERO_WIDTH_JOINER).all(is_emoji_element),
+    }
+}
+
+/// One pictograph with its optional presentation selector and skin tone, or
+/// a subdivision flag like England's.
+fn is_emoji_element(element: &str) -> bool {
+    let mut chars = element.chars().peekable();
+
+    let base = match chars.next() {
+        Some(base) if is_pictograph(base) => base,
+        _ => return false,
+    };
+
+    chars.next_if_eq(&VARIATION_SELECTOR);
+    chars.next_if(|c| is_skin_tone(*c));
+
+    let tags: Vec<char> = chars.collect();
+
+    match tags.split_last() {
+        None => true,
+        Some((&CANCEL_TAG, letters)) => {
+            base == BLACK_FLAG
+                && !letters.is_empty()
+                && letters
+                    .iter()
+                    .all(|c| ('\u{E0020}'..='\u{E007E}').contains(c))
+        }
+        Some(_) => false,
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+fn is_skin_tone(c: char) -> bool {
+    ('\u{1F3FB}'..='\u{1F3FF}').contains(&c)
+}
+
+/// Code points that are emoji on their own, see Unicode's `Extended_Pictographic`.
+fn is_pictograph(c: char) -> bool {
+    matches!(c,
+        '\u{A9}' | '\u{AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{2199}' | '\u{21A9}'..='\u{21AA}' | '\u{231A}'..='\u{231B}'
+        | '\u{2328}' | '\u{23CF}' | '\u{23E9}'..='\u{23F3}' | '\u{23F8}'..='\u{23FA}'
+        | '\u{24C2}' | '\u{25AA}'..='\u{25AB}' | '\u{25B6}' | '\u{25C0}'
+        | '\u{25FB}'..='\u{25FE}' | '\u{2600}'..='\u{27BF}' | '\u{2934}'..='\u{2935}'
+        | '\u{2B05}'..='\u{2B07}' | '\u{2B1B}'..='\u{2B1C}' | '\u{2B50}' | '\u{2B55}'
+        | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}' | '\u{1F000}'..='\u{1FAFF}')
+        && !is_regional_indicator(c)
+        && !is_skin_tone(c)
+}
+
+/// Looks up the channel and message a reaction is for, checking the user can see them.
+async fn find_reaction_target(
+    prisma: &prisma::PrismaClient,
+    user_data: &prisma::user::Data,
+    channel_id: String,
+    message_id: String,
+) -> ApiResult<(prisma::channel::Data, prisma::message::Data)> {
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let message_data = find_channel_message(prisma, &channel_data.id, message_id).await?;
+
+    Ok((channel_data, message_data))
+}
+
+fn reaction_event(reaction_data: &prisma::reaction::Data, channel_id: &str) -> protocol::Reaction {
+    protocol::Reaction {
+        channel_id: channel_id.to_owned(),
+        message_id: reaction_data.message_id.to_owned(),
+        user_id: reaction_data.user_id.to_owned(),
+        emoji: reaction_data.emoji.to_owned(),
+    }
+}
+
+/// Reacting twice with the same emoji does nothing.
+pub async fn add_reaction(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id, emoji)): Path<(String, String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let (channel_data, message_data) =
+        find_reaction_target(prisma, &user_data, channel_id, message_id).await?;
+
+    require_unarchived(&channel_data)?;
+
+    if emoji.len() > MAX_EMOJI_LENGTH || !is_emoji(&emoji) {
+        return Err(ApiError::BadRequest("Invalid emoji.".to_owned()));
+    }
+
+    let existing = prisma
+        .reaction()
+        .find_unique(
+            prisma::reaction::UniqueWhereParam::MessageIdUserIdEmojiEquals(
+                message_data.id.to_owned(),
+                user_data.id.to_owned(),
+                emoji.to_owned(),
+            ),
+        )
+        .exec()
+        .await?;
+
+    if let Some(reaction_data) = existing {
+        return Ok((StatusCode::OK, Json(json!(reaction_data))));
+    }
+
+    let usage: Vec<EmojiUsage> = prisma
+        ._query_raw(raw!(
+            r#"SELECT COUNT(DISTINCT "emoji") AS "count",
+                EXISTS (SELECT 1 FROM "Reaction" WHERE "messageId" = {} AND "emoji" = {}) AS "used"
+            FROM "Reaction"
+            WHERE "messageId" = {}"#,
+            PrismaValue::String(message_data.id.to_owned()),
+            PrismaValue::String(emoji.to_owned()),
+            PrismaValue::String(message_data.id.to_owned())
+        ))
+        .await?;
+
+    let is_new_emoji = usage.first().map_or(true, |usage| usage.used == 0);
+    let emoji_count = usage.first().map_or(0, |usage| usage.count);
+
+    if is_new_emoji && emoji_count >= MAX_EMOJI_PER_MESSAGE {
+        return Err(ApiError::BadRequest(format!(
+            "Messages can have at most {} different reactions.",
+            MAX_EMOJI_PER_MESSAGE
+        )));
+    }
+
+    let reaction_data = prisma
+        .reaction()
+        .create(
+            prisma::reaction::emoji::set(emoji),
+            prisma::reaction::message::link(prisma::message::UniqueWhereParam::IdEquals(
+                message_data.id,
+            )),
+            prisma::reaction::user::link(prisma::user::UniqueWhereParam::IdEquals(user_data.id)),
+            vec![],
+        )
+        .exec()
+        .await?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::ReactionAdd(reaction_event(&reaction_data, &channel_data.id)),
+    });
+
+    Ok((StatusCode::OK, Json(json!(reaction_data))))
+}
+
+pub async fn remove_reaction(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id, emoji)): Path<(String, String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let (channel_data, message_data) =
+        find_reaction_target(prisma, &user_data, channel_id, message_id).await?;
+
+    require_unarchived(&channel_data)?;
+
+    let reaction_data = prisma
+        .reaction()
+        .find_unique(
+            prisma::reaction::UniqueWhereParam::MessageIdUserIdEmojiEquals(
+                message_data.id,
+                user_data.id,
+                emoji,
+            ),
+        )
+        .delete()
+        .exec()
+        .await?
+        .or_not_found(Resource::Reaction)?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::ReactionRemove(reaction_event(
+            &reaction_data,
+            &channel_data.id,
+        )),
+    });
+
+    Ok((StatusCode::OK, Json(json!(reaction_data))))
+}
+
+/// The first users to react to a message with an emoji.
+pub async fn get_reactors(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id, emoji)): Path<(String, String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let (_, message_data) =
+        find_reaction_target(prisma, &user_data, channel_id, message_id).await?;
+
+    let reactions_data = prisma
+        .reaction()
+        .find_many(vec![
+            prisma::reaction::message_id::equals(message_data.id),
+            prisma::reaction::emoji::equals(emoji),
+        ])
+        .with(prisma::reaction::WithParam::User)
+        .order_by(prisma::reaction::created_at::order(Direction::Asc))
+        .take(MAX_REACTORS)
+        .exec()
+        .await?;
+
+    let reactors: Vec<serde_json::Value> = reactions_data
+        .iter()
+        .map(|reaction_data| {
+            let reactor = reaction_data.user().unwrap();
+
+            json!({
+                "id": reactor.id,
+                "username": reactor.username,
+            })
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!(reactors))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_emoji() {
+        for emoji in [
+            "👍",
+            // with a presentation selector
+            "❤️",
+            // skin tone
+            "👍🏽",
+            // zero width joiner sequences
+            "👨‍👩‍👧",
+            "🏳️‍🌈",
+            "🧑🏽‍💻",
+            // flags
+            "🇯🇵",
+            "🏴󠁧󠁢󠁥󠁮󠁧󠁿",
+            // keycaps
+            "1️⃣",
+            "#⃣",
+        ] {
+            assert!(is_emoji(emoji), "{:?} should be accepted", emoji);
+        }
+    }
+
+    #[test]
+    fn rejects_other_text() {
+        for text in [
+            "",
+            "a",
+            "<script>",
+            "1",
+            "a⃣",
+            "👍a",
+            "👍👍",
+            "\u{200D}",
+            "👍\u{200D}",
+            "🇯",
+            "🇯🇵🇫🇷",
+            // subdivision tags only belong on the black flag
+            "🏳󠁧󠁢󠁥󠁮󠁧󠁿",
+        ] {
+            assert!(!is_emoji(text), "{:?} should be rejected", text);
+        }
+    }
+
+    #[test]
+    fn elements_allow_one_selector_and_skin_tone() {
+        assert!(is_emoji_element("👋\u{FE0F}🏻"));
+        assert!(!is_emoji_element("👋🏻🏻"));
+        assert!(!is_emoji_element("🏻"));
+        assert!(!is_emoji_element("\u{FE0F}"));
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use prisma_client_rust::{raw, PrismaValue};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::{ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::{
+    channels::find_channel_message,
+    socket::{SocketMessageType, SocketPayload},
+};
+
+/// How much of a channel a user hasn't read yet.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnreadState {
+    channel_id: String,
+    last_read_message_id: Option<String>,
+    /// messages by others since the last read one
+    unread_count: i64,
+    mention_count: i64,
+}
+
+/// Unread states for every channel in `channel_ids`. Channels the user has
+/// never read count everything as unread.
+pub async fn unread_states(
+    prisma: &prisma::PrismaClient,
+    user_id: &str,
+    channel_ids: &[&str],
+) -> ApiResult<Vec<UnreadState>> {
+    let user_id = PrismaValue::String(user_id.to_owned());
+
+    Ok(prisma
+        ._query_raw(raw!(
+            r#"SELECT "channel"."value" AS "channelId",
+                "ReadState"."lastReadMessageId" AS "lastReadMessageId",
+                (SELECT COUNT(*) FROM "Message"
+                    WHERE "Message"."channelId" = "channel"."value"
+                        AND "Message"."authorId" != {}
+                        AND ("ReadState"."lastReadAt" IS NULL
+                            OR "Message"."createdAt" > "ReadState"."lastReadAt")
+                ) AS "unreadCount",
+                COALESCE("ReadState"."mentionCount", 0) AS "mentionCount"
+            FROM json_each({}) AS "channel"
+            LEFT JOIN "ReadState" ON "ReadState"."channelId" = "channel"."value"
+                AND "ReadState"."userId" = {}"#,
+            user_id.clone(),
+            PrismaValue::String(json!(channel_ids).to_string()),
+            user_id
+        ))
+        .await?)
+}
+
+/// Marks a channel as read up to and including a message, in all of the
+/// user's sessions. Returns the read state as it is afterwards.
+pub async fn ack_message(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id.to_owned()))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let message_data = find_channel_message(prisma, &channel_id, message_id).await?;
+
+    // only ever moves forward, acking an older message keeps the newer read
+    // position. Mentions in messages after the acked one still count.
+    let user_id = PrismaValue::String(user_data.id.to_owned());
+    let last_read_at = PrismaValue::DateTime(message_data.created_at);
+
+    let advanced = prisma
+        ._execute_raw(raw!(
+            r#"INSERT INTO "ReadState"
+                ("userId", "channelId", "lastReadMessageId", "lastReadAt", "mentionCount")
+            VALUES ({}, {}, {}, {}, (SELECT COUNT(*) FROM "Message"
+                WHERE "Message"."channelId" = {}
+                    AND "Message"."createdAt" > {}
+                    AND "Message"."authorId" != {}
+                    AND ("Message"."mentionEveryone"
+                        OR EXISTS (SELECT 1 FROM "MessageMention"
+                            WHERE "MessageMention"."messageId" = "Message"."id"
+                                AND "MessageMention"."userId" = {})
+                        OR EXISTS (SELECT 1 FROM "MessageRoleMention"
+                            JOIN "MemberRole" ON "MemberRole"."roleId" = "MessageRoleMention"."roleId"
+                            WHERE "MessageRoleMention"."messageId" = "Message"."id"
+                                AND "MemberRole"."userId" = {}))))
+            ON CONFLICT ("userId", "channelId") DO UPDATE SET
+                "lastReadMessageId" = excluded."lastReadMessageId",
+                "lastReadAt" = excluded."lastReadAt",
+                "mentionCount" = excluded."mentionCount"
+            WHERE "ReadState"."lastReadAt" IS NULL
+                OR excluded."lastReadAt" > "ReadState"."lastReadAt""#,
+            user_id.clone(),
+            PrismaValue::String(channel_id.to_owned()),
+            PrismaValue::String(message_data.id.to_owned()),
+            last_read_at.clone(),
+            PrismaValue::String(channel_id.to_owned()),
+            last_read_at,
+            user_id.clone(),
+            user_id.clone(),
+            user_id
+        ))
+        .await?
+        > 0;
+
+    let read_state_data = prisma
+        .read_state()
+        .find_unique(prisma::read_state::UniqueWhereParam::UserIdChannelIdEquals(
+            user_data.id.to_owned(),
+            channel_id.to_owned(),
+        ))
+        .exec()
+        .await?
+        .or_not_found(Resource::ReadState)?;
+
+    let read_state = protocol::ReadState {
+        channel_id,
+        last_read_message_id: read_state_data.last_read_message_id,
+        mention_count: read_state_data.mention_count as u32,
+    };
+
+    if advanced {
+        state.gateway.publish(SocketPayload {
+            message: SocketMessageType::ReadStateUpdate(read_state.clone(), user_data.id),
+        });
+    }
+
+    Ok((StatusCode::OK, Json(json!(read_state))))
+}
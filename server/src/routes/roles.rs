@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::socket::{SocketMessageType, SocketPayload};
+
+#[derive(Deserialize)]
+pub struct RoleCreatePayload {
+    name: String,
+    #[serde(default)]
+    permissions: i32,
+    #[serde(default)]
+    position: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RoleUpdatePayload {
+    name: Option<String>,
+    permissions: Option<i32>,
+    position: Option<i32>,
+}
+
+/// Members can only hand out permissions they hold themselves.
+fn check_grantable(permissions: Permissions, requested: i32) -> ApiResult<Permissions> {
+    let requested = Permissions::from_bits(requested)
+        .ok_or_else(|| ApiError::BadRequest("Unknown permission bits.".to_owned()))?;
+
+    permissions.require(requested)?;
+
+    Ok(requested)
+}
+
+async fn find_guild_role(
+    prisma: &prisma::PrismaClient,
+    guild_id: &str,
+    role_id: String,
+) -> ApiResult<prisma::role::Data> {
+    prisma
+        .role()
+        .find_unique(prisma::role::id::equals(role_id))
+        .exec()
+        .await?
+        .filter(|role_data| role_data.guild_id == guild_id)
+        .or_not_found(Resource::Role)
+}
+
+pub async fn get_roles(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    permissions::resolve(prisma, &guild_id, &user_data.id).await?;
+
+    let roles_data = prisma
+        .role()
+        .find_many(vec![prisma::role::guild_id::equals(guild_id)])
+        .exec()
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!(roles_data))))
+}
+
+pub async fn create_role(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+    Json(payload): Json<RoleCreatePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let member =
+        permissions::require_member(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD)
+            .await?;
+
+    let role_permissions = check_grantable(member.permissions, payload.permissions)?;
+
+    // position 0 is reserved for @everyone
+    let position = payload.position.max(1);
+    member.require_above(position)?;
+
+    let role_data = prisma
+        .role()
+        .create(
+            prisma::role::name::set(payload.name),
+            prisma::role::guild::link(prisma::guild::UniqueWhereParam::IdEquals(
+                guild_id.to_owned(),
+            )),
+            vec![
+                prisma::role::permissions::set(role_permissions.bits()),
+                prisma::role::position::set(position),
+            ],
+        )
+        .exec()
+        .await?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id),
+    });
+
+    Ok((StatusCode::CREATED, Json(json!(role_data))))
+}
+
+pub async fn update_role(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, role_id)): Path<(String, String)>,
+    Json(payload): Json<RoleUpdatePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let member =
+        permissions::require_member(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD)
+            .await?;
+
+    let role_data = find_guild_role(prisma, &guild_id, role_id).await?;
+
+    member.require_above(role_data.position)?;
+
+    let is_everyone = role_data.id == permissions::everyone_role_id(&guild_id);
+
+    let mut updates = vec![];
+
+    if let Some(name) = payload.name {
+        if is_everyone {
+            return Err(ApiError::BadRequest(
+                "The @everyone role cannot be renamed.".to_owned(),
+            ));
+        }
+        updates.push(prisma::role::name::set(name));
+    }
+
+    if let Some(requested) = payload.permissions {
+        let role_permissions = check_grantable(member.permissions, requested)?;
+        updates.push(prisma::role::permissions::set(role_permissions.bits()));
+    }
+
+    if let Some(position) = payload.position {
+        if is_everyone {
+            return Err(ApiError::BadRequest(
+                "The @everyone role cannot be moved.".to_owned(),
+            ));
+        }
+        let position = position.max(1);
+        member.require_above(position)?;
+        updates.push(prisma::role::position::set(position));
+    }
+
+    let role_data = prisma
+        .role()
+        .find_unique(prisma::role::id::equals(role_data.id))
+        .update(updates)
+        .exec()
+        .await?
+        .or_not_found(Resource::Role)?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(role_data))))
+}
+
+pub async fn delete_role(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, role_id)): Path<(String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let member =
+        permissions::require_member(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD)
+            .await?;
+
+    let role_data = find_guild_role(prisma, &guild_id, role_id).await?;
+
+    if role_data.id == permissions::everyone_role_id(&guild_id) {
+        return Err(ApiError::BadRequest(
+            "The @everyone role cannot be deleted.".to_owned(),
+        ));
+    }
+
+    member.require_above(role_data.position)?;
+
+    prisma
+        .role()
+        .find_unique(prisma::role::id::equals(role_data.id.to_owned()))
+        .delete()
+        .exec()
+        .await?;
+
+    // overwrites only reference roles by id, so they have to be cleaned up by hand
+    prisma
+        .channel_overwrite()
+        .find_many(vec![prisma::channel_overwrite::target_id::equals(
+            role_data.id.to_owned(),
+        )])
+        .delete()
+        .exec()
+        .await?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(role_data))))
+}
+
+pub async fn add_member_role(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, member_id, role_id)): Path<(String, String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let member =
+        permissions::require_member(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD)
+            .await?;
+
+    let role_data = find_guild_role(prisma, &guild_id, role_id).await?;
+
+    check_grantable(member.permissions, role_data.permissions)?;
+    member.require_above(role_data.position)?;
+    permissions::require_above_member(prisma, &guild_id, &member, &member_id).await?;
+
+    if role_data.id == permissions::everyone_role_id(&guild_id) {
+        return Err(ApiError::BadRequest(
+            "Every member already has the @everyone role.".to_owned(),
+        ));
+    }
+
+    prisma
+        .guild_membership()
+        .find_unique(
+            prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                member_id.to_owned(),
+                guild_id.to_owned(),
+            ),
+        )
+        .exec()
+        .await?
+        .or_not_found(Resource::Member)?;
+
+    let member_role_data = prisma
+        .member_role()
+        .create(
+            prisma::member_role::role::link(prisma::role::UniqueWhereParam::IdEquals(role_data.id)),
+            prisma::member_role::membership::link(
+                prisma::guild_membership::UniqueWhereParam::UserIdGuildIdEquals(
+                    member_id.to_owned(),
+                    guild_id,
+                ),
+            ),
+            vec![],
+        )
+        .exec()
+        .await?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::UserGuildDataUpdate(member_id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(member_role_data))))
+}
+
+pub async fn remove_member_role(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path((guild_id, member_id, role_id)): Path<(String, String, String)>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let member =
+        permissions::require_member(prisma, &guild_id, &user_data.id, Permissions::MANAGE_GUILD)
+            .await?;
+
+    let role_data = find_guild_role(prisma, &guild_id, role_id).await?;
+
+    check_grantable(member.permissions, role_data.permissions)?;
+    member.require_above(role_data.position)?;
+    permissions::require_above_member(prisma, &guild_id, &member, &member_id).await?;
+
+    let member_role_data = prisma
+        .member_role()
+        .find_unique(prisma::member_role::UniqueWhereParam::RoleIdUserIdEquals(
+            role_data.id,
+            member_id.to_owned(),
+        ))
+        .delete()
+        .exec()
+        .await?
+        .or_not_found(Resource::Role)?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::UserGuildDataUpdate(member_id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(member_role_data))))
+}
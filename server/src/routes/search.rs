@@ -0,0 +1,383 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, TimeZone, Utc};
+use prisma_client_rust::{raw, PrismaValue};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions, prisma, State,
+};
+
+use super::channels::{message_json, message_relations};
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+const DEFAULT_SEARCH_LIMIT: i64 = 25;
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// A search string split into its filters and the free text left over,
+/// e.g. `author:alice in:#general before:2022-06-01 release notes`.
+#[derive(Default)]
+struct ParsedQuery {
+    terms: Vec<String>,
+    author: Option<String>,
+    channel: Option<String>,
+    before: Option<DateTime<FixedOffset>>,
+    after: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Deserialize)]
+struct SearchHit {
+    id: String,
+    snippet: String,
+}
+
+fn parse_query(query: &str) -> ApiResult<ParsedQuery> {
+    let mut parsed = ParsedQuery::default();
+
+    for token in query.split_whitespace() {
+        match token.split_once(':') {
+            Some(("author", username)) if !username.is_empty() => {
+                parsed.author = Some(username.to_owned());
+            }
+            Some(("in", channel)) if !channel.is_empty() => {
+                parsed.channel = Some(channel.trim_start_matches('#').to_owned());
+            }
+            Some(("before", date)) => parsed.before = Some(parse_date(date, false)?),
+            Some(("after", date)) => parsed.after = Some(parse_date(date, true)?),
+            _ => parsed.terms.push(token.to_owned()),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Accepts RFC 3339 timestamps or plain `YYYY-MM-DD` dates. A plain date covers
+/// the whole day, so `after:` a date starts at the following midnight (UTC).
+fn parse_date(value: &str, end_of_day: bool) -> ApiResult<DateTime<FixedOffset>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp);
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| ApiError::BadRequest(format!("Invalid date: {}", value)))?;
+
+    let mut start = Utc.from_utc_datetime(&date.and_hms(0, 0, 0));
+    if end_of_day {
+        start = start + Duration::days(1);
+    }
+
+    Ok(start.into())
+}
+
+/// Quotes every term so user input is matched literally instead of being
+/// interpreted as FTS5 query syntax. Terms are implicitly ANDed together.
+fn fts_match_expression(terms: &[String]) -> String {
+    terms
+        .iter()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn optional(value: Option<PrismaValue>) -> PrismaValue {
+    value.unwrap_or(PrismaValue::Null)
+}
+
+pub async fn search_guild_messages(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(guild_id): Path<String>,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let parsed = parse_query(&query.q)?;
+
+    if parsed.terms.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Search query has no terms to match.".to_owned(),
+        ));
+    }
+
+    let channels_data = prisma
+        .channel()
+        .find_many(vec![prisma::channel::guild_id::equals(Some(
+            guild_id.to_owned(),
+        ))])
+        .exec()
+        .await?;
+
+    // fails for non-members, and leaves out channels the user can't see
+    let mut channels_data =
+        permissions::visible_channels(prisma, &guild_id, channels_data, &user_data.id).await?;
+
+    if let Some(name) = &parsed.channel {
+        channels_data.retain(|channel| &channel.name == name);
+
+        if channels_data.is_empty() {
+            return Err(ApiError::NotFound(Resource::Channel));
+        }
+    }
+
+    let author_id = match &parsed.author {
+        Some(username) => Some(
+            prisma
+                .user()
+                .find_unique(prisma::user::username::equals(username.to_owned()))
+                .exec()
+                .await?
+                .or_not_found(Resource::User)?
+                .id,
+        ),
+        None => None,
+    };
+
+    let channel_ids: Vec<&str> = channels_data
+        .iter()
+        .map(|channel| channel.id.as_str())
+        .collect();
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let author_id = optional(author_id.map(PrismaValue::String));
+    let before = optional(parsed.before.map(PrismaValue::DateTime));
+    let after = optional(parsed.after.map(PrismaValue::DateTime));
+
+    // bm25() ranks better matches lower; matched terms are wrapped in ** for the client
+    let hits: Vec<SearchHit> = prisma
+        ._query_raw(raw!(
+            r#"SELECT "Message"."id" AS "id",
+                snippet("MessageSearch", 0, '**', '**', '…', 16) AS "snippet"
+            FROM "MessageSearch"
+            JOIN "Message" ON "Message"."id" = "MessageSearch"."messageId"
+            WHERE "MessageSearch" MATCH {}
+                AND "Message"."channelId" IN (SELECT "value" FROM json_each({}))
+                AND ({} IS NULL OR "Message"."authorId" = {})
+                AND ({} IS NULL OR "Message"."createdAt" < {})
+                AND ({} IS NULL OR "Message"."createdAt" >= {})
+            ORDER BY bm25("MessageSearch"), "Message"."createdAt" DESC
+            LIMIT {} OFFSET {}"#,
+            PrismaValue::String(fts_match_expression(&parsed.terms)),
+            PrismaValue::String(json!(channel_ids).to_string()),
+            author_id.clone(),
+            author_id,
+            before.clone(),
+            before,
+            after.clone(),
+            after,
+            PrismaValue::Int(limit),
+            PrismaValue::Int(offset)
+        ))
+        .await?;
+
+    let mut messages_data: HashMap<String, prisma::message::Data> = prisma
+        .message()
+        .find_many(vec![prisma::message::id::in_vec(
+            hits.iter().map(|hit| hit.id.to_owned()).collect(),
+        )])
+        .with(prisma::message::WithParam::Author)
+        .with(prisma::message::WithParam::Mentions(vec![]))
+        .with(prisma::message::WithParam::RoleMentions(vec![]))
+        .with(prisma::message::WithParam::Attachments(vec![]))
+        .exec()
+        .await?
+        .into_iter()
+        .map(|message_data| (message_data.id.to_owned(), message_data))
+        .collect();
+
+    let relations = message_relations(
+        prisma,
+        &messages_data.values().cloned().collect::<Vec<_>>(),
+        &user_data.id,
+    )
+    .await?;
+
+    let results: Vec<serde_json::Value> = hits
+        .into_iter()
+        .filter_map(|hit| {
+            // skip anything deleted between the two queries
+            let message_data = messages_data.remove(&hit.id)?;
+
+            let mut message = message_json(&message_data, &relations);
+            message["channel_id"] = json!(message_data.channel_id);
+            message["snippet"] = json!(hit.snippet);
+            Some(message)
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!({ "messages": results }))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_filters_from_terms() {
+        let parsed = parse_query("author:alice in:#general release notes").unwrap();
+
+        assert_eq!(parsed.terms, ["release", "notes"]);
+        assert_eq!(parsed.author.as_deref(), Some("alice"));
+        assert_eq!(parsed.channel.as_deref(), Some("general"));
+        assert!(parsed.before.is_none() && parsed.after.is_none());
+    }
+
+    #[test]
+    fn keeps_empty_filters_as_terms() {
+        let parsed = parse_query("author: in: hello").unwrap();
+
+        assert_eq!(parsed.terms, ["author:", "in:", "hello"]);
+        assert!(parsed.author.is_none() && parsed.channel.is_none());
+    }
+
+    #[test]
+    fn plain_dates_cover_the_whole_day() {
+        let parsed = parse_query("before:2022-06-01 after:2022-05-01").unwrap();
+
+        assert_eq!(
+            parsed.before.unwrap().to_rfc3339(),
+            "2022-06-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            parsed.after.unwrap().to_rfc3339(),
+            "2022-05-02T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn accepts_timestamps() {
+        let parsed = parse_query("after:2022-05-01T12:30:00+02:00").unwrap();
+
+        assert_eq!(
+            parsed.after.unwrap().to_rfc3339(),
+            "2022-05-01T12:30:00+02:00"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert!(parse_query("before:yesterday").is_err());
+        assert!(parse_query("after:").is_err());
+    }
+
+    /// A bare "Message" table with the search index and triggers the
+    /// migrations set up on top of it.
+    fn search_db(messages: &[(&str, &str)]) -> rusqlite::Connection {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+
+        db.execute_batch(
+            r#"CREATE TABLE "Message" ("id" TEXT NOT NULL PRIMARY KEY, "content" TEXT NOT NULL);"#,
+        )
+        .unwrap();
+
+        for (id, content) in messages {
+            insert_message(&db, id, content);
+        }
+
+        db.execute_batch(include_str!(
+            "../../../prisma/migrations/20220705120000_message_search/migration.sql"
+        ))
+        .unwrap();
+        db.execute_batch(include_str!(
+            "../../../prisma/migrations/20220908120000_message_search_id/migration.sql"
+        ))
+        .unwrap();
+
+        db
+    }
+
+    fn insert_message(db: &rusqlite::Connection, id: &str, content: &str) {
+        db.execute(
+            r#"INSERT INTO "Message" ("id", "content") VALUES (?, ?)"#,
+            [id, content],
+        )
+        .unwrap();
+    }
+
+    fn search(db: &rusqlite::Connection, query: &str) -> Vec<String> {
+        let terms = parse_query(query).unwrap().terms;
+
+        db.prepare(
+            r#"SELECT "Message"."id" FROM "MessageSearch"
+            JOIN "Message" ON "Message"."id" = "MessageSearch"."messageId"
+            WHERE "MessageSearch" MATCH ?
+            ORDER BY "Message"."id""#,
+        )
+        .unwrap()
+        .query_map([fts_match_expression(&terms)], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn indexes_existing_messages() {
+        let db = search_db(&[("a", "release notes"), ("b", "unrelated")]);
+
+        assert_eq!(search(&db, "release"), ["a"]);
+    }
+
+    #[test]
+    fn triggers_keep_the_index_in_sync() {
+        let db = search_db(&[]);
+
+        insert_message(&db, "a", "release notes");
+        insert_message(&db, "b", "Release party");
+        assert_eq!(search(&db, "release"), ["a", "b"]);
+
+        db.execute(
+            r#"UPDATE "Message" SET "content" = 'hotfix notes' WHERE "id" = 'a'"#,
+            [],
+        )
+        .unwrap();
+        assert_eq!(search(&db, "release"), ["b"]);
+        assert_eq!(search(&db, "hotfix"), ["a"]);
+
+        db.execute(r#"DELETE FROM "Message" WHERE "id" = 'b'"#, [])
+            .unwrap();
+        assert!(search(&db, "release").is_empty());
+        assert_eq!(search(&db, "notes"), ["a"]);
+    }
+
+    #[test]
+    fn survives_vacuum() {
+        let db = search_db(&[]);
+
+        insert_message(&db, "a", "first");
+        insert_message(&db, "b", "second");
+        insert_message(&db, "c", "third");
+        db.execute(r#"DELETE FROM "Message" WHERE "id" = 'a'"#, [])
+            .unwrap();
+
+        // renumbers the rowids of "Message", which has no INTEGER PRIMARY KEY
+        db.execute_batch("VACUUM").unwrap();
+
+        assert_eq!(search(&db, "second"), ["b"]);
+        assert_eq!(search(&db, "third"), ["c"]);
+    }
+
+    #[test]
+    fn quotes_terms_for_fts() {
+        let terms = vec!["say".to_owned(), "\"hi\"".to_owned(), "OR".to_owned()];
+
+        assert_eq!(fts_match_expression(&terms), r#""say" """hi""" "OR""#);
+    }
+}
@@ -1,30 +1,62 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
-    extract::{ws::WebSocket, WebSocketUpgrade},
+    extract::{
+        ws::{Message, WebSocket},
+        Query, WebSocketUpgrade,
+    },
+    http::StatusCode,
     response::IntoResponse,
-    Extension,
+    Extension, Json,
+};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use log::debug;
+use protocol::{Frame, GatewayEvent, PROTOCOL_VERSION};
+use serde::Deserialize;
+use tokio::{
+    sync::mpsc,
+    time::{interval, timeout, Instant},
+};
+
+use crate::{
+    error::{ApiError, ApiResult},
+    gateway::{Attachment, GatewaySession, Subscriptions},
+    permissions, prisma, Claims, State,
 };
-use serde::Serialize;
-use serde_json::json;
-
-use crate::{prisma, State, User};
-
-#[derive(Debug, Clone, Serialize)]
-pub struct SocketMessagePayload {
-    pub(crate) msg_type: String,
-    pub(crate) content: String,
-    pub(crate) author: User,
-    pub(crate) channel_id: String,
-    pub(crate) created_at: String,
-    pub(crate) id: String,
-}
+
+use super::users::user_presence;
 
 #[derive(Debug, Clone)]
 pub enum SocketMessageType {
-    NewMessage(SocketMessagePayload, String),
+    NewMessage(protocol::Message, String),
+    MessageUpdate(protocol::Message, String),
+    MessageDelete {
+        channel_id: String,
+        id: String,
+    },
     GuildDataUpdate(String),
     UserGuildDataUpdate(String),
+    /// A direct message channel was opened, sent to its recipients
+    ChannelCreate(protocol::Channel, Vec<String>),
+    /// Sent to everyone in the listed guilds, and the user's own sessions
+    PresenceUpdate(protocol::Presence, Vec<String>),
+    TypingStart {
+        channel_id: String,
+        user: protocol::User,
+    },
+    /// Sent to every session of the user who read the channel
+    ReadStateUpdate(protocol::ReadState, String),
+    /// Sent to the mentioned users, whether or not they are watching the channel
+    MessageMention(protocol::Message, Vec<String>),
+    ReactionAdd(protocol::Reaction),
+    ReactionRemove(protocol::Reaction),
+    ChannelPinsUpdate {
+        channel_id: String,
+        last_pin_timestamp: Option<String>,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -32,123 +64,316 @@ pub struct SocketPayload {
     pub(crate) message: SocketMessageType,
 }
 
+#[derive(Deserialize)]
+pub struct GatewayQuery {
+    /// protocol version the client speaks, see `protocol::PROTOCOL_VERSION`
+    v: Option<u32>,
+}
+
 pub async fn upgrade(
     ws: WebSocketUpgrade,
+    Query(query): Query<GatewayQuery>,
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state, user_data))
+    Extension(claims): Extension<Claims>,
+) -> ApiResult<impl IntoResponse> {
+    if query.v != Some(PROTOCOL_VERSION) {
+        return Err(ApiError::BadRequest(format!(
+            "Unsupported gateway protocol version, this server speaks v{}.",
+            PROTOCOL_VERSION
+        )));
+    }
+
+    Ok(ws.on_upgrade(|socket| websocket(socket, state, user_data, claims.sid)))
 }
 
-async fn websocket(mut socket: WebSocket, state: Arc<State>, mut user_data: prisma::user::Data) {
-    let mut rx = state.tx.subscribe();
+/// Counts of events and frames the gateway had to drop, for spotting
+/// overloaded servers and slow clients.
+pub async fn gateway_metrics(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    (StatusCode::OK, Json(state.gateway.metrics()))
+}
 
-    let user_id = user_data.clone().id;
+async fn load_subscriptions(state: &State, user_id: &str) -> ApiResult<Subscriptions> {
+    let prisma = &state.prisma;
 
-    let mut user_guild_data = state
-        .prisma
+    let guilds_data = prisma
         .guild()
-        .find_many(vec![prisma::guild::id::in_vec(
-            user_data
-                .memberships()
-                .unwrap()
-                .iter()
-                .map(|m| m.guild_id.to_owned())
-                .collect(),
-        )])
+        .find_many(vec![prisma::guild::members::some(vec![
+            prisma::guild_membership::user_id::equals(user_id.to_owned()),
+        ])])
         .with(prisma::guild::WithParam::Channels(vec![]))
         .exec()
-        .await
-        .unwrap();
+        .await?;
+
+    let mut subscriptions = Subscriptions::default();
+
+    for guild_data in guilds_data {
+        let channels = permissions::visible_channels(
+            prisma,
+            &guild_data.id,
+            guild_data.channels().unwrap().clone(),
+            user_id,
+        )
+        .await?;
+
+        subscriptions
+            .channel_ids
+            .extend(channels.into_iter().map(|channel| channel.id));
+        subscriptions.guild_ids.insert(guild_data.id);
+    }
+
+    let dm_channels_data = prisma
+        .channel()
+        .find_many(vec![prisma::channel::recipients::some(vec![
+            prisma::channel_recipient::user_id::equals(user_id.to_owned()),
+        ])])
+        .exec()
+        .await?;
+
+    subscriptions
+        .channel_ids
+        .extend(dm_channels_data.into_iter().map(|channel| channel.id));
+
+    Ok(subscriptions)
+}
+
+type SocketSink = SplitSink<WebSocket, Message>;
+
+/// Sends a frame, returning false once the connection is gone.
+async fn send_frame(sink: &mut SocketSink, frame: &Frame) -> bool {
+    // frames only hold strings and plain structs, so serialization can't fail
+    let text = serde_json::to_string(frame).unwrap();
+
+    sink.send(Message::Text(text)).await.is_ok()
+}
+
+/// Waits for the next frame from the client, skipping pings and anything that
+/// isn't a valid frame. `None` once the connection is closed.
+async fn next_frame(stream: &mut SplitStream<WebSocket>) -> Option<Frame> {
+    while let Some(Ok(message)) = stream.next().await {
+        match message {
+            Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(frame) => return Some(frame),
+                Err(err) => debug!("Ignoring malformed gateway frame: {}", err),
+            },
+            Message::Close(_) => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Turns the events published to a session into gateway events, whether or not
+/// a connection is attached, until the session expires or is removed. Keeps
+/// its subscriptions current as the user's guilds and permissions change.
+async fn pump(
+    state: Arc<State>,
+    session: Arc<GatewaySession>,
+    mut inbox: mpsc::Receiver<SocketPayload>,
+) {
+    let resume_timeout = Duration::from_secs(state.config.gateway.resume_timeout_secs);
+
+    let mut expiry_check = interval(Duration::from_secs(5));
 
     loop {
-        let msg = rx.recv().await;
-
-        match msg.unwrap().message {
-            SocketMessageType::NewMessage(message_payload, channel_id) => {
-                if user_guild_data
-                    .iter()
-                    .map(|g| g.channels().unwrap())
-                    .any(|channels| channels.iter().any(|channel| channel.id == channel_id))
-                {
-                    let socket_msg = json!(message_payload);
-                    socket
-                        .send(axum::extract::ws::Message::Text(socket_msg.to_string()))
-                        .await
-                        .ok();
+        let payload = tokio::select! {
+            payload = inbox.recv() => match payload {
+                Some(payload) => payload,
+                // the session was removed, e.g. for lagging behind
+                None => return,
+            },
+            _ = expiry_check.tick() => {
+                if session.is_expired(resume_timeout) {
+                    state.gateway.remove(&session.id);
+                    return;
                 }
+                continue;
             }
+        };
 
-            SocketMessageType::GuildDataUpdate(updated_guild_id) => {
-                if user_guild_data.iter().any(|g| g.id == updated_guild_id) {
-                    user_guild_data = state
-                        .prisma
-                        .guild()
-                        .find_many(vec![prisma::guild::id::in_vec(
-                            user_data
-                                .memberships()
-                                .unwrap()
-                                .iter()
-                                .map(|m| m.guild_id.to_owned())
-                                .collect(),
-                        )])
-                        .with(prisma::guild::WithParam::Channels(vec![]))
-                        .exec()
-                        .await
-                        .unwrap();
-
-                    socket
-                        .send(axum::extract::ws::Message::Text(
-                            json!({
-                                "msg_type": "guild_data_update",
-                                "guild_id": updated_guild_id,
-                            })
-                            .to_string(),
-                        ))
-                        .await
-                        .ok();
+        // only events the session is subscribed to are published to it
+        let event = match payload.message {
+            SocketMessageType::NewMessage(message, _) => GatewayEvent::MessageCreate(message),
+            SocketMessageType::MessageUpdate(message, _) => GatewayEvent::MessageUpdate(message),
+            SocketMessageType::MessageDelete { channel_id, id } => {
+                GatewayEvent::MessageDelete { channel_id, id }
+            }
+            SocketMessageType::GuildDataUpdate(guild_id) => {
+                refresh_subscriptions(&state, &session).await;
+                GatewayEvent::GuildDataUpdate { guild_id }
+            }
+            SocketMessageType::UserGuildDataUpdate(_) => {
+                refresh_subscriptions(&state, &session).await;
+                GatewayEvent::UserGuildDataUpdate
+            }
+            SocketMessageType::ChannelCreate(channel, _) => {
+                refresh_subscriptions(&state, &session).await;
+                GatewayEvent::ChannelCreate(channel)
+            }
+            SocketMessageType::PresenceUpdate(presence, _) => {
+                GatewayEvent::PresenceUpdate(presence)
+            }
+            SocketMessageType::TypingStart { channel_id, user } => {
+                // people don't need to be told they are typing
+                if user.id == session.user_id {
+                    continue;
+                }
+
+                // stale by the time a client resumes, so never replayed
+                session.dispatch_ephemeral(GatewayEvent::TypingStart { channel_id, user });
+                continue;
+            }
+            SocketMessageType::ReadStateUpdate(read_state, _) => {
+                GatewayEvent::ReadStateUpdate(read_state)
+            }
+            SocketMessageType::MessageMention(message, _) => GatewayEvent::MessageMention(message),
+            SocketMessageType::ReactionAdd(reaction) => GatewayEvent::ReactionAdd(reaction),
+            SocketMessageType::ReactionRemove(reaction) => GatewayEvent::ReactionRemove(reaction),
+            SocketMessageType::ChannelPinsUpdate {
+                channel_id,
+                last_pin_timestamp,
+            } => GatewayEvent::ChannelPinsUpdate {
+                channel_id,
+                last_pin_timestamp,
+            },
+        };
+
+        session.dispatch(event);
+    }
+}
+
+async fn refresh_subscriptions(state: &State, session: &GatewaySession) {
+    let subscriptions = load_subscriptions(state, &session.user_id)
+        .await
+        .unwrap_or_default();
+
+    state.gateway.set_subscriptions(&session.id, subscriptions);
+}
+
+/// Starts a new session for the user, or resumes one of theirs.
+async fn start_session(
+    state: &Arc<State>,
+    user_data: &prisma::user::Data,
+    login_session_id: &str,
+    frame: Frame,
+) -> Option<(Arc<GatewaySession>, Attachment)> {
+    let user_id = &user_data.id;
+
+    match frame {
+        Frame::Identify => {
+            // subscribed before the session is announced, so nothing is missed in between
+            let subscriptions = load_subscriptions(state, user_id).await.ok()?;
+
+            let (session, inbox) = state.gateway.create(
+                user_id.to_owned(),
+                login_session_id.to_owned(),
+                subscriptions,
+                user_presence(user_data),
+            );
+            let attachment = session.attach(None)?;
+
+            tokio::spawn(pump(state.clone(), session.clone(), inbox));
+
+            Some((session, attachment))
+        }
+        Frame::Resume { session_id, seq } => {
+            let session = state
+                .gateway
+                .get(&session_id)
+                .filter(|session| &session.user_id == user_id)?;
+            let attachment = session.attach(Some(seq))?;
+
+            Some((session, attachment))
+        }
+        _ => None,
+    }
+}
+
+async fn websocket(
+    socket: WebSocket,
+    state: Arc<State>,
+    user_data: prisma::user::Data,
+    login_session_id: String,
+) {
+    let (mut sink, mut stream) = socket.split();
+
+    let heartbeat_interval = Duration::from_secs(state.config.gateway.heartbeat_interval_secs);
+    let heartbeat_timeout = heartbeat_interval * 2;
+
+    let hello = Frame::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        heartbeat_interval_ms: heartbeat_interval.as_millis() as u64,
+    };
+
+    if !send_frame(&mut sink, &hello).await {
+        return;
+    }
+
+    // the client has to Identify or Resume before anything else
+    let (session, mut attachment) = loop {
+        let frame = match timeout(heartbeat_timeout, next_frame(&mut stream)).await {
+            Ok(Some(frame)) => frame,
+            _ => return,
+        };
+
+        match start_session(&state, &user_data, &login_session_id, frame).await {
+            Some(started) => break started,
+            None => {
+                if !send_frame(&mut sink, &Frame::InvalidSession).await {
+                    return;
                 }
             }
+        }
+    };
+
+    let mut last_heartbeat = Instant::now();
+    let mut heartbeat_check = interval(heartbeat_interval);
 
-            SocketMessageType::UserGuildDataUpdate(updated_user_id) => {
-                if *user_id == updated_user_id {
-                    user_data = state
-                        .prisma
-                        .user()
-                        .find_unique(prisma::user::id::equals(user_id.to_owned()))
-                        .with(prisma::user::WithParam::Memberships(vec![]))
-                        .exec()
-                        .await
-                        .unwrap()
-                        .unwrap();
-
-                    user_guild_data = state
-                        .prisma
-                        .guild()
-                        .find_many(vec![prisma::guild::id::in_vec(
-                            user_data
-                                .memberships()
-                                .unwrap()
-                                .iter()
-                                .map(|m| m.guild_id.to_owned())
-                                .collect(),
-                        )])
-                        .with(prisma::guild::WithParam::Channels(vec![]))
-                        .exec()
-                        .await
-                        .unwrap();
-
-                    socket
-                        .send(axum::extract::ws::Message::Text(
-                            json!({
-                                "msg_type": "user_guild_data_update",
-                            })
-                            .to_string(),
-                        ))
-                        .await
-                        .ok();
+    loop {
+        tokio::select! {
+            frame = attachment.frames.recv() => match frame {
+                Some(frame) => {
+                    if !send_frame(&mut sink, &frame).await {
+                        break;
+                    }
+                }
+                // a newer connection resumed the session, or this one fell behind
+                None => break,
+            },
+            frame = next_frame(&mut stream) => match frame {
+                Some(Frame::Heartbeat { .. }) => {
+                    last_heartbeat = Instant::now();
+
+                    if !send_frame(&mut sink, &Frame::HeartbeatAck).await {
+                        break;
+                    }
+                }
+                Some(Frame::TypingStart { channel_id }) => {
+                    if state.gateway.allow_typing(&session, &channel_id) {
+                        state.gateway.publish(SocketPayload {
+                            message: SocketMessageType::TypingStart {
+                                channel_id,
+                                user: protocol::User {
+                                    id: user_data.id.to_owned(),
+                                    username: user_data.username.to_owned(),
+                                },
+                            },
+                        });
+                    }
+                }
+                Some(_) => {}
+                None => break,
+            },
+            _ = heartbeat_check.tick() => {
+                if last_heartbeat.elapsed() > heartbeat_timeout {
+                    debug!("Session {} missed its heartbeats, disconnecting", session.id);
+                    break;
                 }
             }
         }
     }
+
+    session.detach(attachment.generation);
+    sink.close().await.ok();
 }
@@ -0,0 +1,224 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions::{self, Permissions},
+    prisma, State,
+};
+
+use super::{
+    channels::{find_channel_message, CHANNEL_TEXT, CHANNEL_THREAD},
+    socket::{SocketMessageType, SocketPayload},
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadCreatePayload {
+    name: String,
+    /// the message the thread is started from
+    message_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ThreadQuery {
+    #[serde(default)]
+    archived: bool,
+}
+
+async fn find_channel(
+    prisma: &prisma::PrismaClient,
+    channel_id: String,
+) -> ApiResult<prisma::channel::Data> {
+    prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_id))
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)
+}
+
+/// Threads are started from messages in guild text channels, one per message.
+pub async fn create_thread(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(channel_id): Path<String>,
+    Json(payload): Json<ThreadCreatePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = find_channel(prisma, channel_id).await?;
+
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let guild_id = match (&channel_data.guild_id, channel_data.kind.as_str()) {
+        (Some(guild_id), CHANNEL_TEXT) => guild_id.to_owned(),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Threads can only be started in guild text channels.".to_owned(),
+            ))
+        }
+    };
+
+    if payload.name.trim().is_empty() {
+        return Err(ApiError::BadRequest(
+            "Thread name can't be empty.".to_owned(),
+        ));
+    }
+
+    let message_data = find_channel_message(prisma, &channel_data.id, payload.message_id).await?;
+
+    let existing = prisma
+        .channel()
+        .find_unique(prisma::channel::parent_message_id::equals(
+            message_data.id.to_owned(),
+        ))
+        .exec()
+        .await?;
+
+    if existing.is_some() {
+        return Err(ApiError::BadRequest(
+            "A thread was already started from this message.".to_owned(),
+        ));
+    }
+
+    let thread_data = prisma
+        .channel()
+        .create(
+            prisma::channel::name::set(payload.name),
+            vec![
+                prisma::channel::kind::set(CHANNEL_THREAD.to_owned()),
+                prisma::channel::owner_id::set(Some(user_data.id)),
+                prisma::channel::guild::link(prisma::guild::UniqueWhereParam::IdEquals(
+                    guild_id.to_owned(),
+                )),
+                prisma::channel::parent_channel::link(prisma::channel::UniqueWhereParam::IdEquals(
+                    channel_data.id,
+                )),
+                prisma::channel::parent_message::link(prisma::message::UniqueWhereParam::IdEquals(
+                    message_data.id,
+                )),
+            ],
+        )
+        .exec()
+        .await?;
+
+    // members refetch the guild, which also subscribes them to the thread
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::GuildDataUpdate(guild_id),
+    });
+
+    Ok((StatusCode::OK, Json(json!(thread_data))))
+}
+
+/// Active threads of a channel, or archived ones with `?archived=true`.
+pub async fn get_threads(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(channel_id): Path<String>,
+    Query(query): Query<ThreadQuery>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let channel_data = find_channel(prisma, channel_id).await?;
+
+    // threads can be seen by everyone who can see their parent
+    permissions::require_channel(
+        prisma,
+        &channel_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    let threads_data = prisma
+        .channel()
+        .find_many(vec![
+            prisma::channel::parent_channel_id::equals(Some(channel_data.id)),
+            prisma::channel::archived::equals(query.archived),
+        ])
+        .exec()
+        .await?;
+
+    Ok((StatusCode::OK, Json(json!(threads_data))))
+}
+
+async fn set_archived(
+    state: &State,
+    user_data: &prisma::user::Data,
+    thread_id: String,
+    archived: bool,
+) -> ApiResult<prisma::channel::Data> {
+    let prisma = &state.prisma;
+
+    let thread_data = find_channel(prisma, thread_id).await?;
+
+    if thread_data.kind != CHANNEL_THREAD {
+        return Err(ApiError::BadRequest(
+            "Only threads can be archived.".to_owned(),
+        ));
+    }
+
+    let user_permissions = permissions::require_channel(
+        prisma,
+        &thread_data,
+        &user_data.id,
+        Permissions::VIEW_CHANNEL,
+    )
+    .await?;
+
+    // whoever started the thread can archive it, moderators can archive any
+    if thread_data.owner_id.as_ref() != Some(&user_data.id) {
+        user_permissions.require(Permissions::MANAGE_CHANNELS)?;
+    }
+
+    let thread_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(thread_data.id))
+        .update(vec![prisma::channel::archived::set(archived)])
+        .exec()
+        .await?
+        .or_not_found(Resource::Channel)?;
+
+    if let Some(guild_id) = &thread_data.guild_id {
+        state.gateway.publish(SocketPayload {
+            message: SocketMessageType::GuildDataUpdate(guild_id.to_owned()),
+        });
+    }
+
+    Ok(thread_data)
+}
+
+pub async fn archive_thread(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(thread_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let thread_data = set_archived(&state, &user_data, thread_id, true).await?;
+
+    Ok((StatusCode::OK, Json(json!(thread_data))))
+}
+
+pub async fn unarchive_thread(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(thread_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let thread_data = set_archived(&state, &user_data, thread_id, false).await?;
+
+    Ok((StatusCode::OK, Json(json!(thread_data))))
+}
@@ -1,9 +1,70 @@
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 
-use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Extension, Json};
+use chrono::Utc;
+use prisma_client_rust::{raw, PrismaValue};
+use protocol::{Presence, Status};
+use serde::Deserialize;
 use serde_json::json;
 
-use crate::{prisma, State};
+use crate::{
+    error::{ApiError, ApiResult, OrNotFound, Resource},
+    permissions, prisma, session, Claims, State,
+};
+
+use super::{
+    channels::{CHANNEL_DM, CHANNEL_GROUP_DM},
+    read_states,
+    socket::{SocketMessageType, SocketPayload},
+};
+
+#[derive(Deserialize)]
+pub struct DmCreatePayload {
+    /// ids of the users to talk to, not including the current user
+    recipients: Vec<String>,
+    /// only used for group DMs
+    name: Option<String>,
+}
+
+/// Group DMs can have at most this many recipients, the creator included.
+const MAX_DM_RECIPIENTS: usize = 10;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresencePayload {
+    status: Option<Status>,
+    /// an empty string clears it
+    custom_status: Option<String>,
+}
+
+const MAX_CUSTOM_STATUS_LENGTH: usize = 128;
+
+fn status_name(status: Status) -> &'static str {
+    match status {
+        Status::Online => "online",
+        Status::Idle => "idle",
+        Status::Dnd => "dnd",
+        Status::Offline => "offline",
+    }
+}
+
+fn parse_status(name: &str) -> Status {
+    match name {
+        "idle" => Status::Idle,
+        "dnd" => Status::Dnd,
+        "offline" => Status::Offline,
+        _ => Status::Online,
+    }
+}
+
+/// The presence a user chose, which applies while they are connected.
+pub fn user_presence(user_data: &prisma::user::Data) -> Presence {
+    Presence {
+        user_id: user_data.id.to_owned(),
+        status: parse_status(&user_data.status),
+        custom_status: user_data.custom_status.to_owned(),
+    }
+}
 
 pub async fn me(Extension(user_data): Extension<prisma::user::Data>) -> impl IntoResponse {
     (
@@ -20,26 +81,324 @@ pub async fn me(Extension(user_data): Extension<prisma::user::Data>) -> impl Int
 pub async fn get_user_guilds(
     Extension(state): Extension<Arc<State>>,
     Extension(user_data): Extension<prisma::user::Data>,
-) -> impl IntoResponse {
+) -> ApiResult<impl IntoResponse> {
     let prisma = &state.prisma;
 
-    let guilds_query = prisma
+    let guilds_data = prisma
         .guild()
         .find_many(vec![prisma::guild::members::some(vec![
-            prisma::guild_membership::user_id::equals(user_data.id),
+            prisma::guild_membership::user_id::equals(user_data.id.to_owned()),
         ])])
         .with(prisma::guild::WithParam::Channels(vec![]))
         .exec()
+        .await?;
+
+    let mut guilds_user_data: Vec<serde_json::Value> = vec![];
+
+    for guild_data in guilds_data {
+        let member = permissions::member_roles(prisma, &guild_data.id, &user_data.id).await?;
+
+        let channels = permissions::visible_channels(
+            prisma,
+            &guild_data.id,
+            guild_data.channels().unwrap().clone(),
+            &user_data.id,
+        )
+        .await?;
+
+        let channel_ids: Vec<&str> = channels.iter().map(|channel| channel.id.as_str()).collect();
+        let unread = read_states::unread_states(prisma, &user_data.id, &channel_ids).await?;
+
+        let mut guild_json = json!(guild_data);
+        guild_json["permissions"] = json!(member.permissions.bits());
+        // lets clients tell which role mentions are meant for them
+        guild_json["roleIds"] = json!(member.role_ids);
+        guild_json["channels"] = json!(channels);
+        guild_json["readStates"] = json!(unread);
+        guilds_user_data.push(guild_json);
+    }
+
+    Ok((StatusCode::OK, Json(json!(guilds_user_data))))
+}
+
+pub async fn get_user_sessions(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Extension(claims): Extension<Claims>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let sessions_data: Vec<serde_json::Value> = prisma
+        .session()
+        .find_many(vec![
+            prisma::session::user_id::equals(user_data.id),
+            prisma::session::revoked_at::equals(None),
+            prisma::session::expires_at::gt(Utc::now().into()),
+        ])
+        .exec()
+        .await?
+        .iter()
+        .map(|session_data| {
+            json!({
+                "id": session_data.id,
+                "userAgent": session_data.user_agent,
+                "createdAt": session_data.created_at.to_rfc3339(),
+                "lastUsedAt": session_data.last_used_at.to_rfc3339(),
+                "current": session_data.id == claims.sid,
+            })
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!(sessions_data))))
+}
+
+pub async fn revoke_user_session(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Path(session_id): Path<String>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    prisma
+        .session()
+        .find_unique(prisma::session::id::equals(session_id.to_owned()))
+        .exec()
+        .await?
+        .filter(|session_data| session_data.user_id == user_data.id)
+        .ok_or(ApiError::NotFound(Resource::Session))?;
+
+    session::revoke(&state, &session_id).await?;
+
+    Ok((StatusCode::OK, Json(json!({}))))
+}
+
+/// The presence the user chose, even if they appear offline to others.
+pub async fn get_user_presence(
+    Extension(user_data): Extension<prisma::user::Data>,
+) -> impl IntoResponse {
+    (StatusCode::OK, Json(json!(user_presence(&user_data))))
+}
+
+pub async fn update_user_presence(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Json(payload): Json<PresencePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let mut updates = vec![];
+
+    if let Some(status) = payload.status {
+        updates.push(prisma::user::status::set(status_name(status).to_owned()));
+    }
+
+    if let Some(custom_status) = payload.custom_status {
+        let custom_status = custom_status.trim();
+
+        if custom_status.chars().count() > MAX_CUSTOM_STATUS_LENGTH {
+            return Err(ApiError::BadRequest(format!(
+                "Custom status can be at most {} characters.",
+                MAX_CUSTOM_STATUS_LENGTH
+            )));
+        }
+
+        updates.push(prisma::user::custom_status::set(
+            (!custom_status.is_empty()).then(|| custom_status.to_owned()),
+        ));
+    }
+
+    let user_data = prisma
+        .user()
+        .find_unique(prisma::user::id::equals(user_data.id))
+        .update(updates)
+        .exec()
+        .await?
+        .or_not_found(Resource::User)?;
+
+    let presence = user_presence(&user_data);
+    state.gateway.set_presence(presence.clone());
+
+    Ok((StatusCode::OK, Json(json!(presence))))
+}
+
+/// The shape direct message channels are dispatched in over the gateway.
+/// Expects the recipients and their users to be fetched.
+fn dm_channel(channel_data: &prisma::channel::Data) -> protocol::Channel {
+    protocol::Channel {
+        id: channel_data.id.to_owned(),
+        kind: channel_data.kind.to_owned(),
+        name: channel_data.name.to_owned(),
+        recipients: channel_data
+            .recipients()
+            .unwrap()
+            .iter()
+            .map(|recipient| {
+                let user = recipient.user().unwrap();
+
+                protocol::User {
+                    id: user.id.to_owned(),
+                    username: user.username.to_owned(),
+                }
+            })
+            .collect(),
+        // direct messages have no threads
+        parent_channel_id: None,
+        parent_message_id: None,
+        archived: false,
+    }
+}
+
+/// The shape direct message channels are returned in by the REST API.
+fn dm_channel_json(channel_data: &prisma::channel::Data) -> serde_json::Value {
+    let mut channel_json = json!(dm_channel(channel_data));
+    channel_json["createdAt"] = json!(channel_data.created_at.to_rfc3339());
+    channel_json
+}
+
+async fn find_dm_channels(
+    prisma: &prisma::PrismaClient,
+    user_id: &str,
+) -> ApiResult<Vec<prisma::channel::Data>> {
+    Ok(prisma
+        .channel()
+        .find_many(vec![prisma::channel::recipients::some(vec![
+            prisma::channel_recipient::user_id::equals(user_id.to_owned()),
+        ])])
+        .with(
+            prisma::channel::recipients::fetch(vec![])
+                .with(prisma::channel_recipient::user::fetch()),
+        )
+        .exec()
+        .await?)
+}
+
+pub async fn get_user_channels(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+) -> ApiResult<impl IntoResponse> {
+    let channels_data: Vec<serde_json::Value> = find_dm_channels(&state.prisma, &user_data.id)
+        .await?
+        .iter()
+        .map(dm_channel_json)
+        .collect();
+
+    Ok((StatusCode::OK, Json(json!(channels_data))))
+}
+
+/// Opens a DM (one other recipient) or group DM (several), reusing the existing
+/// channel if one with exactly the same recipients is already open.
+pub async fn create_user_channel(
+    Extension(state): Extension<Arc<State>>,
+    Extension(user_data): Extension<prisma::user::Data>,
+    Json(payload): Json<DmCreatePayload>,
+) -> ApiResult<impl IntoResponse> {
+    let prisma = &state.prisma;
+
+    let mut recipient_ids: BTreeSet<String> = payload.recipients.into_iter().collect();
+    recipient_ids.insert(user_data.id.to_owned());
+
+    if recipient_ids.len() < 2 {
+        return Err(ApiError::BadRequest(
+            "A direct message needs at least one other recipient.".to_owned(),
+        ));
+    }
+
+    if recipient_ids.len() > MAX_DM_RECIPIENTS {
+        return Err(ApiError::BadRequest(format!(
+            "Group DMs can have at most {} recipients.",
+            MAX_DM_RECIPIENTS
+        )));
+    }
+
+    let users_data = prisma
+        .user()
+        .find_many(vec![prisma::user::id::in_vec(
+            recipient_ids.iter().cloned().collect(),
+        )])
+        .exec()
+        .await?;
+
+    if users_data.len() != recipient_ids.len() {
+        return Err(ApiError::NotFound(Resource::User));
+    }
+
+    let existing = find_dm_channels(prisma, &user_data.id)
+        .await?
+        .into_iter()
+        .find(|channel_data| {
+            let channel_recipient_ids: BTreeSet<String> = channel_data
+                .recipients()
+                .unwrap()
+                .iter()
+                .map(|recipient| recipient.user_id.to_owned())
+                .collect();
+
+            channel_recipient_ids == recipient_ids
+        });
+
+    if let Some(channel_data) = existing {
+        return Ok((StatusCode::OK, Json(dm_channel_json(&channel_data))));
+    }
+
+    let kind = if recipient_ids.len() == 2 {
+        CHANNEL_DM
+    } else {
+        CHANNEL_GROUP_DM
+    };
+
+    let name = match kind {
+        CHANNEL_GROUP_DM => payload.name.unwrap_or_default(),
+        _ => String::new(),
+    };
+
+    let channel_data = prisma
+        .channel()
+        .create(
+            prisma::channel::name::set(name),
+            vec![prisma::channel::kind::set(kind.to_owned())],
+        )
+        .exec()
+        .await?;
+
+    // all recipients in one statement, and no channel left without them if it fails
+    let added = prisma
+        ._execute_raw(raw!(
+            r#"INSERT INTO "ChannelRecipient" ("channelId", "userId", "createdAt")
+            SELECT {}, "value", {} FROM json_each({})"#,
+            PrismaValue::String(channel_data.id.to_owned()),
+            PrismaValue::DateTime(Utc::now().into()),
+            PrismaValue::String(json!(recipient_ids).to_string())
+        ))
         .await;
 
-    if guilds_query.is_err() {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(json!({"error": "User not found"})),
-        );
+    if let Err(err) = added {
+        prisma
+            .channel()
+            .find_unique(prisma::channel::id::equals(channel_data.id))
+            .delete()
+            .exec()
+            .await?;
+
+        return Err(err.into());
     }
 
-    let guilds_data = guilds_query.unwrap();
+    let channel_data = prisma
+        .channel()
+        .find_unique(prisma::channel::id::equals(channel_data.id))
+        .with(
+            prisma::channel::recipients::fetch(vec![])
+                .with(prisma::channel_recipient::user::fetch()),
+        )
+        .exec()
+        .await?
+        .ok_or(ApiError::NotFound(Resource::Channel))?;
+
+    state.gateway.publish(SocketPayload {
+        message: SocketMessageType::ChannelCreate(
+            dm_channel(&channel_data),
+            recipient_ids.into_iter().collect(),
+        ),
+    });
 
-    (StatusCode::OK, Json(json!(guilds_data)))
+    Ok((StatusCode::CREATED, Json(dm_channel_json(&channel_data))))
 }
@@ -0,0 +1,119 @@
+use chrono::{Duration, Utc};
+use hmac_sha256::Hash;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use rand::RngCore;
+
+use crate::{config::Config, prisma, Claims, State};
+
+/// Refresh tokens are opaque random strings; only their hash is stored.
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn hash_refresh_token(token: &str) -> String {
+    let mut hasher = Hash::new();
+    hasher.update(token);
+    hex::encode(hasher.finalize())
+}
+
+/// Returns the encoded access token and its expiry as a unix timestamp.
+pub fn issue_access_token(
+    config: &Config,
+    user_data: &prisma::user::Data,
+    session_id: &str,
+) -> Result<(String, i64), jsonwebtoken::errors::Error> {
+    let exp = Utc::now().timestamp() + config.access_token_ttl_secs;
+
+    let token = jsonwebtoken::encode(
+        &Header::default(),
+        &Claims {
+            username: user_data.username.to_owned(),
+            id: user_data.id.to_owned(),
+            sid: session_id.to_owned(),
+            exp: exp as usize,
+        },
+        &EncodingKey::from_secret(config.jwt_secret.as_ref()),
+    )?;
+
+    Ok((token, exp))
+}
+
+/// Verifies an access token against the current secret, then any old secrets
+/// still accepted during a key rotation.
+pub fn decode_access_token(
+    config: &Config,
+    token: &str,
+) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
+    let validation = Validation::new(Algorithm::HS256);
+
+    let mut result = jsonwebtoken::decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
+        &validation,
+    );
+
+    for secret in &config.old_jwt_secrets {
+        if result.is_ok() {
+            break;
+        }
+
+        result = jsonwebtoken::decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(secret.as_ref()),
+            &validation,
+        );
+    }
+
+    result
+}
+
+/// Creates a new session for `user_id`, returning it with the plaintext refresh token.
+pub async fn create_session(
+    config: &Config,
+    prisma: &prisma::PrismaClient,
+    user_id: String,
+    user_agent: Option<String>,
+) -> prisma_client_rust::queries::Result<(prisma::session::Data, String)> {
+    let refresh_token = generate_refresh_token();
+
+    let session_data = prisma
+        .session()
+        .create(
+            prisma::session::refresh_token_hash::set(hash_refresh_token(&refresh_token)),
+            prisma::session::expires_at::set(refresh_expiry(config)),
+            prisma::session::user::link(prisma::user::UniqueWhereParam::IdEquals(user_id)),
+            vec![prisma::session::user_agent::set(user_agent)],
+        )
+        .exec()
+        .await?;
+
+    Ok((session_data, refresh_token))
+}
+
+pub fn refresh_expiry(config: &Config) -> chrono::DateTime<chrono::FixedOffset> {
+    (Utc::now() + Duration::seconds(config.refresh_token_ttl_secs)).into()
+}
+
+pub fn is_active(session_data: &prisma::session::Data) -> bool {
+    session_data.revoked_at.is_none() && session_data.expires_at > Utc::now()
+}
+
+/// Revokes a session: its refresh token stops working right away, its access
+/// tokens on their next use, and gateway sessions started with it are closed.
+pub async fn revoke(state: &State, session_id: &str) -> prisma_client_rust::queries::Result<()> {
+    state
+        .prisma
+        .session()
+        .find_unique(prisma::session::id::equals(session_id.to_owned()))
+        .update(vec![prisma::session::revoked_at::set(Some(
+            Utc::now().into(),
+        ))])
+        .exec()
+        .await?;
+
+    state.gateway.close_login_session(session_id);
+
+    Ok(())
+}
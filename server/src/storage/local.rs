@@ -0,0 +1,115 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use tokio::fs;
+
+use super::{Storage, StorageError};
+
+/// Keeps blobs as files in a directory, fanned out by the first two
+/// characters of their key so no single directory grows too large.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(root: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let root = root.as_ref().to_owned();
+
+        fs::create_dir_all(&root)
+            .await
+            .map_err(|err| StorageError(format!("could not create {:?}: {}", root, err)))?;
+
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        // keys are hex hashes, anything else could escape the root
+        if !is_key(key) {
+            return Err(StorageError(format!("invalid key {:?}", key)));
+        }
+
+        Ok(self.root.join(&key[..2]).join(key))
+    }
+}
+
+fn is_key(name: &str) -> bool {
+    name.len() >= 3 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn file_names(dir: &Path) -> Result<Vec<String>, StorageError> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(|err| StorageError(err.to_string()))?;
+    let mut names = vec![];
+
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|err| StorageError(err.to_string()))?
+    {
+        names.extend(entry.file_name().into_string());
+    }
+
+    Ok(names)
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, file: &Path) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|err| StorageError(err.to_string()))?;
+        }
+
+        // written next to the final path first, so readers never see a partial blob
+        let partial = path.with_extension("partial");
+
+        fs::copy(file, &partial)
+            .await
+            .map_err(|err| StorageError(err.to_string()))?;
+        fs::rename(&partial, &path)
+            .await
+            .map_err(|err| StorageError(err.to_string()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(StorageError(err.to_string())),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(StorageError(err.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        let mut keys = vec![];
+
+        for prefix in file_names(&self.root).await? {
+            if prefix.len() != 2 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+
+            // skips partial writes along with anything else that isn't a blob
+            keys.extend(
+                file_names(&self.root.join(prefix))
+                    .await?
+                    .into_iter()
+                    .filter(|name| is_key(name)),
+            );
+        }
+
+        Ok(keys)
+    }
+}
@@ -0,0 +1,171 @@
+//! Where attachment contents live. Blobs are stored under the hex SHA-256 of
+//! their contents, so identical uploads share a single blob. Nothing is
+//! deleted as rows stop using a blob, unused blobs are swept periodically.
+//! Content types are kept on the rows, since one blob can be uploaded as several.
+
+mod local;
+mod s3;
+
+use std::{fmt, path::Path};
+
+use async_trait::async_trait;
+
+use crate::config::{StorageBackend, StorageConfig};
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores the file at `path` under `key`, replacing anything already
+    /// there. The file is streamed, never read into memory whole.
+    async fn put(&self, key: &str, path: &Path) -> Result<(), StorageError>;
+
+    /// The contents stored under `key`, if any.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Removes `key`. Missing keys are not an error.
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Every stored key.
+    async fn list(&self) -> Result<Vec<String>, StorageError>;
+}
+
+/// Sets up the backend chosen in the config.
+pub async fn from_config(config: &StorageConfig) -> Result<Box<dyn Storage>, StorageError> {
+    Ok(match config.backend {
+        StorageBackend::Local => Box::new(LocalStorage::new(&config.local_path).await?),
+        StorageBackend::S3 => Box::new(S3Storage::new(&config.s3)?),
+    })
+}
+
+/// Works out the key a blob is stored under while its contents are read in chunks.
+pub struct BlobKey(hmac_sha256::Hash);
+
+impl BlobKey {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.0.finalize())
+    }
+}
+
+impl Default for BlobKey {
+    fn default() -> Self {
+        Self(hmac_sha256::Hash::new())
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::config::S3Config;
+
+    use super::*;
+
+    /// Puts, reads, lists and deletes a blob, the way uploads and sweeps do.
+    async fn round_trip(storage: &dyn Storage) {
+        let data = b"hello storage";
+        let mut key = BlobKey::default();
+        key.update(data);
+        let key = key.finish();
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+
+        assert!(storage.get(&key).await.unwrap().is_none());
+
+        storage.put(&key, file.path()).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), data);
+        assert!(storage.list().await.unwrap().contains(&key));
+
+        // the same upload again just replaces the blob
+        storage.put(&key, file.path()).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap().unwrap(), data);
+
+        storage.delete(&key).await.unwrap();
+        assert!(storage.get(&key).await.unwrap().is_none());
+        assert!(!storage.list().await.unwrap().contains(&key));
+
+        // sweeps racing each other can delete a blob twice
+        storage.delete(&key).await.unwrap();
+    }
+
+    #[test]
+    fn keys_are_sha256_hashes() {
+        let mut key = BlobKey::default();
+        key.update(b"hello ");
+        key.update(b"world");
+
+        assert_eq!(
+            key.finish(),
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+
+    #[tokio::test]
+    async fn local_round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(root.path()).await.unwrap();
+
+        round_trip(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn local_lists_only_blobs() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(root.path()).await.unwrap();
+
+        std::fs::create_dir_all(root.path().join("ab")).unwrap();
+        std::fs::write(root.path().join("ab").join("abcdef"), b"blob").unwrap();
+        std::fs::write(root.path().join("ab").join("abcdef.partial"), b"bl").unwrap();
+        std::fs::write(root.path().join("notes.txt"), b"").unwrap();
+
+        assert_eq!(storage.list().await.unwrap(), ["abcdef"]);
+    }
+
+    #[tokio::test]
+    async fn local_rejects_keys_outside_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(root.path()).await.unwrap();
+
+        assert!(storage.get("../../etc/passwd").await.is_err());
+        assert!(storage.delete("ab/../cd").await.is_err());
+    }
+
+    /// Needs an S3-compatible server with an existing bucket, like a local
+    /// MinIO: `cargo test -- --ignored` with `RUSTCORD_TEST_S3_ENDPOINT` and
+    /// optionally `RUSTCORD_TEST_S3_BUCKET`, `_ACCESS_KEY` and `_SECRET_KEY` set.
+    #[tokio::test]
+    #[ignore]
+    async fn s3_round_trip() {
+        let var = |name: &str, default: &str| {
+            std::env::var(format!("RUSTCORD_TEST_S3_{}", name))
+                .unwrap_or_else(|_| default.to_owned())
+        };
+
+        let storage = S3Storage::new(&S3Config {
+            bucket: var("BUCKET", "rustcord-test"),
+            region: "us-east-1".to_owned(),
+            endpoint: Some(var("ENDPOINT", "http://localhost:9000")),
+            access_key: Some(var("ACCESS_KEY", "minioadmin")),
+            secret_key: Some(var("SECRET_KEY", "minioadmin")),
+            path_style: true,
+        })
+        .unwrap();
+
+        round_trip(&storage).await;
+    }
+}
@@ -0,0 +1,126 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use s3::{creds::Credentials, Bucket, Region};
+
+use crate::config::S3Config;
+
+use super::{Storage, StorageError};
+
+/// Keeps blobs in an S3 bucket. Works with anything speaking the S3 API, like
+/// a local MinIO when `endpoint` points at it and `path_style` is set.
+pub struct S3Storage {
+    bucket: Bucket,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, StorageError> {
+        let region = match &config.endpoint {
+            Some(endpoint) => Region::Custom {
+                region: config.region.to_owned(),
+                endpoint: endpoint.to_owned(),
+            },
+            None => config
+                .region
+                .parse()
+                .map_err(|err| StorageError(format!("invalid region: {}", err)))?,
+        };
+
+        let credentials = Credentials::new(
+            config.access_key.as_deref(),
+            config.secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )
+        .map_err(|err| StorageError(err.to_string()))?;
+
+        let bucket = Bucket::new(&config.bucket, region, credentials)
+            .map_err(|err| StorageError(err.to_string()))?;
+
+        Ok(Self {
+            bucket: if config.path_style {
+                bucket.with_path_style()
+            } else {
+                bucket
+            },
+        })
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("attachments/{}", key)
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, file: &Path) -> Result<(), StorageError> {
+        let mut file = tokio::fs::File::open(file)
+            .await
+            .map_err(|err| StorageError(err.to_string()))?;
+
+        // large files go up as a multipart upload, a chunk at a time
+        let status = self
+            .bucket
+            .put_object_stream(&mut file, self.path(key))
+            .await
+            .map_err(|err| StorageError(err.to_string()))?;
+
+        match status {
+            200..=299 => Ok(()),
+            status => Err(StorageError(format!(
+                "upload failed with status {}",
+                status
+            ))),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let response = self
+            .bucket
+            .get_object(self.path(key))
+            .await
+            .map_err(|err| StorageError(err.to_string()))?;
+
+        match response.status_code() {
+            200..=299 => Ok(Some(response.bytes().to_vec())),
+            404 => Ok(None),
+            status => Err(StorageError(format!(
+                "download failed with status {}",
+                status
+            ))),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        let prefix = self.path("");
+
+        let pages = self
+            .bucket
+            .list(prefix.to_owned(), None)
+            .await
+            .map_err(|err| StorageError(err.to_string()))?;
+
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|object| object.key.strip_prefix(&prefix).map(str::to_owned))
+            .collect())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let response = self
+            .bucket
+            .delete_object(self.path(key))
+            .await
+            .map_err(|err| StorageError(err.to_string()))?;
+
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(StorageError(format!(
+                "delete failed with status {}",
+                status
+            ))),
+        }
+    }
+}